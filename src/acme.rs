@@ -0,0 +1,750 @@
+//! minimal ACMEv2 client. acme-lib registers the account and stores its
+//! key, this client signs requests with that key to handle the orders and
+//! the operations acme-lib does not expose (custom CSRs, key change,
+//! revocation, alternate chains, renewal information).
+
+use std::{cmp, fmt, io, thread};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use base64;
+use chrono::{DateTime, Utc};
+use serde_json::{self, Value};
+use ureq;
+use domain;
+use signal;
+use openssl::{
+  bn::{BigNum, BigNumContext, BigNumRef},
+  ec::EcKey,
+  ecdsa::EcdsaSig,
+  error::ErrorStack,
+  hash::{hash, MessageDigest},
+  pkey::{PKey, Private},
+  sign::Signer,
+};
+
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+const RATE_LIMITED: &str = "urn:ietf:params:acme:error:rateLimited";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+/// badNonce errors are transient, the request is retried with the
+/// nonce from the error response this many times
+const MAX_BAD_NONCE_RETRIES: usize = 5;
+
+pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+#[derive(Debug)]
+pub enum AcmeError {
+  /// the HTTP request could not be sent
+  Http(String),
+  /// the CA answered with a problem document (RFC 7807)
+  Problem(Problem),
+  /// the CA refused the request because of a rate limit, and
+  /// may indicate when to retry
+  RateLimited(Problem, Option<Duration>),
+  Io(io::Error),
+  Json(serde_json::Error),
+  Crypto(ErrorStack),
+  /// the CA did not complete an operation in time
+  Timeout(String),
+  Other(String),
+}
+
+impl fmt::Display for AcmeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      AcmeError::Http(ref e)    => write!(f, "HTTP error: {}", e),
+      AcmeError::Problem(ref p) => write!(f, "{}", p),
+      AcmeError::RateLimited(ref p, Some(ref d)) => write!(f, "rate limited by the CA, retry in {} seconds ({})", d.as_secs(), p),
+      AcmeError::RateLimited(ref p, None) => write!(f, "rate limited by the CA ({})", p),
+      AcmeError::Io(ref e)      => write!(f, "IO error: {}", e),
+      AcmeError::Json(ref e)    => write!(f, "JSON error: {}", e),
+      AcmeError::Crypto(ref e)  => write!(f, "crypto error: {}", e),
+      AcmeError::Timeout(ref e) => write!(f, "timeout: {}", e),
+      AcmeError::Other(ref e)   => write!(f, "{}", e),
+    }
+  }
+}
+
+impl From<io::Error> for AcmeError {
+  fn from(e: io::Error) -> Self { AcmeError::Io(e) }
+}
+
+impl From<serde_json::Error> for AcmeError {
+  fn from(e: serde_json::Error) -> Self { AcmeError::Json(e) }
+}
+
+impl From<ErrorStack> for AcmeError {
+  fn from(e: ErrorStack) -> Self { AcmeError::Crypto(e) }
+}
+
+pub type AcmeResult<T> = Result<T, AcmeError>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Problem {
+  #[serde(rename = "type")]
+  pub kind:   String,
+  pub detail: Option<String>,
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.detail {
+      Some(ref detail) => write!(f, "{}: {}", self.kind, detail),
+      None             => write!(f, "{}", self.kind),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiDirectory {
+  pub new_nonce:   String,
+  pub new_account: String,
+  pub new_order:   String,
+  pub revoke_cert: String,
+  pub key_change:  String,
+  /// ACME Renewal Information endpoint (RFC 9773)
+  pub renewal_info: Option<String>,
+  pub meta:        Option<ApiDirectoryMeta>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiDirectoryMeta {
+  pub terms_of_service: Option<String>,
+  pub website:          Option<String>,
+  /// domains recognized by the CA in CAA records
+  #[serde(default)]
+  pub caa_identities:   Vec<String>,
+  /// certificate profiles offered by the CA, with their description
+  #[serde(default)]
+  pub profiles:         BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenewalInfo {
+  pub suggested_window: SuggestedWindow,
+  #[serde(rename = "explanationURL")]
+  pub explanation_url:  Option<String>,
+}
+
+/// RFC 3339 timestamps
+#[derive(Debug, Clone, Deserialize)]
+pub struct SuggestedWindow {
+  pub start: String,
+  pub end:   String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identifier {
+  #[serde(rename = "type")]
+  pub kind:  String,
+  pub value: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiOrder {
+  pub status:         String,
+  pub identifiers:    Vec<Identifier>,
+  pub authorizations: Vec<String>,
+  pub finalize:       String,
+  pub certificate:    Option<String>,
+  pub error:          Option<Problem>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiAuthorization {
+  pub identifier: Identifier,
+  pub status:     String,
+  pub challenges: Vec<ApiChallenge>,
+  pub wildcard:   Option<bool>,
+}
+
+impl ApiAuthorization {
+  pub fn challenge(&self, kind: &str) -> Option<&ApiChallenge> {
+    self.challenges.iter().find(|challenge| challenge.kind == kind)
+  }
+
+  /// the validation error reported on one of the challenges
+  pub fn error(&self) -> Option<&Problem> {
+    self.challenges.iter().filter_map(|challenge| challenge.error.as_ref()).next()
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiChallenge {
+  #[serde(rename = "type")]
+  pub kind:   String,
+  pub url:    String,
+  pub token:  String,
+  pub status: String,
+  pub error:  Option<Problem>,
+}
+
+/// loads the CA's directory
+pub fn directory(directory_url: &str) -> AcmeResult<ApiDirectory> {
+  let res = ureq::get(directory_url).call();
+  if res.synthetic() || res.error() {
+    return Err(AcmeError::Http(format!("could not load directory {}: {}", directory_url, res.status_line())));
+  }
+  Ok(serde_json::from_str(&res.into_string()?)?)
+}
+
+/// fetches the suggested renewal window for a certificate. The certificate
+/// identifier is the authority key identifier and the serial number
+pub fn renewal_info(directory: &ApiDirectory, authority_key_id: &[u8], serial: &[u8]) -> AcmeResult<Option<RenewalInfo>> {
+  let base = match directory.renewal_info {
+    Some(ref url) => url,
+    None => return Ok(None),
+  };
+
+  let url = format!("{}/{}.{}", base.trim_end_matches('/'), b64(authority_key_id), b64(serial));
+  let res = ureq::get(&url).call();
+  if res.synthetic() || res.error() {
+    return Err(AcmeError::Http(format!("could not get renewal information from {}: {}", url, res.status_line())));
+  }
+  Ok(Some(serde_json::from_str(&res.into_string()?)?))
+}
+
+/// key identifier and MAC key the CA gave for the external account binding
+pub struct ExternalAccount {
+  pub kid:      String,
+  pub hmac_key: Vec<u8>,
+}
+
+pub struct AcmeClient {
+  pub directory: ApiDirectory,
+  key:           EcKey<Private>,
+  /// account URL, used as key identifier in signed requests
+  kid:           String,
+  nonce:         Option<String>,
+  /// when rate limited, requests are retried if the CA asks
+  /// to wait less than this
+  pub max_rate_limit_wait: Duration,
+  /// how long we wait for the CA to validate a challenge
+  pub validation_timeout: Duration,
+}
+
+impl AcmeClient {
+  /// loads the directory and looks up the existing account for this key
+  pub fn new(directory_url: &str, account_key_pem: &[u8]) -> AcmeResult<AcmeClient> {
+    let mut client = AcmeClient::with_key(directory_url, account_key_pem)?;
+    client.account(&json!({ "onlyReturnExisting": true }))?;
+    debug!("found ACME account {}", client.kid);
+    Ok(client)
+  }
+
+  /// registers a new account for this key, agreeing to the terms of
+  /// service. Some CAs require the account to be bound to an account
+  /// of theirs (RFC 8555 section 7.3.4)
+  pub fn register(directory_url: &str, account_key_pem: &[u8], email: &str, external: Option<&ExternalAccount>) -> AcmeResult<AcmeClient> {
+    let mut client = AcmeClient::with_key(directory_url, account_key_pem)?;
+    let mut payload = json!({
+      "termsOfServiceAgreed": true,
+      "contact": [format!("mailto:{}", email)],
+    });
+    if let Some(external) = external {
+      let url = client.directory.new_account.clone();
+      let protected = json!({
+        "alg": "HS256",
+        "kid": external.kid,
+        "url": url,
+      });
+      let binding = jws_hmac(&external.hmac_key, &protected, &jwk(&client.key)?.to_string())?;
+      payload["externalAccountBinding"] = serde_json::from_str(&binding)?;
+    }
+    client.account(&payload)?;
+    info!("registered the ACME account {}", client.kid);
+    Ok(client)
+  }
+
+  fn with_key(directory_url: &str, account_key_pem: &[u8]) -> AcmeResult<AcmeClient> {
+    Ok(AcmeClient {
+      directory: directory(directory_url)?,
+      key: EcKey::private_key_from_pem(account_key_pem)?,
+      kid: String::new(),
+      nonce: None,
+      max_rate_limit_wait: Duration::from_secs(0),
+      validation_timeout: Duration::from_secs(120),
+    })
+  }
+
+  /// newAccount request signed with the key itself, sets the account URL
+  fn account(&mut self, payload: &Value) -> AcmeResult<()> {
+    let url = self.directory.new_account.clone();
+    let mut retries = 0;
+    let res = loop {
+      let body = self.sign_with_jwk(&url, &payload.to_string())?;
+      match self.send(&url, &body) {
+        Err(ref e) if is_bad_nonce(e) && retries < MAX_BAD_NONCE_RETRIES => {
+          debug!("bad nonce, retrying the account request");
+          retries += 1;
+        },
+        res => break res?,
+      }
+    };
+    self.kid = res.header("Location")
+      .ok_or_else(|| AcmeError::Other("the CA did not return the account URL".to_string()))?
+      .to_string();
+    Ok(())
+  }
+
+  pub fn account_url(&self) -> &str {
+    &self.kid
+  }
+
+  /// signed POST request with the account URL as key identifier.
+  /// An empty payload is a POST-as-GET request
+  pub fn post(&mut self, url: &str, payload: &str) -> AcmeResult<ureq::Response> {
+    let mut bad_nonce_retries = 0;
+    loop {
+      let nonce = self.nonce()?;
+      let protected = json!({
+        "alg": "ES256",
+        "kid": self.kid,
+        "nonce": nonce,
+        "url": url,
+      });
+      let body = jws(&self.key, &protected, payload)?;
+
+      match self.send(url, &body) {
+        Err(AcmeError::RateLimited(problem, Some(wait))) => {
+          if wait > self.max_rate_limit_wait {
+            return Err(AcmeError::RateLimited(problem, Some(wait)));
+          }
+          warn!("rate limited by the CA ({}), retrying in {} seconds", problem, wait.as_secs());
+          thread::sleep(wait);
+          signal::check_shutdown();
+        },
+        Err(ref e) if is_bad_nonce(e) && bad_nonce_retries < MAX_BAD_NONCE_RETRIES => {
+          debug!("bad nonce for {}, retrying", url);
+          bad_nonce_retries += 1;
+        },
+        res => return res,
+      }
+    }
+  }
+
+  /// RFC 8555 section 7.3.2, replaces the account's contact URLs
+  pub fn update_contact(&mut self, emails: &[&str]) -> AcmeResult<()> {
+    let contact: Vec<String> = emails.iter().map(|email| format!("mailto:{}", email)).collect();
+    let url = self.kid.clone();
+    self.post(&url, &json!({ "contact": contact }).to_string())?;
+    Ok(())
+  }
+
+  /// RFC 8555 section 7.3.5: the new key signs the key change object,
+  /// which is then sent signed by the current key
+  pub fn change_key(&mut self, new_key: &EcKey<Private>) -> AcmeResult<()> {
+    let url = self.directory.key_change.clone();
+    let inner_protected = json!({
+      "alg": "ES256",
+      "jwk": jwk(new_key)?,
+      "url": url,
+    });
+    let inner_payload = json!({
+      "account": self.kid,
+      "oldKey": jwk(&self.key)?,
+    });
+    let inner = jws(new_key, &inner_protected, &inner_payload.to_string())?;
+
+    self.post(&url, &inner)?;
+    self.key = new_key.clone();
+    Ok(())
+  }
+
+  /// creates an order for the domains (or IP addresses), returns the order URL and the order
+  pub fn new_order(&mut self, domains: &[&str], profile: Option<&str>) -> AcmeResult<(String, ApiOrder)> {
+    let identifiers: Vec<Identifier> = domains.iter().map(|domain| Identifier {
+      kind:  if domain::is_ip(domain) { "ip" } else { "dns" }.to_string(),
+      value: domain.to_string(),
+    }).collect();
+
+    let mut payload = json!({ "identifiers": identifiers });
+    if let Some(profile) = profile {
+      payload["profile"] = json!(profile);
+    }
+
+    let url = self.directory.new_order.clone();
+    let res = self.post(&url, &payload.to_string())?;
+    let order_url = res.header("Location")
+      .ok_or_else(|| AcmeError::Other("the CA did not return the order URL".to_string()))?
+      .to_string();
+    let order = serde_json::from_str(&res.into_string()?)?;
+
+    Ok((order_url, order))
+  }
+
+  pub fn order(&mut self, url: &str) -> AcmeResult<ApiOrder> {
+    Ok(serde_json::from_str(&self.post(url, "")?.into_string()?)?)
+  }
+
+  pub fn authorization(&mut self, url: &str) -> AcmeResult<ApiAuthorization> {
+    Ok(serde_json::from_str(&self.post(url, "")?.into_string()?)?)
+  }
+
+  /// the token and the account key thumbprint (RFC 8555 section 8.1)
+  pub fn key_authorization(&self, token: &str) -> AcmeResult<String> {
+    let thumbprint = hash(MessageDigest::sha256(), jwk(&self.key)?.to_string().as_bytes())?;
+    Ok(format!("{}.{}", token, b64(&thumbprint)))
+  }
+
+  /// tells the CA the challenge is ready, then polls the authorization
+  /// until the CA is done validating it, or the validation timeout expires.
+  /// Only a valid authorization counts as success, whatever requests we answered
+  pub fn validate(&mut self, auth_url: &str, challenge: &ApiChallenge, delay_millis: u64) -> AcmeResult<()> {
+    self.post(&challenge.url, "{}")?;
+    let deadline = Instant::now() + self.validation_timeout;
+
+    loop {
+      let auth = self.authorization(auth_url)?;
+      match auth.status.as_str() {
+        "pending" => {
+          if Instant::now() >= deadline {
+            return Err(AcmeError::Timeout(format!("authorization for {} still pending after {} seconds",
+              auth.identifier.value, self.validation_timeout.as_secs())));
+          }
+          debug!("authorization for {} is pending", auth.identifier.value);
+          thread::sleep(Duration::from_millis(delay_millis));
+          signal::check_shutdown();
+        },
+        "valid"   => return Ok(()),
+        status    => return Err(match auth.error() {
+          Some(problem) => AcmeError::Problem(problem.clone()),
+          None => AcmeError::Other(format!("authorization for {} is {}", auth.identifier.value, status)),
+        }),
+      }
+    }
+  }
+
+  /// submits the CSR (in DER format), then polls the order until the
+  /// certificate is issued, or the validation timeout expires. The order can
+  /// still be ready right after the CSR was submitted, and the CA can ask
+  /// for a longer delay between the polls with Retry-After
+  pub fn finalize(&mut self, order_url: &str, order: &ApiOrder, csr: &[u8], delay_millis: u64) -> AcmeResult<ApiOrder> {
+    self.post(&order.finalize, &json!({ "csr": b64(csr) }).to_string())?;
+    let deadline = Instant::now() + self.validation_timeout;
+
+    loop {
+      let res = self.post(order_url, "")?;
+      let retry_after = res.header("Retry-After").and_then(parse_retry_after);
+      let order: ApiOrder = serde_json::from_str(&res.into_string()?)?;
+      match order.status.as_str() {
+        "processing" | "ready" => {
+          let now = Instant::now();
+          if now >= deadline {
+            return Err(AcmeError::Timeout(format!("order still {} after {} seconds",
+              order.status, self.validation_timeout.as_secs())));
+          }
+          debug!("order is {}", order.status);
+          let delay = retry_after.unwrap_or_else(|| Duration::from_millis(delay_millis));
+          thread::sleep(cmp::min(delay, deadline - now));
+          signal::check_shutdown();
+        },
+        "valid"      => return Ok(order),
+        status       => return Err(match order.error {
+          Some(problem) => AcmeError::Problem(problem),
+          None => AcmeError::Other(format!("order is {}", status)),
+        }),
+      }
+    }
+  }
+
+  /// RFC 8555 section 7.6, the certificate is in DER format
+  pub fn revoke(&mut self, certificate: &[u8], reason: u8) -> AcmeResult<()> {
+    let url = self.directory.revoke_cert.clone();
+    let payload = json!({
+      "certificate": b64(certificate),
+      "reason": reason,
+    });
+    self.post(&url, &payload.to_string())?;
+    Ok(())
+  }
+
+  /// downloads the certificate chain, followed by the alternate chains
+  /// the CA offers through `Link: <url>;rel="alternate"` headers
+  pub fn certificate_chains(&mut self, url: &str) -> AcmeResult<Vec<String>> {
+    let res = self.post(url, "")?;
+    let alternates: Vec<String> = res.all("Link").iter()
+      .flat_map(|header| header.split(','))
+      .filter_map(|link| link_target(link, "alternate"))
+      .collect();
+
+    let mut chains = vec![res.into_string()?];
+    for alternate in alternates.iter() {
+      chains.push(self.post(alternate, "")?.into_string()?);
+    }
+
+    Ok(chains)
+  }
+
+  fn sign_with_jwk(&mut self, url: &str, payload: &str) -> AcmeResult<String> {
+    let nonce = self.nonce()?;
+    let protected = json!({
+      "alg": "ES256",
+      "jwk": jwk(&self.key)?,
+      "nonce": nonce,
+      "url": url,
+    });
+    jws(&self.key, &protected, payload)
+  }
+
+  fn nonce(&mut self) -> AcmeResult<String> {
+    if let Some(nonce) = self.nonce.take() {
+      return Ok(nonce);
+    }
+
+    let res = ureq::head(&self.directory.new_nonce).call();
+    match res.header("Replay-Nonce") {
+      Some(nonce) => Ok(nonce.to_string()),
+      None => Err(AcmeError::Http(format!("could not get a nonce: {}", res.status_line()))),
+    }
+  }
+
+  fn send(&mut self, url: &str, body: &str) -> AcmeResult<ureq::Response> {
+    let res = ureq::post(url)
+      .set("Content-Type", "application/jose+json")
+      .send_string(body);
+
+    if let Some(nonce) = res.header("Replay-Nonce") {
+      self.nonce = Some(nonce.to_string());
+    }
+
+    if res.synthetic() {
+      return Err(AcmeError::Http(format!("request to {} failed: {}", url, res.status_line())));
+    }
+
+    if res.error() {
+      let status = res.status();
+      let retry_after = res.header("Retry-After").and_then(parse_retry_after);
+      let body = res.into_string()?;
+      let problem = serde_json::from_str::<Problem>(&body).ok();
+
+      return match problem {
+        Some(problem) => if problem.kind == RATE_LIMITED || status == 429 {
+          Err(AcmeError::RateLimited(problem, retry_after))
+        } else {
+          Err(AcmeError::Problem(problem))
+        },
+        None => if status == 429 {
+          Err(AcmeError::RateLimited(Problem { kind: RATE_LIMITED.to_string(), detail: Some(body) }, retry_after))
+        } else {
+          Err(AcmeError::Http(body))
+        },
+      };
+    }
+
+    Ok(res)
+  }
+}
+
+/// the CA rejected the nonce, the error response carries a new one
+fn is_bad_nonce(error: &AcmeError) -> bool {
+  match *error {
+    AcmeError::Problem(ref problem) => problem.kind == BAD_NONCE,
+    _ => false,
+  }
+}
+
+/// Retry-After header value, either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+  if let Ok(seconds) = value.trim().parse::<u64>() {
+    return Some(Duration::from_secs(seconds));
+  }
+
+  DateTime::parse_from_rfc2822(value.trim()).ok()
+    .map(|date| (date.with_timezone(&Utc) - Utc::now()).num_seconds().max(0) as u64)
+    .map(Duration::from_secs)
+}
+
+/// target of a `<url>;rel="relation"` link, if it has this relation
+fn link_target(link: &str, relation: &str) -> Option<String> {
+  let mut parts = link.split(';');
+  let target = parts.next()?.trim();
+  if !target.starts_with('<') || !target.ends_with('>') {
+    return None;
+  }
+
+  let has_relation = parts.any(|param| {
+    let param = param.trim();
+    param == format!("rel=\"{}\"", relation) || param == format!("rel={}", relation)
+  });
+
+  if has_relation {
+    Some(target[1..target.len() - 1].to_string())
+  } else {
+    None
+  }
+}
+
+/// value of the dns-01 TXT record
+pub fn dns_proof(key_authorization: &str) -> AcmeResult<String> {
+  Ok(b64(&hash(MessageDigest::sha256(), key_authorization.as_bytes())?))
+}
+
+fn b64(data: &[u8]) -> String {
+  base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// big endian unsigned integer, left padded to the field size
+fn padded(n: &BigNumRef, len: usize) -> Vec<u8> {
+  let bytes = n.to_vec();
+  let mut v = vec![0u8; len.saturating_sub(bytes.len())];
+  v.extend(bytes);
+  v
+}
+
+/// public key of a P-256 key in JSON Web Key format
+pub fn jwk(key: &EcKey<Private>) -> AcmeResult<Value> {
+  let mut ctx = BigNumContext::new()?;
+  let mut x = BigNum::new()?;
+  let mut y = BigNum::new()?;
+  key.public_key().affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx)?;
+
+  // the members are in lexicographic order, as required for thumbprints
+  Ok(json!({
+    "crv": "P-256",
+    "kty": "EC",
+    "x": b64(&padded(&x, 32)),
+    "y": b64(&padded(&y, 32)),
+  }))
+}
+
+/// flattened JWS serialization, MACed with HS256, for the external account binding
+fn jws_hmac(key: &[u8], protected: &Value, payload: &str) -> AcmeResult<String> {
+  let protected = b64(protected.to_string().as_bytes());
+  let payload = b64(payload.as_bytes());
+
+  let key = PKey::hmac(key)?;
+  let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+  signer.update(format!("{}.{}", protected, payload).as_bytes())?;
+
+  Ok(json!({
+    "protected": protected,
+    "payload": payload,
+    "signature": b64(&signer.sign_to_vec()?),
+  }).to_string())
+}
+
+/// flattened JWS serialization, signed with ES256
+pub fn jws(key: &EcKey<Private>, protected: &Value, payload: &str) -> AcmeResult<String> {
+  let protected = b64(protected.to_string().as_bytes());
+  let payload = b64(payload.as_bytes());
+
+  let digest = hash(MessageDigest::sha256(), format!("{}.{}", protected, payload).as_bytes())?;
+  let sig = EcdsaSig::sign(&digest, key)?;
+  let mut signature = padded(sig.r(), 32);
+  signature.extend(padded(sig.s(), 32));
+
+  Ok(json!({
+    "protected": protected,
+    "payload": payload,
+    "signature": b64(&signature),
+  }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::{ec::EcGroup, nid::Nid};
+
+  fn key() -> EcKey<Private> {
+    EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()
+  }
+
+  fn decode(data: &Value) -> Vec<u8> {
+    base64::decode_config(data.as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap()
+  }
+
+  fn problem(kind: &str) -> Problem {
+    Problem { kind: kind.to_string(), detail: None }
+  }
+
+  #[test]
+  fn encodes_in_url_safe_base64_without_padding() {
+    assert_eq!(b64(&[0xfb, 0xff]), "-_8");
+    assert_eq!(dns_proof("abc").unwrap(), "ungWv48Bz-pBQUDeXa4iI7ADYaOWF3qctBD_YfIAFa0");
+  }
+
+  #[test]
+  fn pads_the_coordinates() {
+    let one = BigNum::from_u32(1).unwrap();
+    let mut expected = vec![0u8; 31];
+    expected.push(1);
+    assert_eq!(padded(&one, 32), expected);
+
+    let jwk = jwk(&key()).unwrap();
+    assert_eq!(jwk["kty"], "EC");
+    assert_eq!(jwk["crv"], "P-256");
+    assert_eq!(decode(&jwk["x"]).len(), 32);
+    assert_eq!(decode(&jwk["y"]).len(), 32);
+  }
+
+  #[test]
+  fn signs_with_es256() {
+    let key = key();
+    let protected = json!({ "alg": "ES256", "nonce": "abc", "url": "https://ca.example/new-order" });
+    let jws: Value = serde_json::from_str(&jws(&key, &protected, "{\"a\":1}").unwrap()).unwrap();
+
+    assert_eq!(serde_json::from_slice::<Value>(&decode(&jws["protected"])).unwrap(), protected);
+    assert_eq!(decode(&jws["payload"]), b"{\"a\":1}");
+
+    let signature = decode(&jws["signature"]);
+    assert_eq!(signature.len(), 64);
+    let signed = format!("{}.{}", jws["protected"].as_str().unwrap(), jws["payload"].as_str().unwrap());
+    let digest = hash(MessageDigest::sha256(), signed.as_bytes()).unwrap();
+    let sig = EcdsaSig::from_private_components(BigNum::from_slice(&signature[..32]).unwrap(),
+      BigNum::from_slice(&signature[32..]).unwrap()).unwrap();
+    assert!(sig.verify(&digest, &key).unwrap());
+  }
+
+  #[test]
+  fn macs_the_external_account_binding_with_hs256() {
+    let hmac_key = b"external account key";
+    let protected = json!({ "alg": "HS256", "kid": "kid-1", "url": "https://ca.example/new-account" });
+    let jws: Value = serde_json::from_str(&jws_hmac(hmac_key, &protected, "{\"kty\":\"EC\"}").unwrap()).unwrap();
+
+    assert_eq!(serde_json::from_slice::<Value>(&decode(&jws["protected"])).unwrap(), protected);
+    assert_eq!(decode(&jws["payload"]), b"{\"kty\":\"EC\"}");
+
+    // HMAC (RFC 2104) computed from SHA-256 directly
+    let signed = format!("{}.{}", jws["protected"].as_str().unwrap(), jws["payload"].as_str().unwrap());
+    let mut block = hmac_key.to_vec();
+    block.resize(64, 0);
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(signed.as_bytes());
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&hash(MessageDigest::sha256(), &inner).unwrap());
+    assert_eq!(decode(&jws["signature"]), hash(MessageDigest::sha256(), &outer).unwrap().to_vec());
+  }
+
+  #[test]
+  fn retries_only_bad_nonce_problems() {
+    assert!(is_bad_nonce(&AcmeError::Problem(problem(BAD_NONCE))));
+    assert!(!is_bad_nonce(&AcmeError::Problem(problem("urn:ietf:params:acme:error:malformed"))));
+    assert!(!is_bad_nonce(&AcmeError::RateLimited(problem(RATE_LIMITED), None)));
+    assert!(!is_bad_nonce(&AcmeError::Http(BAD_NONCE.to_string())));
+  }
+
+  #[test]
+  fn parses_retry_after() {
+    assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+    assert_eq!(parse_retry_after(" 5 "), Some(Duration::from_secs(5)));
+    assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::from_secs(0)));
+    assert_eq!(parse_retry_after("soon"), None);
+
+    let later = (Utc::now() + chrono::Duration::hours(1)).to_rfc2822();
+    let delay = parse_retry_after(&later).unwrap();
+    assert!(delay > Duration::from_secs(3590) && delay <= Duration::from_secs(3600));
+  }
+
+  #[test]
+  fn finds_link_targets() {
+    assert_eq!(link_target("<https://ca.example/cert/1/1>;rel=\"alternate\"", "alternate"),
+      Some("https://ca.example/cert/1/1".to_string()));
+    assert_eq!(link_target(" <https://ca.example/cert/1/2> ; rel=alternate", "alternate"),
+      Some("https://ca.example/cert/1/2".to_string()));
+    assert_eq!(link_target("<https://ca.example/directory>;rel=\"index\"", "alternate"), None);
+    assert_eq!(link_target("https://ca.example/cert/1/1;rel=\"alternate\"", "alternate"), None);
+  }
+}
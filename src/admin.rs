@@ -0,0 +1,41 @@
+//! HTTP endpoint of the daemon, for load balancer checks and dashboards
+
+use std::{thread, sync::{Arc, Mutex}};
+use serde_json::Value;
+use tiny_http::{Server, Response, Header};
+
+/// the JSON document served on /status, updated by the daemon's loop
+pub type Status = Arc<Mutex<Value>>;
+
+/// serves `/healthz`, which answers `ok` while the daemon runs, and `/status`
+pub fn serve(address: &str) -> Result<Status, String> {
+  let server = Server::http(address).map_err(|e| format!("could not listen on {}: {}", address, e))?;
+  let status: Status = Arc::new(Mutex::new(Value::Null));
+
+  let server_status = status.clone();
+  thread::spawn(move || {
+    info!("admin endpoint listening on {}", server.server_addr());
+    loop {
+      let request = match server.recv() {
+        Ok(rq) => rq,
+        Err(e) => { error!("admin endpoint error: {}", e); break }
+      };
+
+      debug!("admin request to URL: {}", request.url());
+      let response = match request.url() {
+        "/healthz" => Response::from_data(&b"ok\n"[..]).with_status_code(200),
+        "/status" => {
+          let body = server_status.lock().unwrap().to_string();
+          let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).expect("valid header");
+          Response::from_data(body.into_bytes()).with_status_code(200).with_header(content_type)
+        },
+        _ => Response::from_data(&b"not found"[..]).with_status_code(404),
+      };
+      if let Err(e) = request.respond(response) {
+        debug!("could not answer the admin request: {}", e);
+      }
+    }
+  });
+
+  Ok(status)
+}
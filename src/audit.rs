@@ -0,0 +1,87 @@
+//! append-only log of the orders sent to sozu, one JSON object per line, to
+//! reconstruct what was changed in the proxies and when
+
+use std::{env, fs::OpenOptions, io::Write};
+use chrono::Utc;
+use serde_json;
+use sozu_command::{
+  certificate::calculate_fingerprint,
+  command::{CommandRequestData, CommandResponse, CommandStatus},
+  proxy::ProxyRequestData,
+};
+
+use sozu::SozuError;
+
+/// the path of the audit log, inherited by the runs the daemon and the
+/// batches start, and read by the threads of the daemon
+pub const VARIABLE: &str = "SOZU_ACME_AUDIT_LOG";
+
+pub fn set_path(path: &str) {
+  env::set_var(VARIABLE, path);
+}
+
+/// records the order and its answer, or why it got none. The queries are
+/// not recorded, they change nothing
+pub fn record(socket: &str, id: &str, data: &CommandRequestData, result: &Result<CommandResponse, SozuError>) {
+  let path = match env::var(VARIABLE) {
+    Ok(path) => path,
+    Err(_) => return,
+  };
+  if let CommandRequestData::Proxy(ProxyRequestData::Query(_)) = *data {
+    return;
+  }
+
+  let (status, message) = match *result {
+    Ok(ref answer) => (match answer.status {
+      CommandStatus::Ok => "ok",
+      CommandStatus::Processing => "processing",
+      CommandStatus::Error => "error",
+    }, answer.message.clone()),
+    Err(ref e) => ("failed", e.to_string()),
+  };
+  let entry = json!({
+    "timestamp": Utc::now().to_rfc3339(),
+    "socket": socket,
+    "order_id": id,
+    "order_type": order_type(data),
+    "summary": summary(data),
+    "status": status,
+    "message": message,
+  });
+
+  // a single write per entry, the runs of a batch append in parallel
+  let line = format!("{}\n", entry);
+  let written = OpenOptions::new().append(true).create(true).open(&path)
+    .and_then(|mut file| file.write_all(line.as_bytes()));
+  if let Err(e) = written {
+    error!("could not append to the audit log {}: {}", path, e);
+  }
+}
+
+/// the name of the variant, like AddCertificate or SaveState
+fn order_type(data: &CommandRequestData) -> String {
+  let debug = match *data {
+    CommandRequestData::Proxy(ref order) => format!("{:?}", order),
+    _ => format!("{:?}", data),
+  };
+  debug.split(|c: char| !c.is_alphanumeric()).next().unwrap_or_default().to_string()
+}
+
+/// the order without the private keys and the certificates, which are
+/// identified by their fingerprint
+fn summary(data: &CommandRequestData) -> String {
+  let fingerprint = |pem: &str| calculate_fingerprint(pem.as_bytes())
+    .map(|fingerprint| fingerprint.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+    .unwrap_or_default();
+
+  match *data {
+    CommandRequestData::Proxy(ProxyRequestData::AddCertificate(ref add)) =>
+      format!("certificate {} for {} on {}", fingerprint(&add.certificate.certificate), add.names.join(", "), add.front),
+    CommandRequestData::Proxy(ProxyRequestData::ReplaceCertificate(ref replace)) =>
+      format!("certificate {} replaced with {} for {} on {}",
+        replace.old_fingerprint.0.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+        fingerprint(&replace.new_certificate.certificate), replace.new_names.join(", "), replace.front),
+    CommandRequestData::Proxy(ref order) => serde_json::to_string(order).unwrap_or_else(|_| format!("{:?}", order)),
+    _ => serde_json::to_string(data).unwrap_or_else(|_| format!("{:?}", data)),
+  }
+}
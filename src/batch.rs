@@ -0,0 +1,153 @@
+//! certificate requests for many domains, described in a TOML file
+
+use std::{env, fs, io, thread, process::{Child, Command, ExitStatus}, time::Duration};
+use toml::{self, Value};
+
+use exit;
+use lock;
+
+/// the file has an optional `[defaults]` table, and a `[[certificate]]`
+/// table per certificate. Their keys are the options of the main command,
+/// without the leading dashes: strings and numbers are option values,
+/// arrays repeat the option, `true` sets a flag. Up to `parallel`
+/// certificates are processed at the same time, under the run lock of the batch
+pub fn run(path: &str, parallel: usize) -> bool {
+  let entries = match load(path) {
+    Ok(entries) => entries,
+    Err(e) => {
+      error!("could not load {}: {}", path, e);
+      return false;
+    }
+  };
+
+  let executable = match env::current_exe() {
+    Ok(executable) => executable,
+    Err(e) => {
+      error!("could not find the sozu-acme executable: {}", e);
+      return false;
+    }
+  };
+
+  // each certificate gets its own process, a failure does not stop the batch
+  let mut results: Vec<(String, Option<Result<&str, String>>)> = Vec::new();
+  let mut running: Vec<(usize, Child)> = Vec::new();
+  for (name, arguments) in entries {
+    while running.len() >= parallel {
+      wait_for_one(&mut running, &mut results);
+    }
+
+    info!("requesting the certificate for {}", name);
+    match Command::new(&executable).args(&arguments).env(lock::LOCK_HELD_VARIABLE, "1").spawn() {
+      Ok(child) => running.push((results.len(), child)),
+      Err(e) => {
+        results.push((name, Some(Err(format!("failed ({})", e)))));
+        continue;
+      }
+    }
+    results.push((name, None));
+  }
+  while !running.is_empty() {
+    wait_for_one(&mut running, &mut results);
+  }
+  let results: Vec<(String, Result<&str, String>)> = results.into_iter()
+    .map(|(name, result)| (name, result.expect("every process was waited for")))
+    .collect();
+
+  for &(ref name, ref result) in results.iter() {
+    match *result {
+      Ok(message) => println!("{}\t{}", name, message),
+      Err(ref message) => println!("{}\t{}", name, message),
+    }
+  }
+  results.iter().all(|&(_, ref result)| result.is_ok())
+}
+
+/// waits until one of the processes exits, and records its result
+fn wait_for_one(running: &mut Vec<(usize, Child)>, results: &mut Vec<(String, Option<Result<&str, String>>)>) {
+  loop {
+    for i in 0..running.len() {
+      let status = match running[i].1.try_wait() {
+        Ok(None) => continue,
+        Ok(Some(status)) => Ok(status),
+        Err(e) => Err(e),
+      };
+      let (index, _) = running.remove(i);
+      results[index].1 = Some(result(status));
+      return;
+    }
+    thread::sleep(Duration::from_millis(100));
+  }
+}
+
+fn result(status: io::Result<ExitStatus>) -> Result<&'static str, String> {
+  match status {
+    Ok(ref status) if status.success() => Ok("done"),
+    Ok(ref status) if status.code() == Some(exit::NOT_DUE) => Ok("not due"),
+    Ok(status) => match status.code() {
+      Some(code) => Err(format!("failed ({}, {})", exit::description(code), status)),
+      None => Err(format!("failed ({})", status)),
+    },
+    Err(e) => Err(format!("failed ({})", e)),
+  }
+}
+
+/// name and command line of each certificate, `renew` is used so
+/// certificates that are not due are skipped
+pub fn load(path: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+  let data = fs::read_to_string(path).map_err(|e| e.to_string())?;
+  let file: Value = toml::from_str(&data).map_err(|e| e.to_string())?;
+
+  let defaults = match file.get("defaults") {
+    Some(&Value::Table(ref defaults)) => defaults.clone(),
+    Some(_) => return Err("defaults must be a table".to_string()),
+    None => toml::value::Table::new(),
+  };
+  let certificates = match file.get("certificate") {
+    Some(&Value::Array(ref certificates)) => certificates,
+    _ => return Err("no [[certificate]] entry".to_string()),
+  };
+
+  let mut entries = Vec::new();
+  for (index, certificate) in certificates.iter().enumerate() {
+    let certificate = match *certificate {
+      Value::Table(ref certificate) => certificate,
+      _ => return Err(format!("certificate {} must be a table", index + 1)),
+    };
+
+    let mut options = defaults.clone();
+    options.extend(certificate.iter().map(|(key, value)| (key.clone(), value.clone())));
+
+    let name = match options.get("domain") {
+      Some(&Value::String(ref domain)) => domain.clone(),
+      Some(&Value::Array(ref domains)) if !domains.is_empty() => domains.iter().filter_map(|d| d.as_str()).collect::<Vec<_>>().join(", "),
+      _ => options.get("id").and_then(|id| id.as_str()).map(|id| id.to_string()).unwrap_or_else(|| format!("certificate {}", index + 1)),
+    };
+
+    let mut arguments = vec!["renew".to_string()];
+    for (key, value) in options.iter() {
+      arguments.extend(option_arguments(key, value).map_err(|e| format!("{}: {}", name, e))?);
+    }
+    entries.push((name, arguments));
+  }
+
+  Ok(entries)
+}
+
+/// command line arguments setting the option to the value
+pub fn option_arguments(key: &str, value: &Value) -> Result<Vec<String>, String> {
+  let option = format!("--{}", key);
+  match *value {
+    Value::String(ref s) => Ok(vec![option, s.clone()]),
+    Value::Integer(i) => Ok(vec![option, i.to_string()]),
+    Value::Boolean(true) => Ok(vec![option]),
+    Value::Boolean(false) => Ok(Vec::new()),
+    Value::Array(ref values) => {
+      let mut arguments = Vec::new();
+      for value in values.iter() {
+        arguments.extend(option_arguments(key, value)?);
+      }
+      Ok(arguments)
+    },
+    _ => Err(format!("unsupported value for {}", key)),
+  }
+}
@@ -0,0 +1,264 @@
+//! CAA pre-check (RFC 8659): before ordering, make sure the domains'
+//! CAA records allow the CA to issue, instead of failing the order later
+
+use std::{
+  fs,
+  net::{SocketAddr, UdpSocket},
+  time::Duration,
+};
+use rand::{thread_rng, Rng};
+
+use domain;
+
+const TYPE_CAA: u16 = 257;
+const RCODE_NXDOMAIN: u8 = 3;
+const FLAG_CRITICAL: u8 = 0x80;
+const KNOWN_TAGS: &[&str] = &["issue", "issuewild", "iodef", "issuemail", "contactemail", "contactphone"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaaRecord {
+  pub flags: u8,
+  pub tag:   String,
+  pub value: String,
+}
+
+/// checks that the CAA records of every domain allow one of the CA's identities.
+/// Lookup failures are only logged, to avoid blocking issuance on a DNS problem
+pub fn check(domains: &[&str], ca_identities: &[String]) -> Result<(), String> {
+  let resolver = resolver();
+
+  // CAA only applies to domain names
+  for domain in domains.iter().filter(|domain| !domain::is_ip(domain)) {
+    let wildcard = domain.starts_with("*.");
+    let name = domain.trim_start_matches("*.");
+
+    let records = match relevant_records(&resolver, name) {
+      Ok(records) => records,
+      Err(e) => {
+        warn!("could not check the CAA records of {}: {}", domain, e);
+        continue;
+      }
+    };
+
+    let (set_name, records) = match records {
+      Some(r) => r,
+      None => {
+        debug!("no CAA records for {}, any CA can issue", domain);
+        continue;
+      }
+    };
+
+    // the CA cannot issue if it does not understand a critical property
+    if let Some(record) = records.iter().find(|r| r.flags & FLAG_CRITICAL != 0 && !KNOWN_TAGS.iter().any(|t| t.eq_ignore_ascii_case(&r.tag))) {
+      return Err(format!("the CAA records of {} contain the unknown critical property \"{}\", the CA will refuse to issue for {}",
+        set_name, record.tag, domain));
+    }
+
+    // issuewild takes precedence for wildcard names, if there is one
+    let tag = if wildcard && records.iter().any(|r| r.tag.eq_ignore_ascii_case("issuewild")) { "issuewild" } else { "issue" };
+    let properties: Vec<&CaaRecord> = records.iter().filter(|r| r.tag.eq_ignore_ascii_case(tag)).collect();
+    if properties.is_empty() {
+      continue;
+    }
+
+    let allowed = properties.iter().any(|record| {
+      let issuer = record.value.split(';').next().unwrap_or("").trim();
+      ca_identities.iter().any(|identity| identity.eq_ignore_ascii_case(issuer))
+    });
+
+    if !allowed {
+      let values: Vec<String> = properties.iter().map(|r| format!("{} \"{}\"", r.tag, r.value)).collect();
+      return Err(format!("the CAA records of {} ({}) do not allow {} to issue for {}. Add a record like `{} CAA 0 {} \"{}\"`",
+        set_name, values.join(", "), ca_identities.join(" or "), domain, set_name, tag,
+        ca_identities.first().map(|s| s.as_str()).unwrap_or("<CA domain>")));
+    }
+  }
+
+  Ok(())
+}
+
+/// the closest CAA record set, climbing from the name to its parents
+fn relevant_records(resolver: &SocketAddr, name: &str) -> Result<Option<(String, Vec<CaaRecord>)>, String> {
+  let mut current = name.trim_end_matches('.');
+
+  while !current.is_empty() {
+    let records = query(resolver, current)?;
+    if !records.is_empty() {
+      return Ok(Some((current.to_string(), records)));
+    }
+
+    current = match current.find('.') {
+      Some(i) => &current[i + 1..],
+      None    => "",
+    };
+  }
+
+  Ok(None)
+}
+
+/// first nameserver of /etc/resolv.conf
+fn resolver() -> SocketAddr {
+  fs::read_to_string("/etc/resolv.conf").ok()
+    .and_then(|conf| conf.lines()
+      .filter_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+          (Some("nameserver"), Some(address)) => address.parse().ok(),
+          _ => None,
+        }
+      })
+      .next())
+    .map(|ip| SocketAddr::new(ip, 53))
+    .unwrap_or_else(|| "127.0.0.1:53".parse().unwrap())
+}
+
+fn query(resolver: &SocketAddr, name: &str) -> Result<Vec<CaaRecord>, String> {
+  let id: u16 = thread_rng().gen();
+
+  let mut packet = Vec::new();
+  packet.extend_from_slice(&id.to_be_bytes());
+  // recursion desired, one question
+  packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+  for label in name.split('.') {
+    packet.push(label.len() as u8);
+    packet.extend_from_slice(label.as_bytes());
+  }
+  packet.push(0);
+  packet.extend_from_slice(&TYPE_CAA.to_be_bytes());
+  packet.extend_from_slice(&[0x00, 0x01]);
+
+  let bind = if resolver.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+  let socket = UdpSocket::bind(bind).map_err(|e| e.to_string())?;
+  socket.set_read_timeout(Some(Duration::from_secs(5))).map_err(|e| e.to_string())?;
+  socket.send_to(&packet, resolver).map_err(|e| e.to_string())?;
+
+  let mut buffer = [0u8; 4096];
+  let answer = loop {
+    let (size, from) = socket.recv_from(&mut buffer).map_err(|e| e.to_string())?;
+    if from == *resolver && size >= 12 && buffer[0..2] == id.to_be_bytes() {
+      break &buffer[..size];
+    }
+  };
+
+  parse_answer(answer)
+}
+
+fn parse_answer(answer: &[u8]) -> Result<Vec<CaaRecord>, String> {
+  let truncated = answer[2] & 0x02 != 0;
+  let rcode = answer[3] & 0x0f;
+  if rcode == RCODE_NXDOMAIN {
+    return Ok(Vec::new());
+  }
+  if rcode != 0 {
+    return Err(format!("DNS error code {}", rcode));
+  }
+  if truncated {
+    return Err("truncated DNS answer".to_string());
+  }
+
+  let questions = u16::from_be_bytes([answer[4], answer[5]]);
+  let answers = u16::from_be_bytes([answer[6], answer[7]]);
+
+  let mut position = 12;
+  for _ in 0..questions {
+    position = skip_name(answer, position)? + 4;
+  }
+
+  let mut records = Vec::new();
+  for _ in 0..answers {
+    position = skip_name(answer, position)?;
+    let header = answer.get(position..position + 10).ok_or("invalid DNS answer")?;
+    let kind = u16::from_be_bytes([header[0], header[1]]);
+    let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+    position += 10;
+
+    let data = answer.get(position..position + length).ok_or("invalid DNS answer")?;
+    position += length;
+
+    // the answer can also contain the CNAME chain
+    if kind != TYPE_CAA || data.len() < 2 {
+      continue;
+    }
+
+    let tag_length = data[1] as usize;
+    let tag = data.get(2..2 + tag_length).ok_or("invalid CAA record")?;
+    records.push(CaaRecord {
+      flags: data[0],
+      tag:   String::from_utf8_lossy(tag).to_string(),
+      value: String::from_utf8_lossy(&data[2 + tag_length..]).to_string(),
+    });
+  }
+
+  Ok(records)
+}
+
+/// returns the position after the (possibly compressed) name
+fn skip_name(packet: &[u8], mut position: usize) -> Result<usize, String> {
+  loop {
+    let length = *packet.get(position).ok_or("invalid DNS name")?;
+    if length & 0xc0 == 0xc0 {
+      return Ok(position + 2);
+    }
+    if length == 0 {
+      return Ok(position + 1);
+    }
+    position += 1 + length as usize;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// answer to a CAA query for example.com, with these records (type and data)
+  fn answer(flags: u8, rcode: u8, records: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut packet = vec![0x12, 0x34, 0x81 | flags, 0x80 | rcode, 0x00, 0x01, 0x00, records.len() as u8, 0x00, 0x00, 0x00, 0x00];
+    packet.extend_from_slice(b"\x07example\x03com\x00");
+    packet.extend_from_slice(&TYPE_CAA.to_be_bytes());
+    packet.extend_from_slice(&[0x00, 0x01]);
+    for &(kind, data) in records.iter() {
+      // compressed name pointing to the question, class IN and TTL
+      packet.extend_from_slice(&[0xc0, 0x0c]);
+      packet.extend_from_slice(&kind.to_be_bytes());
+      packet.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x0e, 0x10]);
+      packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+      packet.extend_from_slice(data);
+    }
+    packet
+  }
+
+  #[test]
+  fn parses_caa_records() {
+    let records = parse_answer(&answer(0, 0, &[
+      (5, b"\x03www\xc0\x0c"),
+      (TYPE_CAA, b"\x00\x05issueletsencrypt.org"),
+      (TYPE_CAA, b"\x80\x03tbsUnknown"),
+    ])).unwrap();
+
+    assert_eq!(records, vec![
+      CaaRecord { flags: 0, tag: "issue".to_string(), value: "letsencrypt.org".to_string() },
+      CaaRecord { flags: FLAG_CRITICAL, tag: "tbs".to_string(), value: "Unknown".to_string() },
+    ]);
+  }
+
+  #[test]
+  fn handles_dns_errors() {
+    assert_eq!(parse_answer(&answer(0, RCODE_NXDOMAIN, &[])), Ok(Vec::new()));
+    assert_eq!(parse_answer(&answer(0, 0, &[])), Ok(Vec::new()));
+    assert!(parse_answer(&answer(0, 2, &[])).is_err());
+    assert!(parse_answer(&answer(0x02, 0, &[])).is_err());
+  }
+
+  #[test]
+  fn rejects_truncated_records() {
+    let mut packet = answer(0, 0, &[(TYPE_CAA, b"\x00\x05issueletsencrypt.org")]);
+    let length = packet.len();
+    packet.truncate(length - 4);
+    assert!(parse_answer(&packet).is_err());
+
+    assert!(parse_answer(&answer(0, 0, &[(TYPE_CAA, b"\x00\x09issue")])).is_err());
+    assert!(skip_name(b"\x07example", 0).is_err());
+    assert_eq!(skip_name(b"\x07example\x03com\x00\x01", 0), Ok(13));
+    assert_eq!(skip_name(b"\xc0\x0c", 0), Ok(2));
+  }
+}
@@ -0,0 +1,236 @@
+//! certificate signing requests and key material
+
+use std::{fs, io, net::{Ipv4Addr, Ipv6Addr}};
+use acme_lib::{create_p256_key, create_p384_key, create_rsa_key};
+use openssl::{
+  error::ErrorStack,
+  hash::{hash, MessageDigest},
+  nid::Nid,
+  pkey::{PKey, Private},
+  stack::Stack,
+  symm::Cipher,
+  x509::{X509, X509Extension, X509NameBuilder, X509Req, X509ReqBuilder, extension::SubjectAlternativeName},
+};
+
+use domain;
+
+/// generates the certificate's private key: "rsa" (2048 bits) or "ecdsa" on the "p256" or "p384" curve
+pub fn generate_key(key_type: &str, curve: &str) -> PKey<Private> {
+  match (key_type, curve) {
+    ("rsa", _)       => create_rsa_key(2048),
+    (_, "p256")      => create_p256_key(),
+    _                => create_p384_key(),
+  }
+}
+
+/// loads the private key at this path, decrypted with the passphrase if
+/// given. Returns None if there is no file yet
+pub fn load_key(path: &str, passphrase: Option<&[u8]>) -> Result<Option<PKey<Private>>, String> {
+  let pem = match fs::read(path) {
+    Ok(pem) => pem,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(format!("could not read {}: {}", path, e)),
+  };
+
+  match passphrase {
+    Some(passphrase) => PKey::private_key_from_pem_passphrase(&pem, passphrase),
+    None => PKey::private_key_from_pem(&pem),
+  }.map(Some).map_err(|e| format!("could not parse {}: {}", path, e))
+}
+
+/// the private key in PEM format: "pkcs8", "pkcs1" for RSA keys or "sec1" for
+/// ECDSA keys. Encrypted with AES-256-CBC when a passphrase is given, the
+/// PKCS#1 and SEC1 keys the traditional way, with the DEK-Info header
+pub fn key_to_pem(pkey: &PKey<Private>, format: &str, passphrase: Option<&[u8]>) -> Result<Vec<u8>, String> {
+  let cipher = Cipher::aes_256_cbc();
+  match format {
+    "pkcs1" => {
+      let rsa = pkey.rsa().map_err(|_| "PKCS#1 only encodes RSA keys".to_string())?;
+      match passphrase {
+        Some(passphrase) => rsa.private_key_to_pem_passphrase(cipher, passphrase),
+        None => rsa.private_key_to_pem(),
+      }
+    },
+    "sec1" => {
+      let ec_key = pkey.ec_key().map_err(|_| "SEC1 only encodes ECDSA keys".to_string())?;
+      match passphrase {
+        Some(passphrase) => ec_key.private_key_to_pem_passphrase(cipher, passphrase),
+        None => ec_key.private_key_to_pem(),
+      }
+    },
+    _ => match passphrase {
+      Some(passphrase) => pkey.private_key_to_pem_pkcs8_passphrase(cipher, passphrase),
+      None => pkey.private_key_to_pem_pkcs8(),
+    },
+  }.map_err(|e| e.to_string())
+}
+
+/// builds the CSR (in DER format) for the domains, the first domain name is the common name.
+/// With `must_staple`, the TLS Feature extension (RFC 7633) requires OCSP stapling
+pub fn generate_csr(pkey: &PKey<Private>, domains: &[&str], must_staple: bool) -> Result<Vec<u8>, ErrorStack> {
+  // IP addresses only go in the SAN extension
+  let mut name = X509NameBuilder::new()?;
+  if let Some(common_name) = domains.iter().find(|domain| !domain::is_ip(domain)) {
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+  }
+  let name = name.build();
+
+  let mut builder = X509ReqBuilder::new()?;
+  builder.set_subject_name(&name)?;
+  builder.set_pubkey(pkey)?;
+
+  let mut extensions = Stack::new()?;
+  let mut san = SubjectAlternativeName::new();
+  for domain in domains.iter() {
+    if domain::is_ip(domain) {
+      san.ip(domain);
+    } else {
+      san.dns(domain);
+    }
+  }
+  extensions.push(san.build(&builder.x509v3_context(None))?)?;
+  if must_staple {
+    extensions.push(X509Extension::new(None, None, "tlsfeature", "status_request")?)?;
+  }
+  builder.add_extensions(&extensions)?;
+
+  builder.sign(pkey, MessageDigest::sha256())?;
+  builder.build().to_der()
+}
+
+/// SHA-256 hash of the certificate's SubjectPublicKeyInfo, in DER: the value
+/// of HPKP pins and of the TLSA records with selector 1
+pub fn spki_sha256(certificate: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+  let spki = X509::from_pem(certificate)?.public_key()?.public_key_to_der()?;
+  Ok(hash(MessageDigest::sha256(), &spki)?.to_vec())
+}
+
+/// loads a CSR in PEM or DER format, and returns it in DER format
+pub fn load_csr(path: &str) -> Result<Vec<u8>, String> {
+  let data = fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+
+  X509Req::from_pem(&data).or_else(|_| X509Req::from_der(&data))
+    .and_then(|csr| csr.to_der())
+    .map_err(|e| format!("could not parse {}: {}", path, e))
+}
+
+/// the names the CSR requests, lowercased and sorted: the DNS names and IP
+/// addresses of its subjectAltName extension, and its common name
+pub fn csr_names(csr: &[u8]) -> Result<Vec<String>, String> {
+  let request = X509Req::from_der(csr).map_err(|e| e.to_string())?;
+  let mut names: Vec<String> = request.subject_name().entries_by_nid(Nid::COMMONNAME)
+    .filter_map(|entry| entry.data().as_utf8().ok().map(|name| name.to_string()))
+    .collect();
+
+  // the openssl crate cannot read the extensions of a request
+  if let Some(extension) = extension(csr, SAN_OID) {
+    let invalid = || "invalid subjectAltName extension".to_string();
+    let (sequence, mut general_names, _) = der_element(extension).ok_or_else(invalid)?;
+    if sequence != 0x30 {
+      return Err(invalid());
+    }
+    while !general_names.is_empty() {
+      let (tag, value, rest) = der_element(general_names).ok_or_else(invalid)?;
+      match (tag, value.len()) {
+        // dNSName
+        (0x82, _) => names.push(String::from_utf8_lossy(value).into_owned()),
+        // iPAddress
+        (0x87, 4) => names.push(Ipv4Addr::new(value[0], value[1], value[2], value[3]).to_string()),
+        (0x87, 16) => {
+          let mut octets = [0u8; 16];
+          octets.copy_from_slice(value);
+          names.push(Ipv6Addr::from(octets).to_string());
+        },
+        _ => {},
+      }
+      general_names = rest;
+    }
+  }
+
+  let mut names: Vec<String> = names.iter().map(|name| name.to_lowercase()).collect();
+  names.sort();
+  names.dedup();
+  Ok(names)
+}
+
+/// OID 2.5.29.17, subjectAltName
+const SAN_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+
+/// the content of the extension with this OID (encoded in DER, like
+/// SAN_OID) in a certificate or a request, also in DER. The extension is
+/// found by its OID: the openssl crate does not give access to all of them
+pub fn extension<'a>(der: &'a [u8], oid: &[u8]) -> Option<&'a [u8]> {
+  let start = der.windows(oid.len()).position(|window| window == oid)?;
+  let mut rest = &der[start + oid.len()..];
+  // the critical flag
+  if rest.first() == Some(&0x01) {
+    rest = der_element(rest)?.2;
+  }
+  // OCTET STRING containing the extension
+  match der_element(rest)? {
+    (0x04, value, _) => Some(value),
+    _ => None,
+  }
+}
+
+/// the tag, the value and what follows the DER element at the start of
+/// data, None if it is truncated
+pub fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+  let (&tag, &first) = (data.first()?, data.get(1)?);
+  let (length, header) = if first < 0x80 {
+    (first as usize, 2)
+  } else {
+    let count = (first & 0x7f) as usize;
+    if count == 0 || count > 4 {
+      return None;
+    }
+    let bytes = data.get(2..2 + count)?;
+    (bytes.iter().fold(0usize, |length, &b| length << 8 | b as usize), 2 + count)
+  };
+  let value = data.get(header..header + length)?;
+  Some((tag, value, &data[header + length..]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_der_elements() {
+    assert_eq!(der_element(&[0x04, 0x02, 0xaa, 0xbb, 0x05]), Some((0x04, &[0xaa, 0xbb][..], &[0x05][..])));
+
+    let mut long = vec![0x30, 0x81, 0x80];
+    long.extend(vec![0x01; 0x80]);
+    assert_eq!(der_element(&long), Some((0x30, &long[3..], &[][..])));
+
+    assert_eq!(der_element(&[0x04, 0x03, 0xaa]), None);
+    assert_eq!(der_element(&[0x04, 0x80]), None);
+    assert_eq!(der_element(&[0x04, 0x85, 0x00, 0x00, 0x00, 0x00, 0x01]), None);
+    assert_eq!(der_element(&[0x04]), None);
+  }
+
+  #[test]
+  fn finds_extensions_by_oid() {
+    let mut extension = SAN_OID.to_vec();
+    extension.extend_from_slice(&[0x04, 0x02, 0x30, 0x00]);
+    assert_eq!(super::extension(&extension, SAN_OID), Some(&[0x30, 0x00][..]));
+
+    let mut critical = SAN_OID.to_vec();
+    critical.extend_from_slice(&[0x01, 0x01, 0xff, 0x04, 0x02, 0x30, 0x00]);
+    assert_eq!(super::extension(&critical, SAN_OID), Some(&[0x30, 0x00][..]));
+
+    assert_eq!(super::extension(&[0x06, 0x03, 0x55, 0x1d, 0x23, 0x04, 0x00], SAN_OID), None);
+  }
+
+  #[test]
+  fn lists_the_requested_names() {
+    let key = generate_key("ecdsa", "p256");
+    let csr = generate_csr(&key, &["Example.com", "www.example.com", "192.0.2.1", "2001:db8::1"], false).unwrap();
+    assert_eq!(csr_names(&csr).unwrap(), vec!["192.0.2.1", "2001:db8::1", "example.com", "www.example.com"]);
+
+    let csr = generate_csr(&key, &["192.0.2.1"], true).unwrap();
+    assert_eq!(csr_names(&csr).unwrap(), vec!["192.0.2.1"]);
+
+    assert!(csr_names(&[0x30, 0x00]).is_err());
+  }
+}
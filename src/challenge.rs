@@ -0,0 +1,202 @@
+//! ACME challenges, answered through sozu
+
+use std::{
+  io, thread,
+  collections::HashMap,
+  net::SocketAddr,
+  process::Command,
+  sync::{Arc, Mutex},
+};
+use tiny_http::{Server, Response};
+
+use acme::{self, AcmeClient, AcmeError, ApiAuthorization, ApiChallenge};
+use domain;
+use exit;
+use signal;
+use sozu::{self, Transaction};
+use timings;
+
+/// temporary web server answering the HTTP-01 challenges, sozu
+/// forwards the challenge requests to it through a dedicated application
+pub struct ChallengeServer {
+  pub app_id:     String,
+  pub backend_id: String,
+  pub address:    SocketAddr,
+  /// key authorizations, indexed by request path
+  proofs:         Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl ChallengeServer {
+  /// the challenge backend and fronts are added to the application app_id
+  pub fn new(app_id: String) -> ChallengeServer {
+    let server = Server::http("127.0.0.1:0").expect("could not create HTTP server");
+    let address = server.server_addr();
+    let proofs: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    let server_proofs = proofs.clone();
+    thread::spawn(move || {
+      info!("HTTP server started");
+      loop {
+        let request = match server.recv() {
+          Ok(rq) => rq,
+          Err(e) => { error!("error: {}", e); break }
+        };
+
+        info!("got request to URL: {}", request.url());
+        let proof = server_proofs.lock().unwrap().get(request.url()).cloned();
+        match proof {
+          // the challenge can be called multiple times
+          Some(key_authorization) => {
+            request.respond(Response::from_data(key_authorization.into_bytes()).with_status_code(200));
+            info!("challenge request answered");
+          },
+          None => {
+            request.respond(Response::from_data(&b"not found"[..]).with_status_code(404));
+          }
+        }
+      }
+    });
+
+    ChallengeServer {
+      app_id,
+      backend_id: sozu::generate_backend_id(),
+      address,
+      proofs,
+    }
+  }
+}
+
+/// asks the CA to validate the challenge and waits for the result, the time
+/// around it counts as the provisioning of the challenges
+fn validate(client: &mut AcmeClient, auth_url: &str, challenge: &ApiChallenge, hostname: &str) -> Result<(), i32> {
+  timings::start("validation");
+  let validated = client.validate(auth_url, challenge, 2000);
+  timings::start("challenges");
+  match validated {
+    Ok(()) => {
+      info!("challenge validated for {}", domain::display(hostname));
+      Ok(())
+    },
+    Err(e) => {
+      error!("validation failed for {}: {}", domain::display(hostname), e);
+      match e {
+        AcmeError::Timeout(_) => Err(exit::VALIDATION_TIMEOUT),
+        _ => Err(exit::VALIDATION_FAILED),
+      }
+    }
+  }
+}
+
+fn find_challenge<'a>(auth: &'a ApiAuthorization, kind: &str) -> Option<&'a ApiChallenge> {
+  let challenge = auth.challenge(kind);
+  if challenge.is_none() {
+    error!("the CA does not offer the {} challenge for {}", kind, auth.identifier.value);
+  }
+  challenge
+}
+
+pub fn http_challenge(channel: &mut Transaction, client: &mut AcmeClient, http: &SocketAddr,
+  server: &ChallengeServer, auth_url: &str, auth: &ApiAuthorization) -> Result<(), i32> {
+  let hostname = &auth.identifier.value;
+  let challenge = match find_challenge(auth, "http-01") {
+    Some(challenge) => challenge,
+    None => return Err(exit::ACME),
+  };
+
+  let path = format!("/.well-known/acme-challenge/{}", challenge.token);
+  let key_authorization = match client.key_authorization(&challenge.token) {
+    Ok(k) => k,
+    Err(e) => {
+      error!("could not compute the key authorization: {}", e);
+      return Err(exit::ACME);
+    }
+  };
+  debug!("HTTP challenge for {}: token: {} key: {}", hostname, challenge.token, key_authorization);
+
+  server.proofs.lock().unwrap().insert(path.clone(), key_authorization);
+
+  debug!("setting up proxying for {}", hostname);
+  if let Err(e) = channel.add_challenge_front(http, &server.app_id, hostname, &path) {
+    error!("could not set up proxying to HTTP challenge server: {}", e);
+    return Err(exit::SOZU);
+  }
+
+  let ready = sozu::wait_for_configuration(channel, |channel| {
+    sozu::challenge_front_ready(channel, http, &server.app_id, hostname, &path)
+  });
+
+  let validated = if ready { validate(client, auth_url, challenge, hostname) } else { Err(exit::SOZU) };
+
+  server.proofs.lock().unwrap().remove(&path);
+  if let Err(e) = channel.remove_challenge_front(http, &server.app_id, hostname, &path) {
+    error!("could not deactivate proxying for {}: {}", hostname, e);
+    return Err(exit::SOZU);
+  }
+
+  validated
+}
+
+/// DNS-01: the key authorization digest is published in a TXT record at
+/// `_acme-challenge.<domain>`, either through the hook command or by the
+/// operator. This is the only challenge type accepted for wildcard names.
+pub fn dns_challenge(client: &mut AcmeClient, hook: Option<&str>, auth_url: &str, auth: &ApiAuthorization) -> Result<(), i32> {
+  let hostname = &auth.identifier.value;
+  let challenge = match find_challenge(auth, "dns-01") {
+    Some(challenge) => challenge,
+    None => return Err(exit::ACME),
+  };
+
+  let record = format!("_acme-challenge.{}", hostname);
+  let value = match client.key_authorization(&challenge.token).and_then(|k| acme::dns_proof(&k)) {
+    Ok(value) => value,
+    Err(e) => {
+      error!("could not compute the key authorization: {}", e);
+      return Err(exit::ACME);
+    }
+  };
+  debug!("DNS challenge for {}: {} TXT {}", hostname, record, value);
+
+  match hook {
+    Some(hook) => if !run_dns_hook(hook, "add", &record, &value) {
+      return Err(exit::FAILURE);
+    },
+    None => {
+      println!("please create the following DNS record, then press enter once it is published:");
+      println!("{}. IN TXT \"{}\"", record, value);
+      let mut line = String::new();
+      let read = io::stdin().read_line(&mut line);
+      signal::check_shutdown();
+      if let Err(e) = read {
+        error!("could not read confirmation: {}", e);
+        return Err(exit::FAILURE);
+      }
+    }
+  }
+
+  let validated = validate(client, auth_url, challenge, hostname);
+
+  let removed = match hook {
+    Some(hook) => run_dns_hook(hook, "remove", &record, &value),
+    None => {
+      println!("the DNS record {} can now be removed", record);
+      true
+    }
+  };
+
+  // a failed validation is reported over a failed removal of the record
+  validated.and(if removed { Ok(()) } else { Err(exit::FAILURE) })
+}
+
+fn run_dns_hook(hook: &str, action: &str, record: &str, value: &str) -> bool {
+  match Command::new(hook).arg(action).arg(record).arg(value).status() {
+    Ok(status) if status.success() => true,
+    Ok(status) => {
+      error!("DNS hook `{} {} {}` failed: {}", hook, action, record, status);
+      false
+    },
+    Err(e) => {
+      error!("could not run DNS hook {}: {}", hook, e);
+      false
+    }
+  }
+}
@@ -0,0 +1,47 @@
+//! posts the results of the runs to a Slack or Mattermost incoming webhook,
+//! mentioning the people on call for the domain
+
+use std::time::Duration;
+use ureq;
+
+use exit;
+
+pub struct Chat {
+  pub url:      String,
+  /// the domain pattern and the mention, like `<@U024BE7LH>` or `@here`.
+  /// `*.example.com` matches the subdomains, `*` every domain
+  pub mentions: Vec<(String, String)>,
+}
+
+impl Chat {
+  /// one message per issued certificate or failed run, the certificates not
+  /// due are not reported
+  pub fn notify(&self, certificate: &str, code: i32) -> bool {
+    let text = match code {
+      0 => format!(":white_check_mark: certificate issued for {}", certificate),
+      exit::NOT_DUE => return true,
+      _ => format!(":x: certificate run failed for {}: {} (exit status {})", certificate, exit::description(code), code),
+    };
+    let mentions: Vec<&str> = self.mentions.iter()
+      .filter(|&&(ref pattern, _)| matches(pattern, certificate))
+      .map(|&(_, ref mention)| mention.as_str())
+      .collect();
+    let text = if mentions.is_empty() || code == 0 { text } else { format!("{} {}", text, mentions.join(" ")) };
+
+    // Slack and Mattermost both take the text field
+    let res = ureq::post(&self.url)
+      .set("Content-Type", "application/json")
+      .timeout(Duration::from_secs(10))
+      .send_string(&json!({ "text": text }).to_string());
+    if res.synthetic() || res.error() {
+      error!("could not post to the chat webhook: {}", res.status_line());
+      return false;
+    }
+    true
+  }
+}
+
+fn matches(pattern: &str, domain: &str) -> bool {
+  pattern == "*" || pattern == domain
+    || (pattern.starts_with("*.") && domain.ends_with(&pattern[1..]))
+}
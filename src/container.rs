@@ -0,0 +1,53 @@
+//! copies the issued certificate to Docker Swarm or Podman secrets, for the
+//! containers next to sozu. Secrets cannot be changed, each renewal creates
+//! new versions, named after the issuance date
+
+use std::{io::Write, process::{Command, Stdio}};
+use chrono::Utc;
+
+pub struct Secrets {
+  /// docker or podman, they take the same arguments
+  pub engine: String,
+  /// the secrets are `<name>.crt.<version>` and `<name>.key.<version>`
+  pub name:   String,
+}
+
+impl Secrets {
+  /// creates the secrets of the certificate followed by its chain, and of
+  /// the key. Returns their names
+  pub fn store(&self, certificate: &str, chain: &str, key: Option<&str>) -> Result<Vec<String>, String> {
+    let key = key.ok_or_else(|| format!("the secret {} needs the private key, it is not known with --csr", self.name))?;
+    let version = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    let fullchain = format!("{}\n{}", certificate.trim_end(), chain);
+
+    let mut names = Vec::new();
+    for &(kind, content) in [("crt", fullchain.as_str()), ("key", key)].iter() {
+      let name = format!("{}.{}.{}", self.name, kind, version);
+      self.create(&name, content)?;
+      names.push(name);
+    }
+
+    info!("stored the certificate in the {} secrets {}", self.engine, names.join(" and "));
+    Ok(names)
+  }
+
+  /// the content goes through stdin, it never appears in the arguments
+  fn create(&self, name: &str, content: &str) -> Result<(), String> {
+    let mut child = Command::new(&self.engine)
+      .args(&["secret", "create", name, "-"])
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .spawn()
+      .map_err(|e| format!("could not run {}: {}", self.engine, e))?;
+
+    let written = child.stdin.take().expect("piped stdin").write_all(content.as_bytes());
+    let status = child.wait().map_err(|e| format!("could not run {}: {}", self.engine, e))?;
+    if let Err(e) = written {
+      return Err(format!("could not send the secret {} to {}: {}", name, self.engine, e));
+    }
+    if !status.success() {
+      return Err(format!("{} could not create the secret {}: {}", self.engine, name, status));
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,96 @@
+//! control socket of the daemon: `sozu-acme ctl` sends a command line, the
+//! daemon answers with text and closes the connection
+
+use std::{
+  fs, thread,
+  io::{BufRead, BufReader, Read, Write},
+  os::unix::net::{UnixListener, UnixStream},
+  sync::mpsc::{self, Receiver},
+  time::Duration,
+};
+
+/// default path of the control socket, in the daemon's directory
+pub const CONTROL_SOCKET: &str = "sozu-acme.sock";
+
+/// how long a client has to send its command
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// a command received on the control socket, answered with reply
+pub struct Request {
+  pub command:   String,
+  pub arguments: Vec<String>,
+  stream:        UnixStream,
+}
+
+impl Request {
+  pub fn reply(mut self, result: Result<String, String>) {
+    let text = match result {
+      Ok(text) => text,
+      Err(e) => format!("error: {}\n", e),
+    };
+    if let Err(e) = self.stream.write_all(text.as_bytes()) {
+      warn!("could not answer the control command {}: {}", self.command, e);
+    }
+  }
+}
+
+/// accepts the connections in a thread, the requests are handled by the daemon's loop
+pub fn listen(path: &str) -> Result<Receiver<Request>, String> {
+  let _ = fs::remove_file(path);
+  let listener = UnixListener::bind(path).map_err(|e| format!("could not listen on {}: {}", path, e))?;
+
+  let (sender, receiver) = mpsc::channel();
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+          error!("could not accept a control connection: {}", e);
+          continue;
+        }
+      };
+
+      let mut line = String::new();
+      let read = stream.set_read_timeout(Some(READ_TIMEOUT))
+        .and_then(|_| BufReader::new(&stream).read_line(&mut line));
+      if let Err(e) = read {
+        warn!("could not read the control command: {}", e);
+        continue;
+      }
+
+      let mut words = line.split_whitespace().map(|word| word.to_string());
+      let command = match words.next() {
+        Some(command) => command,
+        None => continue,
+      };
+      // the daemon stopped
+      if sender.send(Request { command, arguments: words.collect(), stream }).is_err() {
+        break;
+      }
+    }
+  });
+
+  Ok(receiver)
+}
+
+/// sends the command to the daemon and prints its answer, returns false if it failed
+pub fn send(path: &str, command: &[&str]) -> bool {
+  let mut stream = match UnixStream::connect(path) {
+    Ok(stream) => stream,
+    Err(e) => {
+      error!("could not connect to the daemon on {}: {}", path, e);
+      return false;
+    }
+  };
+
+  let mut answer = String::new();
+  let exchange = stream.write_all(format!("{}\n", command.join(" ")).as_bytes())
+    .and_then(|_| stream.read_to_string(&mut answer));
+  if let Err(e) = exchange {
+    error!("could not talk to the daemon on {}: {}", path, e);
+    return false;
+  }
+
+  print!("{}", answer);
+  !answer.starts_with("error:")
+}
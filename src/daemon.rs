@@ -0,0 +1,566 @@
+//! keeps the certificates installed by previous runs, or described in a
+//! batch file, renewed
+
+use std::{cmp, env, fs, thread, collections::HashMap, net::SocketAddr, process::{self, Command},
+  sync::mpsc::RecvTimeoutError, time::{Duration, Instant}};
+use chrono::Local;
+use openssl::x509::X509;
+use serde_json::Value;
+
+use admin;
+use batch;
+use control::{self, Request};
+use domain;
+use exit;
+use lock;
+use renewal;
+use signal;
+use sozu;
+use systemd;
+use watch;
+use schedule::Schedule;
+use state::{State, ManagedCertificate};
+
+/// how often the schedules are evaluated
+const TICK: Duration = Duration::from_secs(30);
+
+/// delay before retrying a failed renewal, doubled at each failure up to MAX_RETRY_DELAY
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(12 * 3600);
+
+/// failed renewals and issuances, by domain set or hostname: number of
+/// failures and time of the next attempt. They are retried on their own
+/// timing, the other certificates keep their schedule
+#[derive(Default)]
+struct Retries {
+  pending: HashMap<String, (u32, Instant)>,
+  /// date (RFC 3339) and success of the latest attempt
+  last:    HashMap<String, (String, bool)>,
+}
+
+impl Retries {
+  /// records a failure, returns the delay before the next attempt
+  fn failed(&mut self, key: &str) -> Duration {
+    self.last.insert(key.to_string(), (Local::now().to_rfc3339(), false));
+    let failures = self.pending.get(key).map(|&(failures, _)| failures).unwrap_or(0) + 1;
+    let delay = RETRY_DELAY.checked_mul(1 << cmp::min(failures - 1, 16))
+      .map(|delay| cmp::min(delay, MAX_RETRY_DELAY))
+      .unwrap_or(MAX_RETRY_DELAY);
+    self.pending.insert(key.to_string(), (failures, Instant::now() + delay));
+    delay
+  }
+
+  fn succeeded(&mut self, key: &str) {
+    self.last.insert(key.to_string(), (Local::now().to_rfc3339(), true));
+    self.pending.remove(key);
+  }
+
+  /// nothing was attempted, the key is not waiting for a retry anymore
+  fn not_needed(&mut self, key: &str) {
+    self.pending.remove(key);
+  }
+
+  /// time of the next attempt, if the key is waiting for a retry
+  fn next_attempt(&self, key: &str) -> Option<Instant> {
+    self.pending.get(key).map(|&(_, next)| next)
+  }
+
+  /// whether the key waits for a retry that is not due yet
+  fn waiting(&self, key: &str) -> bool {
+    self.next_attempt(key).map(|next| Instant::now() < next).unwrap_or(false)
+  }
+}
+
+/// issuance of certificates for the HTTPS fronts sozu has no valid certificate for
+pub struct AutoIssue {
+  /// sozu configuration file
+  pub config:       String,
+  /// HTTP listener, for the challenge
+  pub http:         String,
+  /// where the certificates, chains and keys are written
+  pub directory:    String,
+  /// account and CA options given to each run
+  pub arguments:    Vec<String>,
+  /// time between two looks at sozu's fronts
+  pub interval:     Duration,
+}
+
+/// checks the managed certificates, and runs the command that installed a
+/// certificate again when it expires in less than renew_days. A certificate
+/// is checked when its schedule (the --schedule of its command line, or the
+/// daemon's) matches, or at each interval without schedule.
+/// With a batch file, its certificates are managed instead of those of the
+/// state file, and it is read again on SIGHUP or when it changes.
+/// With auto_issue, new HTTPS fronts get a certificate.
+/// The daemon is controlled with the commands sent on control_socket, and
+/// its state is served over HTTP on admin_address
+pub fn run(interval: Duration, renew_days: u32, schedule: Option<Schedule>, file: Option<&str>, auto_issue: Option<AutoIssue>,
+  control_socket: &str, admin_address: Option<&str>) -> ! {
+  let source = file.unwrap_or(::state::STATE_FILE);
+  match schedule {
+    Some(ref schedule) => info!("renewing the certificates of {} on schedule \"{}\"", source, schedule),
+    None => info!("renewing the certificates of {} every {} seconds", source, interval.as_secs()),
+  }
+
+  signal::handle_reload();
+  let mut file_certificates = file.map(|file| load_file(file).unwrap_or_else(|e| {
+    error!("could not load {}: {}", file, e);
+    process::exit(exit::FAILURE);
+  }));
+  if let Some(file) = file {
+    if let Err(e) = watch::watch(file) {
+      warn!("{}, send SIGHUP to apply its changes", e);
+    }
+  }
+
+  let socket = auto_issue.as_ref().map(|auto_issue| {
+    info!("issuing certificates for the new HTTPS fronts of {} every {} seconds", auto_issue.config, auto_issue.interval.as_secs());
+    sozu::command_socket(&auto_issue.config).unwrap_or_else(|e| {
+      error!("{}", e);
+      process::exit(exit::FAILURE);
+    })
+  });
+
+  let requests = match control::listen(control_socket) {
+    Ok(requests) => Some(requests),
+    Err(e) => {
+      error!("{}, the daemon cannot be controlled", e);
+      None
+    }
+  };
+
+  let admin_status = admin_address.map(|address| admin::serve(address).unwrap_or_else(|e| {
+    error!("{}", e);
+    process::exit(exit::FAILURE);
+  }));
+
+  let watchdog = systemd::watchdog_interval();
+  systemd::notify("READY=1\nSTATUS=watching the certificates");
+
+  let mut next_check = Instant::now();
+  let mut next_watch = Instant::now();
+  let mut last_minute = None;
+  let mut retries = Retries::default();
+  let mut paused = false;
+  loop {
+    if watchdog.is_some() {
+      systemd::notify("WATCHDOG=1");
+    }
+    let now = Local::now();
+    // a schedule matches once per minute
+    let minute = now.timestamp() / 60;
+    let new_minute = last_minute != Some(minute);
+    last_minute = Some(minute);
+    let interval_elapsed = Instant::now() >= next_check;
+    if interval_elapsed {
+      next_check = Instant::now() + interval;
+    }
+
+    // the renewals of the previous iteration are done, the new list applies to the next ones
+    if let Some(file) = file.filter(|_| signal::reload_requested()) {
+      match load_file(file) {
+        Ok(certificates) => {
+          info!("reloaded {}: {} certificates", file, certificates.len());
+          file_certificates = Some(certificates);
+        },
+        Err(e) => error!("could not reload {}, keeping the previous certificates: {}", file, e),
+      }
+    }
+
+    if let (Some(auto_issue), Some(socket)) = (auto_issue.as_ref(), socket.as_ref()) {
+      if !paused && Instant::now() >= next_watch {
+        next_watch = Instant::now() + auto_issue.interval;
+        issue_missing(auto_issue, socket, &mut retries, watchdog);
+      }
+    }
+
+    let certificates: Vec<(String, ManagedCertificate)> = match file_certificates {
+      Some(ref certificates) => certificates.clone(),
+      None => State::load().certificates.into_iter().collect(),
+    };
+    if certificates.is_empty() && interval_elapsed && !paused {
+      warn!("no managed certificate yet, they are added by a successful run");
+    }
+
+    for &(ref domains, ref managed) in certificates.iter() {
+      let check = match retries.next_attempt(domains) {
+        Some(next) => Instant::now() >= next,
+        None => match certificate_schedule(managed).or_else(|| schedule.clone()) {
+          Some(schedule) => new_minute && schedule.matches(&now),
+          None => interval_elapsed,
+        },
+      };
+      if paused || !check {
+        continue;
+      }
+
+      let due = match fs::read(&managed.certificate) {
+        Ok(certificate) => renewal::expires_within(&certificate, renew_days),
+        Err(e) => {
+          warn!("could not read {}: {}", managed.certificate, e);
+          true
+        }
+      };
+
+      if due {
+        info!("renewing the certificate for {}", domains);
+        systemd::notify(&format!("STATUS=renewing the certificate for {}", domains));
+        if run_command(renewal_arguments(managed, Some(renew_days)), watchdog) {
+          retries.succeeded(domains);
+        } else {
+          let delay = retries.failed(domains);
+          error!("could not renew the certificate for {}, retrying in {} seconds", domains, delay.as_secs());
+        }
+        systemd::notify("STATUS=watching the certificates");
+      } else {
+        retries.not_needed(domains);
+        debug!("the certificate for {} is not due for renewal", domains);
+      }
+    }
+
+    if let Some(ref admin_status) = admin_status {
+      *admin_status.lock().unwrap() = status_json(&certificates, &retries, paused);
+    }
+
+    let mut sleep = cmp::min(TICK, next_check.saturating_duration_since(Instant::now()));
+    if auto_issue.is_some() {
+      sleep = cmp::min(sleep, next_watch.saturating_duration_since(Instant::now()));
+    }
+    let sleep = watchdog.map(|watchdog| cmp::min(watchdog, sleep)).unwrap_or(sleep);
+    // a control command wakes the loop up
+    match requests.as_ref().map(|requests| requests.recv_timeout(sleep)) {
+      Some(Ok(request)) => handle_request(request, &certificates, &mut retries, &mut paused, renew_days, watchdog),
+      Some(Err(RecvTimeoutError::Timeout)) => {},
+      Some(Err(RecvTimeoutError::Disconnected)) | None => thread::sleep(sleep),
+    }
+  }
+}
+
+/// answers a command of the control socket: status, renew <domain>, pause or resume
+fn handle_request(request: Request, certificates: &[(String, ManagedCertificate)], retries: &mut Retries, paused: &mut bool,
+  renew_days: u32, watchdog: Option<Duration>) {
+  debug!("control command: {} {}", request.command, request.arguments.join(" "));
+  let result = match (request.command.as_str(), request.arguments.first()) {
+    ("status", None) => Ok(status(certificates, retries, *paused)),
+    ("pause", None) => {
+      *paused = true;
+      info!("paused, the certificates are not renewed until resumed");
+      systemd::notify("STATUS=paused");
+      Ok("paused\n".to_string())
+    },
+    ("resume", None) => {
+      *paused = false;
+      info!("resumed");
+      systemd::notify("STATUS=watching the certificates");
+      Ok("resumed\n".to_string())
+    },
+    ("renew", Some(domain)) => renew_now(domain, certificates, retries, renew_days, watchdog),
+    (command, _) => Err(format!("invalid command {:?}, expected status, renew <domain>, pause or resume", command)),
+  };
+  request.reply(result);
+}
+
+/// whether the daemon is paused, then the expiration date and retry state of each certificate
+fn status(certificates: &[(String, ManagedCertificate)], retries: &Retries, paused: bool) -> String {
+  let mut status = format!("{}\n", if paused { "paused" } else { "running" });
+  for &(ref domains, ref managed) in certificates.iter() {
+    let not_after = fs::read(&managed.certificate).ok()
+      .and_then(|pem| X509::from_pem(&pem).ok())
+      .map(|cert| cert.not_after().to_string())
+      .unwrap_or_else(|| "unknown".to_string());
+    let retry = match retries.pending.get(domains) {
+      Some(&(failures, next)) => format!("{} failed attempts, retrying in {} seconds", failures,
+        next.saturating_duration_since(Instant::now()).as_secs()),
+      None => "ok".to_string(),
+    };
+    status.push_str(&format!("{}\t{}\t{}\n", domains, not_after, retry));
+  }
+  status
+}
+
+/// the managed certificates and the latest renewal results, for the admin endpoint
+fn status_json(certificates: &[(String, ManagedCertificate)], retries: &Retries, paused: bool) -> Value {
+  let certificates: Vec<Value> = certificates.iter().map(|&(ref domains, ref managed)| {
+    let not_after = fs::read(&managed.certificate).ok()
+      .and_then(|pem| X509::from_pem(&pem).ok())
+      .map(|cert| cert.not_after().to_string());
+    let last = retries.last.get(domains).map(|&(ref at, success)| json!({ "at": at, "success": success }));
+    let retry = retries.pending.get(domains).map(|&(failures, next)| json!({
+      "failures": failures,
+      "next_attempt_in": next.saturating_duration_since(Instant::now()).as_secs(),
+    }));
+    json!({
+      "domains": domains.split(',').collect::<Vec<_>>(),
+      "certificate": managed.certificate,
+      "fingerprint": managed.fingerprint,
+      "issued_at": managed.issued_at,
+      "renewed_at": managed.renewed_at,
+      "not_after": not_after,
+      "last_attempt": last,
+      "retry": retry,
+    })
+  }).collect();
+
+  json!({
+    "paused": paused,
+    "certificates": certificates,
+  })
+}
+
+/// renews the certificate covering the domain at once, even if it is not due
+fn renew_now(domain: &str, certificates: &[(String, ManagedCertificate)], retries: &mut Retries,
+  renew_days: u32, watchdog: Option<Duration>) -> Result<String, String> {
+  let domain = domain::to_ascii(domain)?.to_lowercase();
+  let &(ref domains, ref managed) = certificates.iter()
+    .find(|&&(ref domains, _)| domains.split(',').any(|d| d == domain))
+    .ok_or_else(|| format!("no managed certificate for {}", domain))?;
+
+  info!("renewing the certificate for {} on request", domains);
+  systemd::notify(&format!("STATUS=renewing the certificate for {}", domains));
+  let mut arguments = renewal_arguments(managed, Some(renew_days));
+  arguments.push("--force-renewal".to_string());
+  let renewed = run_command(arguments, watchdog);
+  systemd::notify("STATUS=watching the certificates");
+
+  if renewed {
+    retries.succeeded(domains);
+    Ok(format!("renewed the certificate for {}\n", domains))
+  } else {
+    retries.failed(domains);
+    Err(format!("could not renew the certificate for {}", domains))
+  }
+}
+
+/// the certificates of the batch file, with their `renew` command line
+fn load_file(file: &str) -> Result<Vec<(String, ManagedCertificate)>, String> {
+  batch::load(file)?.into_iter().map(|(name, arguments)| {
+    let certificate = option_value(&arguments, "--certificate")
+      .ok_or_else(|| format!("{}: no certificate path", name))?
+      .to_string();
+    Ok((name, ManagedCertificate { certificate, arguments, ..ManagedCertificate::default() }))
+  }).collect()
+}
+
+/// the --schedule option of the certificate's command line
+fn certificate_schedule(managed: &ManagedCertificate) -> Option<Schedule> {
+  let expression = option_value(&managed.arguments, "--schedule")?;
+  Schedule::parse(expression).map_err(|e| warn!("ignoring the schedule of {}: {}", managed.certificate, e)).ok()
+}
+
+/// value of the option in a command line
+fn option_value<'a>(arguments: &'a [String], option: &str) -> Option<&'a str> {
+  let prefix = format!("{}=", option);
+  let mut arguments = arguments.iter();
+  loop {
+    match arguments.next() {
+      Some(argument) if argument == option => return arguments.next().map(|value| value.as_str()),
+      Some(argument) if argument.starts_with(&prefix) => return Some(&argument[prefix.len()..]),
+      Some(_) => continue,
+      None => return None,
+    }
+  }
+}
+
+/// requests a certificate for each HTTPS front of sozu that has no valid
+/// certificate on its listener, and is not managed yet. Wildcard hostnames
+/// are skipped, they need dns-01, and so are the failed hostnames until
+/// their retry is due
+fn issue_missing(auto_issue: &AutoIssue, socket: &str, retries: &mut Retries, watchdog: Option<Duration>) {
+  let mut channel = match sozu::try_connect(&[socket.to_string()]) {
+    Ok(channel) => channel,
+    Err(e) => {
+      error!("could not look for new HTTPS fronts: {}", e);
+      return;
+    }
+  };
+  let fronts = match sozu::https_fronts(&mut channel) {
+    Some(fronts) => fronts,
+    None => {
+      error!("could not query sozu's HTTPS fronts");
+      return;
+    }
+  };
+
+  let state = State::load();
+  let mut missing: Vec<(String, SocketAddr, String)> = Vec::new();
+  for (app_id, frontend, hostname) in fronts {
+    if hostname.starts_with("*.") || state.managed(&hostname).is_some() || retries.waiting(&hostname)
+      || missing.iter().any(|front| front.2 == hostname) {
+      continue;
+    }
+    let valid = sozu::certificate_fingerprint(&mut channel, &frontend, &hostname)
+      .and_then(|fingerprint| sozu::certificate_by_fingerprint(&mut channel, &fingerprint))
+      .map(|(certificate, _)| !renewal::expires_within(certificate.as_bytes(), 0))
+      .unwrap_or(false);
+    if !valid {
+      missing.push((app_id, frontend, hostname));
+    }
+  }
+  // the runs connect on their own
+  drop(channel);
+
+  for (app_id, frontend, hostname) in missing {
+    info!("issuing a certificate for the new HTTPS front {} of {}", hostname, app_id);
+    systemd::notify(&format!("STATUS=issuing a certificate for {}", hostname));
+    let path = |extension: &str| format!("{}/{}.{}", auto_issue.directory.trim_end_matches('/'), hostname, extension);
+    let mut arguments = vec![
+      "issue".to_string(),
+      "--config".to_string(), auto_issue.config.clone(),
+      "--id".to_string(), app_id.clone(),
+      "--domain".to_string(), hostname.clone(),
+      "--http".to_string(), auto_issue.http.clone(),
+      "--https".to_string(), frontend.to_string(),
+      "--certificate".to_string(), path("pem"),
+      "--chain".to_string(), path("chain.pem"),
+      "--key".to_string(), path("key"),
+      // the front already exists
+      "--cert-only".to_string(),
+    ];
+    arguments.extend(auto_issue.arguments.iter().cloned());
+
+    if run_command(arguments, watchdog) {
+      retries.succeeded(&hostname);
+    } else {
+      let delay = retries.failed(&hostname);
+      error!("could not issue a certificate for {}, retrying in {} seconds at the earliest", hostname, delay.as_secs());
+    }
+    systemd::notify("STATUS=watching the certificates");
+  }
+}
+
+/// runs sozu-acme with these arguments, in a separate process so a failure
+/// does not stop the daemon. The watchdog is notified while it runs. It
+/// fails if another run holds the lock
+fn run_command(arguments: Vec<String>, watchdog: Option<Duration>) -> bool {
+  let _lock = match lock::acquire(Duration::from_secs(0)) {
+    Ok(lock) => lock,
+    Err(e) => {
+      error!("{}", e);
+      return false;
+    }
+  };
+
+  let executable = match env::current_exe() {
+    Ok(executable) => executable,
+    Err(e) => {
+      error!("could not find the sozu-acme executable: {}", e);
+      return false;
+    }
+  };
+
+  let mut child = match Command::new(executable).args(arguments).env(lock::LOCK_HELD_VARIABLE, "1").spawn() {
+    Ok(child) => child,
+    Err(e) => {
+      error!("could not run sozu-acme: {}", e);
+      return false;
+    }
+  };
+
+  let poll = watchdog.map(|watchdog| cmp::min(watchdog, Duration::from_secs(1))).unwrap_or(Duration::from_secs(1));
+  loop {
+    match child.try_wait() {
+      Ok(Some(status)) => return status.success() || status.code() == Some(exit::NOT_DUE),
+      Ok(None) => {
+        if watchdog.is_some() {
+          systemd::notify("WATCHDOG=1");
+        }
+        thread::sleep(poll);
+      },
+      Err(e) => {
+        error!("could not wait for sozu-acme: {}", e);
+        return false;
+      }
+    }
+  }
+}
+
+/// the options taking a value that apply to every subcommand
+const GLOBAL_OPTIONS: &[&str] = &["--log-format", "--syslog", "--syslog-facility", "--audit-log", "--lock-timeout"];
+
+/// the `renew` command line of a managed certificate: the arguments of the
+/// installing run, with the current certificate as the one to replace, and
+/// the daemon's renewal threshold if there is one
+pub fn renewal_arguments(managed: &ManagedCertificate, renew_days: Option<u32>) -> Vec<String> {
+  let mut replaced = vec!["--old-certificate"];
+  if renew_days.is_some() {
+    replaced.push("--renew-days");
+  }
+  // a forced renewal is not repeated
+  const REMOVED: &[&str] = &["--force-renewal"];
+
+  // the global options can come before the subcommand
+  let mut recorded = managed.arguments.clone();
+  let mut subcommand = 0;
+  while let Some(argument) = recorded.get(subcommand) {
+    if GLOBAL_OPTIONS.iter().any(|option| argument.starts_with(&format!("{}=", option))) {
+      subcommand += 1;
+    } else if GLOBAL_OPTIONS.contains(&argument.as_str()) {
+      subcommand += 2;
+    } else {
+      break;
+    }
+  }
+  if recorded.get(subcommand).map(|command| command == "renew" || command == "issue").unwrap_or(false) {
+    recorded.remove(subcommand);
+  }
+
+  let mut arguments = vec!["renew".to_string()];
+  let mut skip_value = false;
+  for argument in recorded.iter() {
+    if skip_value {
+      skip_value = false;
+    } else if replaced.contains(&argument.as_str()) {
+      skip_value = true;
+    } else if !REMOVED.contains(&argument.as_str()) && !replaced.iter().any(|option| argument.starts_with(&format!("{}=", option))) {
+      arguments.push(argument.clone());
+    }
+  }
+
+  arguments.push("--old-certificate".to_string());
+  arguments.push(managed.certificate.clone());
+  if let Some(renew_days) = renew_days {
+    arguments.push("--renew-days".to_string());
+    arguments.push(renew_days.to_string());
+  }
+  arguments
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn managed(arguments: &[&str]) -> ManagedCertificate {
+    ManagedCertificate {
+      certificate: "/etc/sozu/cert.pem".to_string(),
+      arguments:   arguments.iter().map(|argument| argument.to_string()).collect(),
+      ..ManagedCertificate::default()
+    }
+  }
+
+  fn renewal(arguments: &[&str], renew_days: Option<u32>) -> Vec<String> {
+    renewal_arguments(&managed(arguments), renew_days)
+  }
+
+  #[test]
+  fn replaces_the_subcommand() {
+    let expected = vec!["renew", "--domain", "example.com", "--old-certificate", "/etc/sozu/cert.pem"];
+    assert_eq!(renewal(&["issue", "--domain", "example.com"], None), expected);
+    assert_eq!(renewal(&["renew", "--domain", "example.com"], None), expected);
+    // recorded without subcommand, the deprecated way
+    assert_eq!(renewal(&["--domain", "example.com"], None), expected);
+  }
+
+  #[test]
+  fn strips_the_subcommand_after_global_options() {
+    assert_eq!(renewal(&["--log-format", "json", "issue", "--domain", "example.com"], None),
+      vec!["renew", "--log-format", "json", "--domain", "example.com", "--old-certificate", "/etc/sozu/cert.pem"]);
+    assert_eq!(renewal(&["--syslog=unix:/dev/log", "--lock-timeout", "60", "renew", "--domain", "example.com"], None),
+      vec!["renew", "--syslog=unix:/dev/log", "--lock-timeout", "60", "--domain", "example.com", "--old-certificate", "/etc/sozu/cert.pem"]);
+  }
+
+  #[test]
+  fn replaces_the_renewal_options() {
+    assert_eq!(renewal(&["issue", "--domain", "example.com", "--old-certificate", "old.pem", "--renew-days=10", "--force-renewal"], Some(20)),
+      vec!["renew", "--domain", "example.com", "--old-certificate", "/etc/sozu/cert.pem", "--renew-days", "20"]);
+  }
+}
@@ -0,0 +1,64 @@
+//! domain name handling
+
+use std::{fs, net::IpAddr};
+use idna;
+
+/// IP address identifiers (RFC 8738) are accepted in place of domain names
+pub fn is_ip(name: &str) -> bool {
+  name.parse::<IpAddr>().is_ok()
+}
+
+/// converts an internationalized domain name to its ASCII (punycode) form,
+/// keeping the wildcard label. IP addresses are only normalized
+pub fn to_ascii(domain: &str) -> Result<String, String> {
+  if let Ok(ip) = domain.parse::<IpAddr>() {
+    return Ok(ip.to_string());
+  }
+
+  let (wildcard, name) = if domain.starts_with("*.") {
+    ("*.", &domain[2..])
+  } else {
+    ("", domain)
+  };
+
+  idna::domain_to_ascii(name)
+    .map(|ascii| format!("{}{}", wildcard, ascii))
+    .map_err(|e| format!("invalid domain name {}: {:?}", domain, e))
+}
+
+/// the domain in both forms, for logs
+pub fn display(ascii: &str) -> String {
+  if is_ip(ascii) {
+    return ascii.to_string();
+  }
+
+  let (unicode, result) = idna::domain_to_unicode(ascii);
+  if result.is_ok() && unicode != ascii {
+    format!("{} ({})", unicode, ascii)
+  } else {
+    ascii.to_string()
+  }
+}
+
+/// reads a list of hostnames exported from an inventory: one certificate per
+/// line, with its names separated by spaces. Empty lines and everything after
+/// a `#` are ignored
+pub fn read_list(path: &str) -> Result<Vec<Vec<String>>, String> {
+  let data = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+
+  let mut lines = Vec::new();
+  for (number, line) in data.lines().enumerate() {
+    let line = line.split('#').next().unwrap_or("");
+    let names = line.split_whitespace()
+      .map(|name| to_ascii(name).map_err(|e| format!("{} line {}: {}", path, number + 1, e)))
+      .collect::<Result<Vec<_>, String>>()?;
+    if !names.is_empty() {
+      lines.push(names);
+    }
+  }
+
+  if lines.is_empty() {
+    return Err(format!("no hostname in {}", path));
+  }
+  Ok(lines)
+}
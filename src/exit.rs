@@ -0,0 +1,53 @@
+//! exit codes of the certificate requests, so scripts and systemd units
+//! can tell the outcomes apart
+
+use std::panic::{self, AssertUnwindSafe};
+
+/// invalid options, unreadable files, and the failures without a code of their own
+pub const FAILURE: i32 = 1;
+/// the certificate is not due for renewal, with `renew`
+pub const NOT_DUE: i32 = 3;
+/// the CA could not be reached, or refused a request
+pub const ACME: i32 = 4;
+/// sozu could not be reached, or did not apply an order
+pub const SOZU: i32 = 5;
+/// a domain was still not validated, or the order not finalized, after --validation-timeout
+pub const VALIDATION_TIMEOUT: i32 = 6;
+/// the CA could not validate a domain
+pub const VALIDATION_FAILED: i32 = 7;
+/// the certificate was installed, but could not be copied to a storage backend
+pub const STORAGE: i32 = 8;
+/// the run was stopped by SIGINT or SIGTERM
+pub const INTERRUPTED: i32 = 130;
+
+/// the payload of a failure, it unwinds so the changes made to sozu are undone
+struct Failure(i32);
+
+/// logs the message and unwinds, the process exits with the code
+pub fn fail(code: i32, message: &str) -> ! {
+  error!("{}", message);
+  panic::resume_unwind(Box::new(Failure(code)))
+}
+
+/// runs the command, returns the exit code of its failure or its own
+pub fn run<F: FnOnce() -> i32>(command: F) -> i32 {
+  match panic::catch_unwind(AssertUnwindSafe(command)) {
+    Ok(code) => code,
+    Err(payload) => payload.downcast::<Failure>().map(|failure| failure.0).unwrap_or(FAILURE),
+  }
+}
+
+/// what the exit code of a run means
+pub fn description(code: i32) -> &'static str {
+  match code {
+    0 => "done",
+    NOT_DUE => "not due",
+    ACME => "ACME error",
+    SOZU => "sozu error",
+    VALIDATION_TIMEOUT => "validation timeout",
+    VALIDATION_FAILED => "validation failed",
+    STORAGE => "storage error",
+    INTERRUPTED => "interrupted",
+    _ => "failed",
+  }
+}
@@ -0,0 +1,171 @@
+//! writes the certificate files atomically, with the permissions and owner
+//! that let the sozu user read them, and nobody else read the key. The
+//! replaced files can be kept in a backup directory, or in certbot's archive
+
+use std::{fs::{self, OpenOptions}, io::{self, Write}, ffi::CString, process,
+  os::unix::{ffi::OsStrExt, fs::{symlink, OpenOptionsExt, PermissionsExt}}, path::Path};
+use chrono::Utc;
+use libc;
+
+/// the owner given to the written files, the process' one by default
+pub struct Owner {
+  pub uid: Option<u32>,
+  pub gid: Option<u32>,
+}
+
+impl Owner {
+  /// the user and group are names or numeric ids
+  pub fn new(user: Option<&str>, group: Option<&str>) -> Result<Owner, String> {
+    let uid = match user {
+      Some(user) => Some(user.parse::<u32>().or_else(|_| user_id(user))?),
+      None => None,
+    };
+    let gid = match group {
+      Some(group) => Some(group.parse::<u32>().or_else(|_| group_id(group))?),
+      None => None,
+    };
+    Ok(Owner { uid, gid })
+  }
+}
+
+fn user_id(name: &str) -> Result<u32, String> {
+  let c_name = CString::new(name).map_err(|e| e.to_string())?;
+  let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+  if passwd.is_null() {
+    return Err(format!("unknown user {}", name));
+  }
+  Ok(unsafe { (*passwd).pw_uid })
+}
+
+fn group_id(name: &str) -> Result<u32, String> {
+  let c_name = CString::new(name).map_err(|e| e.to_string())?;
+  let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+  if group.is_null() {
+    return Err(format!("unknown group {}", name));
+  }
+  Ok(unsafe { (*group).gr_gid })
+}
+
+/// replaces the file atomically: the content is written to a temporary file
+/// next to it, synced, then renamed over it, so sozu and the other readers
+/// never see a truncated file, even after a crash. The temporary file is
+/// created with the mode so it is never readable by others, or with the
+/// permissions of the replaced file, or the umask's default
+pub fn write(path: &str, content: &[u8], mode: Option<u32>, owner: &Owner) -> io::Result<()> {
+  let path = Path::new(path);
+  let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+  let directory = match path.parent() {
+    Some(directory) if !directory.as_os_str().is_empty() => directory,
+    _ => Path::new("."),
+  };
+  let temporary = directory.join(format!(".{}.{}.tmp", name.to_string_lossy(), process::id()));
+
+  let mode = mode.or_else(|| fs::metadata(path).ok().map(|metadata| metadata.permissions().mode() & 0o7777));
+  let mut options = OpenOptions::new();
+  options.write(true).create_new(true);
+  if let Some(mode) = mode {
+    options.mode(mode);
+  }
+
+  let written = options.open(&temporary).and_then(|mut file| {
+    // the mode given at creation is reduced by the umask
+    if let Some(mode) = mode {
+      file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    chown(&temporary, owner)?;
+    file.write_all(content)?;
+    file.sync_all()
+  }).and_then(|_| fs::rename(&temporary, path));
+  if written.is_err() {
+    let _ = fs::remove_file(&temporary);
+  }
+  written?;
+
+  // the rename is durable once the directory is synced
+  fs::File::open(directory).and_then(|directory| directory.sync_all())
+}
+
+/// certbot's layout: the files are written as `archive/<name>/<file>N.pem`,
+/// the next version, then `live/<name>/<file>.pem` is replaced by a relative
+/// link to them. Returns the version
+pub fn write_version(base: &str, name: &str, files: &[(&str, &[u8], Option<u32>)], owner: &Owner) -> io::Result<u32> {
+  let base = base.trim_end_matches('/');
+  let (live, archive) = (format!("{}/live/{}", base, name), format!("{}/archive/{}", base, name));
+  fs::create_dir_all(&live)?;
+  fs::create_dir_all(&archive)?;
+
+  let version = fs::read_dir(&archive)?
+    .filter_map(|entry| entry.ok().map(|entry| entry.file_name().to_string_lossy().into_owned()))
+    .filter_map(|entry| files.iter()
+      .filter(|&&(file, _, _)| entry.starts_with(file) && entry.ends_with(".pem") && entry.len() > file.len() + 4)
+      .filter_map(|&(file, _, _)| entry[file.len()..entry.len() - 4].parse::<u32>().ok())
+      .next())
+    .max().unwrap_or(0) + 1;
+
+  for &(file, content, mode) in files {
+    write(&format!("{}/{}{}.pem", archive, file, version), content, mode, owner)?;
+  }
+
+  // the links are replaced by renaming, like the files
+  for &(file, _, _) in files {
+    let link = format!("{}/{}.pem", live, file);
+    let temporary = format!("{}/.{}.pem.{}.tmp", live, file, process::id());
+    let _ = fs::remove_file(&temporary);
+    symlink(format!("../../archive/{}/{}{}.pem", name, file, version), &temporary)?;
+    if let Err(e) = fs::rename(&temporary, &link) {
+      let _ = fs::remove_file(&temporary);
+      return Err(e);
+    }
+  }
+  fs::File::open(&live).and_then(|live| live.sync_all())?;
+  Ok(version)
+}
+
+/// like openssl's `-pass file:`, the passphrase is the first line of the file
+pub fn read_passphrase(path: &str) -> Result<Vec<u8>, String> {
+  fs::read_to_string(path)
+    .map(|data| data.lines().next().unwrap_or("").as_bytes().to_vec())
+    .map_err(|e| format!("could not read the passphrase file {}: {}", path, e))
+}
+
+/// copies the existing files to a new `<directory>/<date>` directory, with
+/// their permissions, and removes the oldest backups to keep `keep` of them.
+/// Returns the new backup, none when there is no file to keep
+pub fn backup(directory: &str, paths: &[&str], keep: usize) -> io::Result<Option<String>> {
+  let existing: Vec<&Path> = paths.iter().map(Path::new).filter(|path| path.is_file()).collect();
+  if existing.is_empty() {
+    return Ok(None);
+  }
+
+  // the names sort by date
+  let backup = format!("{}/{}", directory.trim_end_matches('/'), Utc::now().format("%Y%m%dT%H%M%SZ"));
+  fs::create_dir_all(&backup)?;
+  for path in existing {
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    fs::copy(path, Path::new(&backup).join(name))?;
+  }
+
+  let mut backups: Vec<_> = fs::read_dir(directory)?
+    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+    .filter(|path| path.is_dir())
+    .collect();
+  backups.sort();
+  let expired = backups.len().saturating_sub(keep);
+  for path in backups.into_iter().take(expired) {
+    fs::remove_dir_all(&path)?;
+  }
+  Ok(Some(backup))
+}
+
+fn chown(path: &Path, owner: &Owner) -> io::Result<()> {
+  if owner.uid.is_none() && owner.gid.is_none() {
+    return Ok(());
+  }
+
+  let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  // -1 leaves the id unchanged
+  if unsafe { libc::chown(c_path.as_ptr(), owner.uid.unwrap_or(!0), owner.gid.unwrap_or(!0)) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
@@ -0,0 +1,59 @@
+//! copies the issued certificate to a `kubernetes.io/tls` Secret, so the
+//! clusters behind an external sozu use the same certificate. kubectl does
+//! the requests, with its own configuration and credentials
+
+use std::{io::Write, process::{Command, Stdio}};
+use base64;
+
+pub struct Secret {
+  pub name:      String,
+  pub namespace: String,
+  /// kubeconfig context, the current one by default
+  pub context:   Option<String>,
+  pub kubectl:   String,
+}
+
+impl Secret {
+  /// creates the Secret or updates it, with the certificate followed by its
+  /// chain in `tls.crt`
+  pub fn store(&self, certificate: &str, chain: &str, key: Option<&str>) -> Result<(), String> {
+    let key = key.ok_or_else(|| format!("the Secret {} needs the private key, it is not known with --csr", self.name))?;
+
+    let manifest = json!({
+      "apiVersion": "v1",
+      "kind": "Secret",
+      "type": "kubernetes.io/tls",
+      "metadata": {
+        "name": self.name,
+        "namespace": self.namespace,
+        "labels": { "app.kubernetes.io/managed-by": "sozu-acme" },
+      },
+      "data": {
+        "tls.crt": base64::encode(format!("{}\n{}", certificate.trim_end(), chain)),
+        "tls.key": base64::encode(key),
+      },
+    });
+
+    let mut command = Command::new(&self.kubectl);
+    if let Some(ref context) = self.context {
+      command.args(&["--context", context]);
+    }
+    let mut child = command.args(&["--namespace", &self.namespace, "apply", "-f", "-"])
+      .stdin(Stdio::piped())
+      .spawn()
+      .map_err(|e| format!("could not run {}: {}", self.kubectl, e))?;
+
+    // the manifest goes through stdin, the key never appears in the arguments
+    let written = child.stdin.take().expect("piped stdin").write_all(manifest.to_string().as_bytes());
+    let status = child.wait().map_err(|e| format!("could not run {}: {}", self.kubectl, e))?;
+    if let Err(e) = written {
+      return Err(format!("could not send the Secret {} to {}: {}", self.name, self.kubectl, e));
+    }
+    if !status.success() {
+      return Err(format!("{} could not apply the Secret {}/{}: {}", self.kubectl, self.namespace, self.name, status));
+    }
+
+    info!("stored the certificate in the Secret {}/{}", self.namespace, self.name);
+    Ok(())
+  }
+}
@@ -0,0 +1,60 @@
+//! lock preventing concurrent runs in the same directory, which would race
+//! on sozu's configuration, the ACME account and the certificate files
+
+use std::{cmp, env, io, thread, fs::{File, OpenOptions}, io::{Read, Seek, SeekFrom, Write},
+  os::unix::io::AsRawFd, process, time::{Duration, Instant}};
+use libc;
+
+pub const LOCK_FILE: &str = "sozu-acme.lock";
+
+/// set in the environment of the processes started by a run holding the
+/// lock (batch, daemon), they run under it
+pub const LOCK_HELD_VARIABLE: &str = "SOZU_ACME_LOCK_HELD";
+
+/// the lock is released when dropped, or when the process exits
+pub struct RunLock {
+  _file: Option<File>,
+}
+
+/// takes the lock, waiting up to `wait` if another run holds it
+pub fn acquire(wait: Duration) -> Result<RunLock, String> {
+  if env::var_os(LOCK_HELD_VARIABLE).is_some() {
+    return Ok(RunLock { _file: None });
+  }
+
+  let mut file = OpenOptions::new().read(true).write(true).create(true).open(LOCK_FILE)
+    .map_err(|e| format!("could not open {}: {}", LOCK_FILE, e))?;
+
+  let deadline = Instant::now() + wait;
+  let mut waiting = false;
+  // flock locks are released by the kernel if the process dies
+  while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+    let error = io::Error::last_os_error();
+    if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+      return Err(format!("could not lock {}: {}", LOCK_FILE, error));
+    }
+
+    let now = Instant::now();
+    if now >= deadline {
+      let mut holder = String::new();
+      let _ = file.read_to_string(&mut holder);
+      return Err(format!("another sozu-acme run (pid {}) holds {}, try again later or wait for it with --lock-timeout",
+        holder.trim(), LOCK_FILE));
+    }
+    if !waiting {
+      info!("waiting for another sozu-acme run to finish");
+      waiting = true;
+    }
+    thread::sleep(cmp::min(Duration::from_secs(1), deadline - now));
+  }
+
+  // the pid of the holder, for the message of the runs that find the lock taken
+  let written = file.set_len(0)
+    .and_then(|_| file.seek(SeekFrom::Start(0)))
+    .and_then(|_| write!(file, "{}", process::id()));
+  if let Err(e) = written {
+    warn!("could not write the pid to {}: {}", LOCK_FILE, e);
+  }
+
+  Ok(RunLock { _file: Some(file) })
+}
@@ -0,0 +1,279 @@
+//! the logger: pretty_env_logger's text, or with --log-format json one JSON
+//! object per event, with the context of the run (domain, phase, sozu order
+//! id, outcome), for Loki or ELK. With --syslog, the events go to a syslog
+//! socket instead of stderr
+
+use std::{
+  env, process, ptr,
+  cell::RefCell,
+  io::{self, Write},
+  net::{TcpStream, ToSocketAddrs, UdpSocket},
+  os::unix::net::UnixDatagram,
+  sync::{Mutex, atomic::{AtomicBool, AtomicPtr, Ordering}},
+  time::Duration,
+};
+use chrono::{Local, Utc};
+use libc;
+use log::{self, Level, Log, Metadata, Record};
+use pretty_env_logger;
+use serde_json::Value;
+
+use timings;
+
+/// sets the format before the arguments are parsed, and is inherited by the
+/// runs the daemon and the batches start
+pub const FORMAT_VARIABLE: &str = "SOZU_ACME_LOG_FORMAT";
+/// the syslog address and facility, inherited like the format
+pub const SYSLOG_VARIABLE: &str = "SOZU_ACME_SYSLOG";
+pub const FACILITY_VARIABLE: &str = "SOZU_ACME_SYSLOG_FACILITY";
+
+pub const DEFAULT_FACILITY: &str = "daemon";
+
+const FACILITIES: &[(&str, u8)] = &[
+  ("kern", 0), ("user", 1), ("mail", 2), ("daemon", 3), ("auth", 4), ("syslog", 5),
+  ("lpr", 6), ("news", 7), ("uucp", 8), ("cron", 9), ("authpriv", 10), ("ftp", 11),
+  ("local0", 16), ("local1", 17), ("local2", 18), ("local3", 19),
+  ("local4", 20), ("local5", 21), ("local6", 22), ("local7", 23),
+];
+
+const TAG: &str = "sozu-acme";
+
+static JSON: AtomicBool = AtomicBool::new(false);
+/// the installed logger, to set its syslog socket once the arguments are parsed
+static LOGGER: AtomicPtr<Logger> = AtomicPtr::new(ptr::null_mut());
+
+#[derive(Default)]
+struct Context {
+  domain:   Option<String>,
+  phase:    Option<&'static str>,
+  order_id: Option<String>,
+  outcome:  Option<String>,
+  /// seconds spent in each phase, on the last event
+  timings:  Vec<(&'static str, f64)>,
+}
+
+thread_local! {
+  static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+enum Socket {
+  Unix(UnixDatagram),
+  Udp(UdpSocket),
+  Tcp(TcpStream),
+}
+
+struct Syslog {
+  address:  String,
+  socket:   Socket,
+  facility: u8,
+  hostname: String,
+}
+
+impl Syslog {
+  fn send(&mut self, level: Level, message: &str) -> io::Result<()> {
+    let severity = match level {
+      Level::Error => 3,
+      Level::Warn => 4,
+      Level::Info => 6,
+      Level::Debug | Level::Trace => 7,
+    };
+    let priority = self.facility * 8 + severity;
+    let remote = self.remote_line(priority, message);
+
+    match self.socket {
+      // RFC 3164, like syslog(3) writes to /dev/log
+      Socket::Unix(ref socket) => {
+        let line = format!("<{}>{} {}[{}]: {}", priority, Local::now().format("%b %e %H:%M:%S"),
+          TAG, process::id(), message);
+        socket.send(line.as_bytes()).map(|_| ())
+      },
+      // RFC 5424 for the remote servers, with the hostname and the year
+      Socket::Udp(ref socket) => socket.send(remote.as_bytes()).map(|_| ()),
+      // with octet counting framing (RFC 6587), the messages can span lines
+      Socket::Tcp(ref mut stream) => {
+        let frame = format!("{} {}", remote.len(), remote);
+        if stream.write_all(frame.as_bytes()).is_ok() {
+          return Ok(());
+        }
+        // the server may have closed the connection since the last event
+        *stream = TcpStream::connect(&self.address["tcp://".len()..])?;
+        stream.write_all(frame.as_bytes())
+      },
+    }
+  }
+
+  fn remote_line(&self, priority: u8, message: &str) -> String {
+    format!("<{}>1 {} {} {} {} - - {}", priority, Utc::now().to_rfc3339(), self.hostname,
+      TAG, process::id(), message)
+  }
+}
+
+struct Logger {
+  text:   Box<dyn Log>,
+  syslog: Mutex<Option<Syslog>>,
+}
+
+impl Log for Logger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    self.text.enabled(metadata)
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    let json = JSON.load(Ordering::Relaxed);
+    let mut syslog = self.syslog.lock().unwrap_or_else(|e| e.into_inner());
+    match *syslog {
+      Some(ref mut syslog) => {
+        let message = if json { event(record).to_string() } else { format!("{}: {}", record.target(), record.args()) };
+        if let Err(e) = syslog.send(record.level(), &message) {
+          eprintln!("could not log to {} ({}): {}", syslog.address, e, message);
+        }
+      },
+      None if json => eprintln!("{}", event(record)),
+      None => self.text.log(record),
+    }
+  }
+
+  fn flush(&self) {
+    self.text.flush()
+  }
+}
+
+fn event(record: &Record) -> Value {
+  let mut event = json!({
+    "timestamp": Utc::now().to_rfc3339(),
+    "level": record.level().to_string(),
+    "target": record.target(),
+    "message": record.args().to_string(),
+  });
+  CONTEXT.with(|context| {
+    let context = context.borrow();
+    let fields = [
+      ("domain", context.domain.clone()),
+      ("phase", context.phase.map(|phase| phase.to_string())),
+      ("order_id", context.order_id.clone()),
+      ("outcome", context.outcome.clone()),
+    ];
+    for &(name, ref value) in fields.iter() {
+      if let Some(ref value) = *value {
+        event[name] = Value::String(value.clone());
+      }
+    }
+    if !context.timings.is_empty() {
+      event["timings"] = context.timings.iter().map(|&(phase, seconds)| (phase.to_string(), json!(seconds))).collect();
+    }
+  });
+  event
+}
+
+/// the levels come from RUST_LOG, like with pretty_env_logger::init
+pub fn init() {
+  let mut builder = pretty_env_logger::formatted_builder();
+  if let Ok(filters) = env::var("RUST_LOG") {
+    builder.parse_filters(&filters);
+  }
+  let text = builder.build();
+  log::set_max_level(text.filter());
+  let logger = Box::into_raw(Box::new(Logger { text: Box::new(text), syslog: Mutex::new(None) }));
+  LOGGER.store(logger, Ordering::Release);
+  log::set_logger(unsafe { &*logger }).expect("could not set the logger");
+
+  set_json(env::var(FORMAT_VARIABLE).map(|format| format == "json").unwrap_or(false));
+  if let Ok(address) = env::var(SYSLOG_VARIABLE) {
+    let facility = env::var(FACILITY_VARIABLE).unwrap_or_else(|_| DEFAULT_FACILITY.to_string());
+    if let Err(e) = set_syslog(&address, &facility) {
+      error!("{}", e);
+    }
+  }
+}
+
+pub fn set_json(json: bool) {
+  JSON.store(json, Ordering::Relaxed);
+  if json {
+    env::set_var(FORMAT_VARIABLE, "json");
+  } else {
+    env::remove_var(FORMAT_VARIABLE);
+  }
+}
+
+/// sends the events to `unix:/dev/log` (or any socket path),
+/// `udp://host:514` or `tcp://host:514` instead of stderr
+pub fn set_syslog(address: &str, facility: &str) -> Result<(), String> {
+  let facility = FACILITIES.iter().find(|&&(name, _)| name == facility).map(|&(_, code)| code)
+    .ok_or_else(|| format!("unknown syslog facility {}", facility))?;
+  let socket = connect(address).map_err(|e| format!("could not connect to syslog at {}: {}", address, e))?;
+  let logger = unsafe { LOGGER.load(Ordering::Acquire).as_ref() }
+    .ok_or_else(|| "the logger is not initialized".to_string())?;
+
+  *logger.syslog.lock().unwrap_or_else(|e| e.into_inner()) = Some(Syslog {
+    address:  address.to_string(),
+    socket:   socket,
+    facility: facility,
+    hostname: hostname(),
+  });
+  env::set_var(SYSLOG_VARIABLE, address);
+  env::set_var(FACILITY_VARIABLE, FACILITIES.iter().find(|&&(_, code)| code == facility).unwrap().0);
+  Ok(())
+}
+
+pub fn facilities() -> Vec<&'static str> {
+  FACILITIES.iter().map(|&(name, _)| name).collect()
+}
+
+fn connect(address: &str) -> io::Result<Socket> {
+  if address.starts_with("udp://") {
+    let remote = address["udp://".len()..].to_socket_addrs()?.next()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for the host"))?;
+    let socket = UdpSocket::bind(if remote.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.connect(remote)?;
+    Ok(Socket::Udp(socket))
+  } else if address.starts_with("tcp://") {
+    Ok(Socket::Tcp(TcpStream::connect(&address["tcp://".len()..])?))
+  } else {
+    let path = if address.starts_with("unix:") { &address["unix:".len()..] } else { address };
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(Socket::Unix(socket))
+  }
+}
+
+fn hostname() -> String {
+  let mut buffer = [0u8; 256];
+  if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+    return "-".to_string();
+  }
+  let length = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+  String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+/// the certificate the next events are about, by its first domain
+pub fn set_domain(domain: Option<&str>) {
+  CONTEXT.with(|context| context.borrow_mut().domain = domain.map(|domain| domain.to_string()));
+}
+
+/// the step of the run: order, validation, finalize, install, storage
+pub fn set_phase(phase: Option<&'static str>) {
+  CONTEXT.with(|context| context.borrow_mut().phase = phase);
+}
+
+/// logs the events of the function with the id of the sozu order they are about
+pub fn with_order_id<T, F: FnOnce() -> T>(id: &str, f: F) -> T {
+  CONTEXT.with(|context| context.borrow_mut().order_id = Some(id.to_string()));
+  let result = f();
+  CONTEXT.with(|context| context.borrow_mut().order_id = None);
+  result
+}
+
+/// logs the result of the run for the certificate, with the time spent in
+/// each phase, then clears the context
+pub fn outcome(outcome: &str, phases: &[(&'static str, Duration)]) {
+  CONTEXT.with(|context| {
+    let mut context = context.borrow_mut();
+    context.outcome = Some(outcome.to_string());
+    context.timings = phases.iter().map(|&(phase, duration)| (phase, timings::seconds(duration))).collect();
+  });
+  info!("run finished: {}", outcome);
+  CONTEXT.with(|context| *context.borrow_mut() = Context::default());
+}
@@ -1,376 +1,2655 @@
 #[macro_use] extern crate log;
 #[macro_use] extern crate clap;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
 extern crate rand;
+extern crate chrono;
+extern crate idna;
+extern crate toml;
+extern crate libc;
+extern crate ureq;
+extern crate base64;
 extern crate mio_uds;
 extern crate tiny_http;
 extern crate acme_lib;
+extern crate openssl;
 extern crate pretty_env_logger;
 extern crate sozu_command_lib as sozu_command;
 
+mod acme;
+mod account;
+mod sozu;
+mod revoke;
+mod renewal;
+mod challenge;
+mod certificate;
+mod domain;
+mod caa;
+mod state;
+mod ssh;
+mod mock;
+mod daemon;
+mod batch;
+mod schedule;
+mod systemd;
+mod signal;
+mod control;
+mod admin;
+mod lock;
+mod watch;
+mod settings;
+mod exit;
+mod vault;
+mod kubernetes;
+mod s3;
+mod container;
+mod files;
+mod metrics;
+mod logging;
+mod webhook;
+mod chat;
+mod audit;
+mod timings;
+
 use std::{
-  iter, thread, time,
-  fs::File,
+  cmp, env, io, thread, time,
+  ffi::CString,
+  fs,
   net::SocketAddr,
-  io::Write,
+  os::unix::ffi::OsStrExt,
+  path::Path,
+  process,
 };
-use clap::{App, Arg};
-use mio_uds::UnixStream;
-use rand::{thread_rng, Rng, distributions::Alphanumeric};
-use tiny_http::{Server, Response};
-use acme_lib::{Error, Directory, DirectoryUrl};
-use acme_lib::persist::FilePersist;
-use acme_lib::create_p384_key;
-use sozu_command::channel::Channel;
+use chrono::Utc;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, ArgSettings, SubCommand};
+use acme_lib::{Directory, DirectoryUrl};
+use acme_lib::persist::MemoryPersist;
+use openssl::{asn1::Asn1Time, nid::Nid, x509::X509};
 use sozu_command::{
   config::Config,
-  certificate::{calculate_fingerprint, split_certificate_chain},
-  command::{CommandRequestData, CommandRequest, CommandResponse, CommandStatus},
-  proxy::{ProxyRequestData, Backend, HttpFront, CertificateAndKey, CertFingerprint,
-    AddCertificate, RemoveBackend, ReplaceCertificate},
+  certificate::calculate_fingerprint,
 };
 
+use challenge::ChallengeServer;
+use state::{State, ManagedCertificate};
+
 fn main() {
-  pretty_env_logger::init();
+  logging::init();
   info!("starting up");
 
+  let arguments = settings::complete(env::args().collect()).unwrap_or_else(|e| {
+    error!("{}", e);
+    process::exit(exit::FAILURE);
+  });
+
   let matches = App::new("sozu-acme")
                         .version(crate_version!())
                         .about("ACME (Let's Encrypt) configuration tool for sozu")
-                        .arg(Arg::with_name("config")
-                            .short("c")
-                            .long("config")
-                            .value_name("FILE")
-                            .help("Sets a custom config file")
-                            .takes_value(true)
-                            .required(true))
-                        .arg(Arg::with_name("domain")
-                            .long("domain")
-                            .value_name("domain name")
-                            .help("application's domain name")
-                            .takes_value(true)
-                            .required(true))
-                        .arg(Arg::with_name("email")
-                            .long("email")
-                            .value_name("registration email")
-                            .help("registration email")
-                            .takes_value(true)
-                            .required(true))
-                        .arg(Arg::with_name("id")
-                            .long("id")
-                            .value_name("Application id")
-                            .help("application identifier")
+                        .setting(AppSettings::SubcommandsNegateReqs)
+                        // without subcommand, the certificate is issued like with `issue`
+                        .args(&certificate_args())
+                        .arg(lock_timeout_arg())
+                        .arg(Arg::with_name("log-format")
+                            .long("log-format")
+                            .value_name("format")
+                            .help("text, or json for one JSON object per event")
+                            .possible_values(&["text", "json"])
                             .takes_value(true)
-                            .required(true))
-                        .arg(Arg::with_name("old-cert")
-                            .long("old-certificate")
-                            .value_name("previous certificate path")
-                            .help("path to the previous certificate")
-                            .takes_value(true))
-                        .arg(Arg::with_name("cert")
-                            .long("certificate")
-                            .value_name("certificate path")
-                            .help("certificate path")
+                            .global(true))
+                        .arg(Arg::with_name("syslog")
+                            .long("syslog")
+                            .value_name("address")
+                            .help("logs to syslog instead of stderr: unix:/dev/log, udp://host:514 or tcp://host:514")
                             .takes_value(true)
-                            .required(true))
-                        .arg(Arg::with_name("chain")
-                            .long("chain")
-                            .value_name("certificate chain path")
-                            .help("certificate chain path")
-                            .takes_value(true)
-                            .required(true))
-                        .arg(Arg::with_name("key")
-                            .long("key")
-                            .value_name("key path")
-                            .help("key path")
-                            .takes_value(true)
-                            .required(true))
-                        .arg(Arg::with_name("http")
-                            .long("http")
-                            .value_name("HTTP frontend address")
-                            .help("format: IP:port")
+                            .global(true))
+                        .arg(Arg::with_name("audit-log")
+                            .long("audit-log")
+                            .value_name("FILE")
+                            .help("appends every order sent to sozu to this file, one JSON object per line")
                             .takes_value(true)
-                            .required(true))
-                        .arg(Arg::with_name("https")
-                            .long("https")
-                            .value_name("HTTPS frontend address")
-                            .help("format: IP:port")
+                            .global(true))
+                        .arg(Arg::with_name("syslog-facility")
+                            .long("syslog-facility")
+                            .value_name("facility")
+                            .help("syslog facility of the events")
+                            .possible_values(&logging::facilities())
+                            .default_value(logging::DEFAULT_FACILITY)
                             .takes_value(true)
-                            .required(true))
-                        .get_matches();
-
-  let config_file = matches.value_of("config").expect("required config file");
-  let app_id      = matches.value_of("id").expect("required application id");
-  let certificate = matches.value_of("cert").expect("required certificate path");
-  let old_cert    = matches.value_of("old-cert");
-  let chain       = matches.value_of("chain").expect("required certificate chain path");
-  let key         = matches.value_of("key").expect("required key path");
-  let domain      = matches.value_of("domain").expect("required domain name");
+                            .global(true))
+                        .subcommand(SubCommand::with_name("issue")
+                            .about("requests a certificate and installs it in sozu")
+                            .args(&certificate_args()))
+                        .subcommand(SubCommand::with_name("check")
+                            .about("checks the options of issue, and the sozu instances they point to, without requesting anything")
+                            .args(&certificate_args()))
+                        .subcommand(SubCommand::with_name("plan")
+                            .about("prints the orders issue would send to sozu, from its current state, without changing anything")
+                            .args(&certificate_args()))
+                        .subcommand(SubCommand::with_name("status")
+                            .about("shows when the certificates of the state file expire, and whether they are due for renewal")
+                            .arg(Arg::with_name("renew-days")
+                                .long("renew-days")
+                                .value_name("days")
+                                .help("a certificate is due when it expires in less than this number of days")
+                                .takes_value(true)
+                                .default_value("30"))
+                            .arg(Arg::with_name("json")
+                                .long("json")
+                                .help("print the certificates as a JSON array")))
+                        .subcommand(SubCommand::with_name("account")
+                            .about("manages the ACME account")
+                            .setting(AppSettings::SubcommandRequiredElseHelp)
+                            .subcommand(SubCommand::with_name("rotate-key")
+                                .about("replaces the account key with a new one")
+                                .args(&acme_args()))
+                            .subcommand(SubCommand::with_name("update")
+                                .about("changes the contact email of the account")
+                                .args(&acme_args())
+                                .arg(Arg::with_name("current-email")
+                                    .long("current-email")
+                                    .value_name("registration email")
+                                    .help("email the account is currently registered with")
+                                    .takes_value(true)
+                                    .required(true))))
+                        .subcommand(SubCommand::with_name("list")
+                            .about("lists the certificates installed in sozu, or those of the state file without --config")
+                            .alias("list-certs")
+                            .arg(Arg::with_name("config")
+                                .short("c")
+                                .long("config")
+                                .value_name("FILE")
+                                .help("sozu configuration file")
+                                .takes_value(true))
+                            .arg(Arg::with_name("json")
+                                .long("json")
+                                .help("print the certificates as a JSON array")))
+                        .subcommand(SubCommand::with_name("revoke")
+                            .about("revokes a certificate with the CA")
+                            .args(&acme_args())
+                            .arg(Arg::with_name("cert")
+                                .long("certificate")
+                                .value_name("certificate path")
+                                .help("path of the certificate to revoke")
+                                .takes_value(true))
+                            .arg(Arg::with_name("domain")
+                                .long("domain")
+                                .value_name("domain name")
+                                .help("revoke the certificate sozu uses for this domain, or the one of the state file without --config")
+                                .takes_value(true))
+                            .group(ArgGroup::with_name("target")
+                                .args(&["cert", "domain"])
+                                .required(true))
+                            .arg(Arg::with_name("reason")
+                                .long("reason")
+                                .value_name("revocation reason")
+                                .takes_value(true)
+                                .possible_values(&["unspecified", "keyCompromise", "affiliationChanged", "superseded", "cessationOfOperation"])
+                                .default_value("unspecified"))
+                            .arg(Arg::with_name("config")
+                                .short("c")
+                                .long("config")
+                                .value_name("FILE")
+                                .help("sozu configuration file. If set, the certificate is also removed from sozu")
+                                .takes_value(true)
+                                .requires("https"))
+                            .arg(Arg::with_name("https")
+                                .long("https")
+                                .value_name("HTTPS frontend address")
+                                .help("format: IP:port")
+                                .takes_value(true))
+                            .arg(Arg::with_name("id")
+                                .long("id")
+                                .value_name("Application id")
+                                .help("also remove the application's HTTPS fronts for the certificate's names")
+                                .takes_value(true)))
+                        .subcommand(SubCommand::with_name("delete")
+                            .about("removes a domain's certificate and HTTPS fronts from sozu")
+                            .arg(Arg::with_name("domain")
+                                .long("domain")
+                                .value_name("domain name")
+                                .help("domain whose certificate is removed, along with the other names it covers")
+                                .takes_value(true)
+                                .required(true))
+                            .arg(Arg::with_name("config")
+                                .short("c")
+                                .long("config")
+                                .value_name("FILE")
+                                .help("sozu configuration file")
+                                .takes_value(true)
+                                .required(true))
+                            .arg(Arg::with_name("https")
+                                .long("https")
+                                .value_name("HTTPS frontend address")
+                                .help("format: IP:port")
+                                .takes_value(true)
+                                .required(true))
+                            .arg(Arg::with_name("id")
+                                .long("id")
+                                .value_name("Application id")
+                                .help("also remove the application's HTTPS fronts for the certificate's names")
+                                .takes_value(true))
+                            .arg(Arg::with_name("revoke")
+                                .long("revoke")
+                                .help("revoke the certificate with the CA before removing it")
+                                .requires("email"))
+                            .arg(Arg::with_name("email")
+                                .long("email")
+                                .value_name("registration email")
+                                .help("registration email of the account, to revoke the certificate")
+                                .takes_value(true))
+                            .arg(Arg::with_name("directory-url")
+                                .long("directory-url")
+                                .value_name("ACME directory URL")
+                                .help("directory of the ACME certificate authority (default: Let's Encrypt)")
+                                .takes_value(true))
+                            .arg(Arg::with_name("staging")
+                                .long("staging")
+                                .help("use the Let's Encrypt staging environment, for testing")
+                                .conflicts_with("directory-url"))
+                            .arg(Arg::with_name("cert")
+                                .long("certificate")
+                                .value_name("certificate path")
+                                .help("delete the certificate file")
+                                .takes_value(true))
+                            .arg(Arg::with_name("chain")
+                                .long("chain")
+                                .value_name("certificate chain path")
+                                .help("delete the certificate chain file")
+                                .takes_value(true))
+                            .arg(Arg::with_name("key")
+                                .long("key")
+                                .value_name("key path")
+                                .help("delete the key file")
+                                .takes_value(true))
+                            .args(&account_key_args()))
+                        .subcommand(SubCommand::with_name("renew")
+                            .about("renews the certificate if it is due, exits with status 3 if it is not")
+                            .args(&renew_args()))
+                        .subcommand(SubCommand::with_name("batch")
+                            .about("requests or renews the certificates described in a TOML file")
+                            .arg(Arg::with_name("file")
+                                .long("file")
+                                .value_name("FILE")
+                                .help("TOML file with a [[certificate]] table per certificate, and optional [defaults]")
+                                .takes_value(true)
+                                .required(true))
+                            .arg(Arg::with_name("parallel")
+                                .long("parallel")
+                                .value_name("N")
+                                .help("number of certificates processed at the same time")
+                                .takes_value(true)
+                                .default_value("1")))
+                        .subcommand(SubCommand::with_name("daemon")
+                            .about("stays running and renews the certificates installed by previous runs")
+                            .arg(Arg::with_name("interval")
+                                .long("interval")
+                                .value_name("seconds")
+                                .help("time between two checks of the certificates")
+                                .takes_value(true)
+                                .default_value("43200"))
+                            .arg(Arg::with_name("renew-days")
+                                .long("renew-days")
+                                .value_name("days")
+                                .help("renew the certificates expiring in less than this number of days")
+                                .takes_value(true)
+                                .default_value("30"))
+                            .arg(Arg::with_name("schedule")
+                                .long("schedule")
+                                .value_name("cron expression")
+                                .help("check the certificates when this expression matches instead of every interval, like \"0 3 * * *\"")
+                                .takes_value(true)
+                                .validator(|expression| schedule::Schedule::parse(&expression).map(|_| ())))
+                            .arg(Arg::with_name("file")
+                                .long("file")
+                                .value_name("FILE")
+                                .help("renew the certificates of this batch file instead of those of the state file, it is read again when it changes or on SIGHUP")
+                                .takes_value(true))
+                            .arg(Arg::with_name("auto-issue")
+                                .long("auto-issue")
+                                .help("request a certificate for the HTTPS fronts sozu has no valid certificate for")
+                                .requires_all(&["config", "email", "http", "certificates-dir"]))
+                            .arg(Arg::with_name("config")
+                                .short("c")
+                                .long("config")
+                                .value_name("FILE")
+                                .help("sozu configuration file, for --auto-issue")
+                                .takes_value(true))
+                            .arg(Arg::with_name("email")
+                                .long("email")
+                                .value_name("registration email")
+                                .help("registration email of the account, for --auto-issue")
+                                .takes_value(true))
+                            .arg(Arg::with_name("http")
+                                .long("http")
+                                .value_name("HTTP frontend address")
+                                .help("HTTP listener for the challenges of --auto-issue, format: IP:port")
+                                .takes_value(true))
+                            .arg(Arg::with_name("certificates-dir")
+                                .long("certificates-dir")
+                                .value_name("DIR")
+                                .help("directory where --auto-issue writes the certificates, chains and keys")
+                                .takes_value(true))
+                            .arg(Arg::with_name("directory-url")
+                                .long("directory-url")
+                                .value_name("ACME directory URL")
+                                .help("directory of the ACME certificate authority (default: Let's Encrypt)")
+                                .takes_value(true))
+                            .arg(Arg::with_name("staging")
+                                .long("staging")
+                                .help("use the Let's Encrypt staging environment, for testing")
+                                .conflicts_with("directory-url"))
+                            .arg(Arg::with_name("agree-tos")
+                                .long("agree-tos")
+                                .help("agree to the CA's terms of service, the daemon cannot answer the prompt"))
+                            .arg(Arg::with_name("watch-interval")
+                                .long("watch-interval")
+                                .value_name("seconds")
+                                .help("time between two looks at sozu's HTTPS fronts with --auto-issue")
+                                .takes_value(true)
+                                .default_value("300"))
+                            .arg(Arg::with_name("control-socket")
+                                .long("control-socket")
+                                .value_name("FILE")
+                                .help("path of the socket receiving the commands of sozu-acme ctl")
+                                .takes_value(true)
+                                .default_value(control::CONTROL_SOCKET))
+                            .arg(Arg::with_name("admin-listen")
+                                .long("admin-listen")
+                                .value_name("IP:port")
+                                .help("serve /healthz and a JSON /status of the certificates over HTTP on this address")
+                                .takes_value(true)))
+                        .subcommand(SubCommand::with_name("ctl")
+                            .about("sends a command to the running daemon")
+                            .arg(Arg::with_name("control-socket")
+                                .long("control-socket")
+                                .value_name("FILE")
+                                .help("path of the daemon's control socket")
+                                .takes_value(true)
+                                .default_value(control::CONTROL_SOCKET))
+                            .arg(Arg::with_name("command")
+                                .help("status: the state of each certificate, renew: renew a certificate at once, pause or resume the renewals")
+                                .possible_values(&["status", "renew", "pause", "resume"])
+                                .required(true)
+                                .index(1))
+                            .arg(Arg::with_name("domain")
+                                .help("domain of the certificate to renew")
+                                .required_if("command", "renew")
+                                .index(2)))
+                        .subcommand(SubCommand::with_name("mock-proxy")
+                            .about("answers on a command socket like sozu would, printing the orders it receives, for testing")
+                            .arg(Arg::with_name("socket")
+                                .long("socket")
+                                .value_name("FILE")
+                                .help("path of the command socket to listen on")
+                                .takes_value(true)
+                                .required(true)))
+                        .get_matches_from(arguments);
+
+  // the global option is in the matches of the subcommand it follows
+  let mut log_format = None;
+  let mut syslog = None;
+  let mut audit_log = None;
+  let mut facility = None;
+  let mut current = Some(&matches);
+  while let Some(matches) = current {
+    log_format = matches.value_of("log-format").or(log_format);
+    syslog = matches.value_of("syslog").or(syslog);
+    audit_log = matches.value_of("audit-log").or(audit_log);
+    if matches.occurrences_of("syslog-facility") > 0 {
+      facility = matches.value_of("syslog-facility");
+    }
+    current = matches.subcommand().1;
+  }
+  if let Some(format) = log_format {
+    logging::set_json(format == "json");
+  }
+  if let Some(path) = audit_log {
+    audit::set_path(path);
+  }
+  if let Some(address) = syslog {
+    if let Err(e) = logging::set_syslog(address, facility.unwrap_or(logging::DEFAULT_FACILITY)) {
+      error!("{}", e);
+      process::exit(exit::FAILURE);
+    }
+  }
+
+  if let Some(matches) = matches.subcommand_matches("list") {
+    process::exit(list_command(matches));
+  }
+
+  if let Some(matches) = matches.subcommand_matches("revoke") {
+    let _lock = run_lock(matches);
+    process::exit(revoke_command(matches));
+  }
+
+  if let Some(matches) = matches.subcommand_matches("delete") {
+    let _lock = run_lock(matches);
+    process::exit(delete_command(matches));
+  }
+
+  if let Some(matches) = matches.subcommand_matches("batch") {
+    let parallel = value_t!(matches, "parallel", usize).unwrap_or_else(|e| e.exit());
+    let _lock = run_lock(matches);
+    if !batch::run(matches.value_of("file").expect("required batch file"), cmp::max(parallel, 1)) {
+      process::exit(exit::FAILURE);
+    }
+    return;
+  }
+
+  if let Some(matches) = matches.subcommand_matches("daemon") {
+    let interval = value_t!(matches, "interval", u64).unwrap_or_else(|e| e.exit());
+    let renew_days = value_t!(matches, "renew-days", u32).unwrap_or_else(|e| e.exit());
+    let schedule = matches.value_of("schedule").map(|expression| schedule::Schedule::parse(expression).expect("validated schedule"));
+    let auto_issue = if matches.is_present("auto-issue") {
+      let mut arguments = vec!["--email".to_string(), matches.value_of("email").expect("required registration email").to_string()];
+      if let Some(url) = matches.value_of("directory-url") {
+        arguments.push("--directory-url".to_string());
+        arguments.push(url.to_string());
+      }
+      for flag in ["staging", "agree-tos"].iter().filter(|flag| matches.is_present(flag)) {
+        arguments.push(format!("--{}", flag));
+      }
+      Some(daemon::AutoIssue {
+        config:    matches.value_of("config").expect("required config file").to_string(),
+        http:      matches.value_of("http").expect("required HTTP frontend address").to_string(),
+        directory: matches.value_of("certificates-dir").expect("required certificates directory").to_string(),
+        arguments,
+        interval:  time::Duration::from_secs(value_t!(matches, "watch-interval", u64).unwrap_or_else(|e| e.exit())),
+      })
+    } else {
+      None
+    };
+    daemon::run(time::Duration::from_secs(interval), renew_days, schedule, matches.value_of("file"), auto_issue,
+      matches.value_of("control-socket").expect("control socket has a default value"), matches.value_of("admin-listen"));
+  }
+
+  if let Some(matches) = matches.subcommand_matches("ctl") {
+    let command: Vec<&str> = matches.value_of("command").into_iter().chain(matches.value_of("domain")).collect();
+    if !control::send(matches.value_of("control-socket").expect("control socket has a default value"), &command) {
+      process::exit(exit::FAILURE);
+    }
+    return;
+  }
+
+  if let Some(matches) = matches.subcommand_matches("mock-proxy") {
+    if !mock::run(matches.value_of("socket").expect("required socket path")) {
+      process::exit(exit::FAILURE);
+    }
+    return;
+  }
+
+  if let Some(matches) = matches.subcommand_matches("account") {
+    let _lock = run_lock(matches);
+    if !matches.subcommand().1.map(set_account_encryption).unwrap_or(true) {
+      process::exit(exit::FAILURE);
+    }
+    let ok = match matches.subcommand() {
+      ("rotate-key", Some(matches)) => account::rotate_key(directory_url(matches),
+        matches.value_of("email").expect("required registration email")),
+      ("update", Some(matches)) => account::update_contact(directory_url(matches),
+        matches.value_of("current-email").expect("required current registration email"),
+        matches.value_of("email").expect("required new email")),
+      _ => unreachable!(),
+    };
+    if !ok {
+      process::exit(exit::ACME);
+    }
+    return;
+  }
+
+  if let Some(matches) = matches.subcommand_matches("renew") {
+    match matches.value_of("managed") {
+      Some(domain) => process::exit(renew_managed(domain)),
+      None => process::exit(certificate_commands(matches, true)),
+    }
+  }
+
+  if let Some(matches) = matches.subcommand_matches("issue") {
+    process::exit(certificate_commands(matches, false));
+  }
+
+  if let Some(matches) = matches.subcommand_matches("check") {
+    if !check_command(matches) {
+      process::exit(exit::FAILURE);
+    }
+    return;
+  }
+
+  if let Some(matches) = matches.subcommand_matches("plan") {
+    process::exit(exit::run(|| plan_command(matches)));
+  }
+
+  if let Some(matches) = matches.subcommand_matches("status") {
+    let renew_days = value_t!(matches, "renew-days", u32).unwrap_or_else(|e| e.exit());
+    status_command(renew_days, matches.is_present("json"));
+    return;
+  }
+
+  warn!("requesting a certificate without subcommand is deprecated, use sozu-acme issue");
+  process::exit(certificate_commands(&matches, false));
+}
+
+/// requests the certificates of the command line, or one per line of
+/// --domains-file with --cert-per-line. Returns the exit code
+fn certificate_commands(matches: &ArgMatches, renew: bool) -> i32 {
+  let _lock = run_lock(matches);
+  signal::handle_shutdown();
+
+  if !matches.is_present("cert-per-line") {
+    let started = time::Instant::now();
+    let code = exit::run(|| certificate_command(matches, renew, None));
+    let phases = timings::take();
+    logging::outcome(exit::description(code), &phases);
+    // the certificate is named after its first domain, or its application
+    // when the domains are discovered
+    let certificate = matches.value_of("domain").and_then(|domain| domain::to_ascii(domain).ok())
+      .or_else(|| matches.value_of("https-app-id").or_else(|| matches.value_of("id")).map(|id| id.to_string()))
+      .unwrap_or_default();
+    print_timings(&certificate, &phases);
+    report(matches, &[metrics::Run { certificate, code, duration: started.elapsed(), phases }]);
+    return code;
+  }
+
+  let lines = match domain::read_list(matches.value_of("domains-file").expect("required domains file")) {
+    Ok(lines) => lines,
+    Err(e) => {
+      error!("{}", e);
+      return exit::FAILURE;
+    }
+  };
+  // otherwise every line would overwrite the same files
+  for &(name, option) in [("cert", "certificate"), ("chain", "chain"), ("key", "key"), ("fullchain", "fullchain")].iter() {
+    if matches.value_of(name).map(|path| !path.contains("{domain}")).unwrap_or(false) {
+      error!("with --cert-per-line, the --{} path must contain {{domain}}", option);
+      return exit::FAILURE;
+    }
+  }
+
+  // a failure does not stop the other lines, like in a batch
+  let mut results = Vec::new();
+  let mut runs = Vec::new();
+  for names in lines.iter() {
+    let started = time::Instant::now();
+    let code = exit::run(|| certificate_command(matches, renew, Some(&names[..])));
+    let phases = timings::take();
+    logging::outcome(exit::description(code), &phases);
+    print_timings(&names[0], &phases);
+    results.push((names.join(", "), code));
+    runs.push(metrics::Run { certificate: names[0].clone(), code, duration: started.elapsed(), phases });
+    if code == exit::INTERRUPTED {
+      break;
+    }
+  }
+  report(matches, &runs);
+
+  for &(ref names, code) in results.iter() {
+    println!("{}\t{}", names, exit::description(code));
+  }
+  let not_due = results.iter().all(|&(_, code)| code == exit::NOT_DUE);
+  results.iter().map(|&(_, code)| code).find(|&code| code != 0 && code != exit::NOT_DUE)
+    .unwrap_or(if not_due { exit::NOT_DUE } else { 0 })
+}
+
+/// the time spent in each phase of the run, nothing when no certificate was requested
+fn print_timings(certificate: &str, phases: &[(&'static str, time::Duration)]) {
+  if !phases.is_empty() {
+    println!("timings for {}: {}", certificate, timings::summary(phases));
+  }
+}
+
+/// with --metrics-file, records the runs in node_exporter's textfile, and
+/// with --chat-webhook-url and --webhook-url, notifies their results. A
+/// failure does not change the exit status, the runs themselves are done
+fn report(matches: &ArgMatches, runs: &[metrics::Run]) {
+  if let Some(path) = matches.value_of("metrics-file") {
+    if let Err(e) = metrics::write(path, runs) {
+      error!("{}", e);
+    }
+  }
+
+  if let Some(url) = matches.value_of("chat-webhook-url").filter(|_| !matches.is_present("dry-run")) {
+    let mut mentions = Vec::new();
+    for mention in matches.values_of("chat-mention").into_iter().flatten() {
+      match mention.find('=') {
+        Some(i) => mentions.push((mention[..i].to_string(), mention[i + 1..].to_string())),
+        None => error!("invalid chat mention {}, the format is <domain>=<mention>", mention),
+      }
+    }
+    let chat = chat::Chat { url: url.to_string(), mentions: mentions };
+    for run in runs {
+      chat.notify(&run.certificate, run.code);
+    }
+  }
+
+  let url = match matches.value_of("webhook-url").filter(|_| !matches.is_present("dry-run")) {
+    Some(url) => url,
+    None => return,
+  };
+  let secret = match matches.value_of("webhook-secret-file").map(files::read_passphrase) {
+    Some(Ok(secret)) => Some(secret),
+    Some(Err(e)) => {
+      error!("{}", e);
+      return;
+    },
+    None => None,
+  };
+  let webhook = webhook::Webhook {
+    url:         url.to_string(),
+    secret:      secret,
+    retries:     value_t!(matches, "webhook-retries", u32).unwrap_or_else(|e| e.exit()),
+    expiry_days: value_t!(matches, "webhook-expiry-days", i32).unwrap_or_else(|e| e.exit()),
+  };
+  for run in runs {
+    webhook.notify(&run.certificate, run.code);
+  }
+}
+
+/// requests the certificate and installs it in sozu, for the names of the
+/// command line or of a line of --domains-file. Returns 0, or exit::NOT_DUE
+/// with renew when the current certificate is not due. Failures go through
+/// exit::fail, so the changes made to sozu are undone
+fn certificate_command(matches: &ArgMatches, renew: bool, line: Option<&[String]>) -> i32 {
+  // the certificate's HTTPS fronts go to this application
+  let app_id      = matches.value_of("https-app-id").or_else(|| matches.value_of("id")).expect("required application id");
+  let http_app_id = matches.value_of("http-app-id");
+  let mut ascii_domains = requested_domains(matches, line);
+  let discover    = matches.is_present("discover");
   let email       = matches.value_of("email").expect("required registration email");
   let http        = matches.value_of("http").expect("required HTTP frontend address").parse::<SocketAddr>().expect("invalid HTTP frontend address format");
-  let https       = matches.value_of("https").expect("required HTTPS frontend address").parse::<SocketAddr>().expect("invalid HTTPS frontend address format");
+  // the certificate and HTTPS fronts go to every listener
+  let https_listeners: Vec<SocketAddr> = matches.values_of("https-listener").or_else(|| matches.values_of("https"))
+    .expect("required HTTPS frontend address")
+    .map(|https| https.parse::<SocketAddr>().expect("invalid HTTPS frontend address format"))
+    .collect();
+  let https_path_begin = matches.value_of("https-path-begin").unwrap_or("");
+  let cert_only   = matches.is_present("cert-only");
+  let renew_days  = value_t!(matches, "renew-days", u32).unwrap_or_else(|e| e.exit());
+  let force_renewal = matches.is_present("force-renewal");
+  let create_app  = matches.is_present("create-app");
+  let save_state  = matches.value_of("save-state");
+  let ssh_destination = matches.value_of("ssh");
+  let challenge_type = matches.value_of("challenge").expect("challenge type has a default value");
+  let dns_hook    = matches.value_of("dns-hook");
+  let dry_run     = matches.is_present("dry-run");
+  let key_mode    = u32::from_str_radix(matches.value_of("key-mode").expect("key mode has a default value"), 8)
+    .unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("invalid key mode: {}", e)));
+  let owner       = files::Owner::new(matches.value_of("owner"), matches.value_of("group"))
+    .unwrap_or_else(|e| exit::fail(exit::FAILURE, &e));
+  let backups     = value_t!(matches, "backups", usize).unwrap_or_else(|e| e.exit());
+  let certbot_layout = matches.value_of("layout") == Some("certbot");
+  let key_passphrase = matches.value_of("key-passphrase-file")
+    .map(|path| files::read_passphrase(path).unwrap_or_else(|e| exit::fail(exit::FAILURE, &e)));
+  let key_passphrase = key_passphrase.as_ref().map(Vec::as_slice);
+  if !set_account_encryption(matches) {
+    exit::fail(exit::FAILURE, "could not read the encryption of the account key");
+  }
+  if certbot_layout && ["cert", "chain", "key", "fullchain"].iter().any(|option| matches.is_present(option)) {
+    exit::fail(exit::FAILURE, "the certbot layout names the files itself, --certificate, --chain, --key and --fullchain cannot be used");
+  }
+  if backups == 0 {
+    exit::fail(exit::FAILURE, "--backups must keep at least one backup");
+  }
+  // a dry run must not use up the production rate limits
+  let directory   = if dry_run && !matches.is_present("directory-url") { acme::LETS_ENCRYPT_STAGING } else { directory_url(&matches) };
+  let preferred_chain = matches.value_of("preferred-chain");
+  let must_staple = matches.is_present("must-staple");
+  let reuse_key   = matches.is_present("reuse-key");
+  let csr_file    = matches.value_of("csr");
+  let rate_limit_wait = value_t!(matches, "rate-limit-wait", u64).unwrap_or_else(|e| e.exit());
+  let validation_timeout = value_t!(matches, "validation-timeout", u64).unwrap_or_else(|e| e.exit());
+  let skip_caa_check = matches.is_present("skip-caa-check");
+  let agree_tos = matches.is_present("agree-tos");
+  let external_account = matches.value_of("eab-hmac-key-file").map(|path| {
+    let key = files::read_passphrase(path).unwrap_or_else(|e| exit::fail(exit::FAILURE, &e));
+    // the CAs give the MAC key base64url encoded, sometimes padded
+    let key = String::from_utf8_lossy(&key).trim().trim_end_matches('=').to_string();
+    acme::ExternalAccount {
+      kid:      matches.value_of("eab-kid").expect("--eab-hmac-key-file requires --eab-kid").to_string(),
+      hmac_key: base64::decode_config(&key, base64::URL_SAFE_NO_PAD)
+        .unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("the MAC key in {} is not base64url: {}", path, e))),
+    }
+  });
+  let profile = matches.value_of("profile");
+  let key_type    = matches.value_of("key-type").expect("key type has a default value");
+  let curve       = matches.value_of("curve").expect("curve has a default value");
+  let key_format  = matches.value_of("key-format").expect("key format has a default value");
+
+  // the tunnels of --ssh stay open for the whole run
+  let (_tunnels, channel) = connect_sozu(matches);
+  let mut channel = sozu::Transaction::new(channel);
+  channel.set_dry_run(dry_run);
 
-  let old_fingerprint = old_cert.and_then(|path| Config::load_file_bytes(path).ok())
-    .and_then(|file| calculate_fingerprint(&file));
+  if create_app && !sozu::application_exists(&mut channel, app_id) {
+    info!("creating the application {}", app_id);
+    if let Err(e) = channel.add_application(app_id) {
+      exit::fail(exit::SOZU, &format!("could not create the application {}: {}", app_id, e));
+    }
+  }
 
-  let config = Config::load_from_path(config_file).expect("could not parse configuration file");
-  let stream = UnixStream::connect(&config.command_socket).expect(&format!("could not connect to the command unix socket: {}", config.command_socket));
-  let mut channel: Channel<CommandRequest,CommandResponse> = Channel::new(stream, 10000, 20000);
-  channel.set_blocking(true);
+  // the hostnames of the application's fronts are added to the given domains
+  if discover {
+    for hostname in sozu::application_hostnames(&mut channel, app_id) {
+      if !ascii_domains.contains(&hostname) {
+        ascii_domains.push(hostname);
+      }
+    }
+    if ascii_domains.is_empty() {
+      exit::fail(exit::FAILURE, &format!("no hostname found for the application {}", app_id));
+    }
+  }
+  let domains: Vec<&str> = ascii_domains.iter().map(|domain| domain.as_str()).collect();
+  logging::set_domain(Some(domains[0]));
 
-  info!("got channel, connecting to Let's Encrypt");
+  // an unreadable CSR, or one for other names, would only fail at the
+  // finalization, once the order and its validations are spent
+  let user_csr = csr_file.map(|path| {
+    let csr = certificate::load_csr(path).unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("could not use the CSR: {}", e)));
+    let names = certificate::csr_names(&csr).unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("could not read the names of the CSR {}: {}", path, e)));
+    let mut requested: Vec<String> = domains.iter().map(|domain| domain.to_lowercase()).collect();
+    requested.sort();
+    requested.dedup();
+    if names != requested {
+      exit::fail(exit::FAILURE, &format!("the CSR {} is for {}, not {}", path, names.join(", "), requested.join(", ")));
+    }
+    csr
+  });
 
-  // Use DirectoryUrl::LetsEncrypStaging for dev/testing
-  //let url = DirectoryUrl::LetsEncryptStaging;
-  let url = DirectoryUrl::LetsEncrypt;
+  let output_dir = output_dir(matches, domains[0]);
+  let path = |option: &str, file: Option<&str>| certificate_path(matches, domains[0], output_dir.as_ref(), option, file);
+  let (certificate, old_cert) = (path("cert", Some("cert.pem")), path("old-cert", None));
+  let key_file    = if certbot_layout { "privkey.pem" } else { "key.pem" };
+  let (chain, key) = (path("chain", Some("chain.pem")), path("key", Some(key_file)));
+  let fullchain = path("fullchain", Some("fullchain.pem"));
+  let certificate = certificate.as_ref().map(String::as_str).expect("required certificate path");
+  let old_cert    = old_cert.as_ref().map(String::as_str);
+  let chain       = chain.as_ref().map(String::as_str).expect("required certificate chain path");
+  let key         = key.as_ref().map(String::as_str).expect("required key path");
 
-  let persist = FilePersist::new(".");
-  // Create a directory entrypoint.
-  let dir = Directory::from_url(persist, url).unwrap();
-  // Reads the private account key from persistence, or
-  // creates a new one before accessing the API to establish
-  // that it's there.
-  let acc = dir.account(email).unwrap();
+  let old_certificate = old_cert.and_then(|path| Config::load_file_bytes(path).ok());
+  let old_fingerprint = old_certificate.as_ref().and_then(|file| calculate_fingerprint(file));
+
+  // the certificate being renewed, the one at the certificate path by default
+  let managed = State::load().managed(domains[0]).map(|(_, managed)| managed.clone());
+  let current_certificate = old_certificate.or_else(|| current_path(certificate, managed.as_ref().map(|managed| &managed.certificate))
+    .and_then(|path| Config::load_file_bytes(&path).ok()));
+
+  // scheduled runs do nothing until the current certificate is due: the CA's
+  // renewal window opened (ARI), or it expires in less than --renew-days days
+  if let Some(current_certificate) = current_certificate.as_ref().filter(|_| !force_renewal) {
+    let names = revoke::certificate_names(&String::from_utf8_lossy(current_certificate));
+    if domains.iter().all(|domain| names.iter().any(|name| name == domain)) {
+      let due = renewal::ari_window_open(directory, current_certificate)
+        .unwrap_or_else(|| renewal::expires_within(current_certificate, renew_days));
+      if !due {
+        info!("the certificate for {} is not due for renewal", domains.join(", "));
+        channel.commit();
+        if renew {
+          return exit::NOT_DUE;
+        }
+        info!("DONE");
+        return 0;
+      }
+    }
+  }
 
-  // Order a new TLS certificate for a domain.
-  let mut ord_new = acc.new_order(domain, &[]).unwrap();
+  logging::set_phase(Some("order"));
+  info!("requesting a certificate for {}", domains.iter().map(|d| domain::display(d)).collect::<Vec<_>>().join(", "));
+
+  info!("got channel, connecting to {}", directory);
+  let url = DirectoryUrl::Other(directory);
+
+  timings::start("account");
+  // the prompt is answered before taking the lock, the other runs of a
+  // batch do not wait on it
+  if !account::registered(email) && !account::accept_terms(directory, agree_tos) {
+    exit::fail(exit::FAILURE, "the account cannot be registered without agreeing to the terms of service");
+  }
+  let registration_lock = account::lock_registration().unwrap_or_else(|e| exit::fail(exit::FAILURE, &e));
+  let registered = account::registered(email);
+  let mut client = if dry_run && !registered {
+    // the key of the new account only lives in memory
+    println!("would register a new ACME account for {}", email);
+    let dir = Directory::from_url(MemoryPersist::new(), url).unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not reach {}: {}", directory, e)));
+    let account = dir.account(email).unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not create the account: {}", e)));
+    acme::AcmeClient::new(directory, account.acme_private_key_pem().as_bytes())
+      .unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not load the ACME account: {}", e)))
+  } else {
+    // acme-lib cannot bind the account, the key is registered first and
+    // acme-lib then finds the account as an existing one
+    if let (false, Some(external)) = (registered, external_account.as_ref()) {
+      if !account::register(directory, email, external) {
+        exit::fail(exit::ACME, "could not register the ACME account");
+      }
+    }
+    let persist = account::persist();
+    // Create a directory entrypoint.
+    let dir = Directory::from_url(persist, url).unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not reach {}: {}", directory, e)));
+    // Reads the private account key from persistence, or
+    // creates a new one before accessing the API to establish
+    // that it's there.
+    dir.account(email).unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not load the account: {}", e)));
+    account::client(directory, email).unwrap_or_else(|| exit::fail(exit::ACME, "could not load the ACME account"))
+  };
+  drop(registration_lock);
+  client.max_rate_limit_wait = time::Duration::from_secs(rate_limit_wait);
+  client.validation_timeout = time::Duration::from_secs(validation_timeout);
+  timings::start("order");
+
+  // CAA records can forbid the CA from issuing for the domains, better
+  // know it before creating authorizations that will fail
+  if !skip_caa_check {
+    let identities = client.directory.meta.as_ref().map(|meta| meta.caa_identities.clone()).unwrap_or_else(Vec::new);
+    if identities.is_empty() {
+      debug!("the CA does not publish its CAA identities, skipping the CAA check");
+    } else if let Err(e) = caa::check(&domains, &identities) {
+      exit::fail(exit::FAILURE, &e);
+    }
+  }
+
+  // Order a new TLS certificate for the domains, the first one
+  // is the primary name, the other ones are added as SANs.
+  // An order left pending by a previous run is resumed instead,
+  // its authorizations may already be valid
+  let mut state = State::load();
+
+  // without --profile, renewals use the profile of the previous order
+  let profile = profile.map(|p| p.to_string()).or_else(|| state.profile(&domains).cloned());
+  if let Some(ref profile) = profile {
+    let profiles = client.directory.meta.as_ref().map(|meta| &meta.profiles);
+    if let Some(profiles) = profiles.filter(|profiles| !profiles.is_empty()) {
+      if !profiles.contains_key(profile) {
+        exit::fail(exit::ACME, &format!("the CA does not offer the profile {}, available profiles: {}", profile,
+          profiles.keys().cloned().collect::<Vec<_>>().join(", ")));
+      }
+    }
+    state.set_profile(&domains, profile);
+  }
+  let resumed = state.pending_order(&domains).cloned().and_then(|url| match client.order(&url) {
+    Ok(order) => if order.status == "pending" || order.status == "ready" {
+      info!("resuming the {} order {}", order.status, url);
+      Some((url, order))
+    } else {
+      debug!("previous order {} is {}, creating a new one", url, order.status);
+      None
+    },
+    Err(e) => {
+      debug!("could not resume the order {}: {}", url, e);
+      None
+    }
+  });
+  let (order_url, mut order) = match resumed {
+    Some(resumed) => resumed,
+    None => {
+      let (url, order) = client.new_order(&domains, profile.as_ref().map(|p| p.as_str())).unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not create the order: {}", e)));
+      if !dry_run {
+        state.set_pending_order(&domains, &url);
+        if !state.save() {
+          warn!("the order {} could not be recorded, an interrupted run will not resume it", url);
+        }
+      }
+      (url, order)
+    }
+  };
+
+  // without the challenge routes, the CA cannot validate the domains: a dry
+  // run stops at the authorizations, and describes the rest
+  if dry_run {
+    println!("created the order {} on {}", order_url, directory);
+    for auth_url in order.authorizations.iter() {
+      let auth = client.authorization(auth_url).unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not get the authorization: {}", e)));
+      if auth.status == "pending" {
+        let challenge_type = if auth.wildcard.unwrap_or(false) { "dns-01" } else { challenge_type };
+        println!("would validate {} with {}", auth.identifier.value, challenge_type);
+      } else {
+        println!("{} is already {}", auth.identifier.value, auth.status);
+      }
+    }
+
+    match csr_file {
+      Some(path) => println!("would finalize the order with the CSR {}", path),
+      None if reuse_key => println!("would finalize the order with a CSR for the key at {}", key),
+      None => println!("would generate a {} key and finalize the order with its CSR", key_type),
+    }
+    println!("would write the certificate to {} and the chain to {}", certificate, chain);
+    if let Some(ref fullchain) = fullchain {
+      println!("would write the certificate and its chain to {}", fullchain);
+    }
+    if csr_file.is_none() && !reuse_key {
+      println!("would write the key to {}", key);
+    }
+
+    for listener in https_listeners.iter() {
+      match old_fingerprint.clone().or_else(|| sozu::certificate_fingerprint(&mut channel, listener, domains[0])) {
+        Some(fingerprint) => println!("would replace the certificate {} on {}",
+          fingerprint.iter().map(|b| format!("{:02x}", b)).collect::<String>(), listener),
+        None => println!("would add the certificate on {}", listener),
+      }
+      let missing = if cert_only { Vec::new() } else { sozu::missing_https_fronts(&mut channel, app_id, listener, &domains, https_path_begin) };
+      if !missing.is_empty() {
+        println!("would add HTTPS fronts for {} to {} on {}", missing.join(", "), app_id, listener);
+      }
+    }
+    if let Some(path) = save_state {
+      println!("would have sozu save its state to {}", path);
+    }
+    println!("would record the certificate in {}", state::STATE_FILE);
+
+    channel.commit();
+    info!("DONE");
+    return 0;
+  }
+
+  logging::set_phase(Some("validation"));
+  timings::start("challenges");
+  // the HTTP challenge server and its sozu backend are shared by all the
+  // domains of the order, and only created if an HTTP challenge is needed
+  let mut challenge_server: Option<ChallengeServer> = None;
+  // a remote sozu reaches the challenge server through SSH
+  let mut challenge_tunnel: Option<ssh::Tunnel> = None;
 
   // If the ownership of the domain(s) have already been
   // authorized in a previous order, you might be able to
   // skip validation. The ACME API provider decides.
-  let ord_csr = loop {
+  loop {
     // are we done?
-    if let Some(ord_csr) = ord_new.confirm_validations() {
-      break ord_csr;
-    }
-
-    // Get the possible authorizations (for a single domain
-    // this will only be one element).
-    let auths = ord_new.authorizations().unwrap();
-    let auth = &auths[0];
-    let challenge = auth.http_challenge();
-    let challenge_token = challenge.http_token();
-
-    let path = format!("/.well-known/acme-challenge/{}", challenge_token);
-    let key_authorization = challenge.http_proof();
-    debug!("HTTP challenge token: {} key: {}", challenge_token, key_authorization);
-
-    let server = Server::http("127.0.0.1:0").expect("could not create HTTP server");
-    let address = server.server_addr();
-    let acme_app_id = generate_app_id(&app_id);
-
-    debug!("setting up proxying");
-    if !set_up_proxying(&mut channel, &http, &acme_app_id, domain, &path, address) {
-      panic!("could not set up proxying to HTTP challenge server");
-    }
-
-    let path2 = path.clone();
-    let server_thread = thread::spawn(move || {
-      info!("HTTP server started");
-      loop {
-        let request = match server.recv() {
-          Ok(rq) => rq,
-          Err(e) => { error!("error: {}", e); break }
-        };
-
-        info!("got request to URL: {}", request.url());
-        if request.url() == path {
-          request.respond(Response::from_data(key_authorization.as_bytes()).with_status_code(200));
-          info!("challenge request answered");
-          // the challenge can be called multiple times
-          //return true;
-        } else {
-          request.respond(Response::from_data(&b"not found"[..]).with_status_code(404));
-        }
+    match order.status.as_str() {
+      "ready" => break,
+      "pending" => {},
+      status => exit::fail(exit::ACME, &format!("the order is {}: {:?}", status, order.error)),
+    }
+
+    // Get the authorizations (one per domain in the order),
+    // and only handle the ones that still need a challenge
+    let mut pending = false;
+    for auth_url in order.authorizations.iter() {
+      let auth = client.authorization(auth_url).unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not get the authorization: {}", e)));
+      if auth.status != "pending" {
+        continue;
       }
+      pending = true;
 
-      false
-    });
+      // wildcard names can only be validated through DNS, and IP addresses never are
+      let challenge_type = if auth.wildcard.unwrap_or(false) {
+        "dns-01"
+      } else if auth.identifier.kind == "ip" && challenge_type == "dns-01" {
+        exit::fail(exit::FAILURE, &format!("{} is an IP address, it cannot be validated with dns-01", auth.identifier.value));
+      } else {
+        challenge_type
+      };
+      let validated = match challenge_type {
+        "dns-01"      => challenge::dns_challenge(&mut client, dns_hook, auth_url, &auth),
+        _             => {
+          if challenge_server.is_none() {
+            // a temporary application by default
+            let server = ChallengeServer::new(http_app_id.map(|id| id.to_string()).unwrap_or_else(|| sozu::generate_app_id(app_id)));
+            if let Some(destination) = ssh_destination {
+              challenge_tunnel = Some(ssh::Tunnel::reverse(destination, &server.address).unwrap_or_else(|e| exit::fail(exit::FAILURE, &e)));
+            }
+            if let Err(e) = channel.set_up_proxying(&server.app_id, &server.backend_id, server.address) {
+              exit::fail(exit::SOZU, &format!("could not set up proxying to HTTP challenge server: {}", e));
+            }
+            challenge_server = Some(server);
+          }
+          challenge::http_challenge(&mut channel, &mut client, &http, challenge_server.as_ref().unwrap(), auth_url, &auth)
+        },
+      };
 
-    thread::sleep(time::Duration::from_millis(100));
+      if let Err(code) = validated {
+        exit::fail(code, &format!("could not validate the {} challenge for {}", challenge_type, auth.identifier.value));
+      }
+    }
 
-    challenge.validate(2000).unwrap();
-    info!("challenge validated");
-    ord_new.refresh().unwrap();
+    if !pending {
+      // no challenge left to answer, the order is not ready yet
+      timings::start("validation");
+      thread::sleep(time::Duration::from_millis(1000));
+    }
+    signal::check_shutdown();
 
-    //let res = server_thread.join().expect("HTTP server thread failed");
-    //if res {
-      if !remove_proxying(&mut channel, &http, &acme_app_id, domain, &path2, address) {
-        error!("could not deactivate proxying");
-        panic!();
-      }
-    //}
-  };
+    // the order status changes once all authorizations are valid
+    order = client.order(&order_url).unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not get the order: {}", e)));
+  }
 
+  if let Some(server) = challenge_server.take() {
+    if let Err(e) = channel.remove_proxying(&server.app_id, &server.backend_id, server.address) {
+      error!("could not deactivate proxying: {}", e);
+    }
+  }
+  challenge_tunnel.take();
+
+  logging::set_phase(Some("finalize"));
+  timings::start("signing");
   // Ownership is proven. Create a private key for
-  // the certificate. These are provided for convenience, you
-  // can provide your own keypair instead if you want.
-  let pkey_pri = create_p384_key();
+  // the certificate, or reuse the current one. With a
+  // user provided CSR, the key never goes through this tool.
+  let (pkey_pri, csr) = match user_csr {
+    Some(csr) => (None, csr),
+    None => {
+      let current_key = current_path(key, managed.as_ref().map(|managed| &managed.key));
+      let existing_key = match current_key {
+        Some(ref current_key) if reuse_key => certificate::load_key(current_key, key_passphrase)
+          .unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("could not reuse the private key: {}", e))),
+        _ => None,
+      };
+      let pkey_pri = match existing_key {
+        Some(pkey) => {
+          info!("reusing the private key at {}", current_key.as_ref().map(String::as_str).unwrap_or(key));
+          pkey
+        },
+        None => certificate::generate_key(key_type, curve),
+      };
+      let csr = certificate::generate_csr(&pkey_pri, &domains, must_staple).expect("could not generate the CSR");
+      (Some(pkey_pri), csr)
+    }
+  };
+
+  // serialized before the order is finalized, a key that does not fit the
+  // --key-format is known before the certificate is issued. The key file is
+  // encrypted with --key-passphrase-file, the storage backends get the key
+  // in clear
+  let serialize = |passphrase: Option<&[u8]>| pkey_pri.as_ref().map(|pkey| certificate::key_to_pem(pkey, key_format, passphrase)
+    .unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("could not serialize the private key: {}", e))));
+  let (key_pem, key_file_pem) = (serialize(None), serialize(key_passphrase));
 
   // Submit the CSR. This causes the ACME provider to enter a
   // state of "processing" that must be polled until the
-  // certificate is either issued or rejected. Again we poll
-  // for the status change.
-  let ord_cert =
-    ord_csr.finalize_pkey(pkey_pri, 5000).unwrap();
-
-  // Now download the certificate. Also stores the cert in
-  // the persistence.
-  let cert = ord_cert.download_and_save_cert().unwrap();
-
-  info!("got cert: \n{}", cert.certificate());
-  let certificates = sozu_command::certificate::split_certificate_chain(cert.certificate().to_string());
-  let mut file = File::create(certificate).unwrap();
-  file.write_all(certificates[0].as_bytes());
-  //FIXME: there may be more than 1 cert in the chain
-  let mut file = File::create(chain).unwrap();
-  file.write_all(certificates[1].as_bytes());
-  let mut file = File::create(key).unwrap();
-  file.write_all(cert.private_key().as_bytes());
+  // certificate is either issued or rejected.
+  let order = client.finalize(&order_url, &order, &csr, 5000).unwrap_or_else(|e| match e {
+    acme::AcmeError::Timeout(_) => exit::fail(exit::VALIDATION_TIMEOUT, &format!("could not finalize the order: {}", e)),
+    e => exit::fail(exit::ACME, &format!("could not finalize the order: {}", e)),
+  });
+  let certificate_url = order.certificate.unwrap_or_else(|| exit::fail(exit::ACME, "the CA did not provide the certificate URL"));
+  state.remove_pending_order(&domains);
+  if !state.save() {
+    warn!("the finalized order is still recorded as pending, the next run will create a new one");
+  }
 
-  info!("saved cert and key");
-  if !add_certificate(&mut channel, &https, domain, certificate, chain, key, old_fingerprint) {
-    error!("could not add new certificate");
+  // Now download the certificate, along with the alternate
+  // chains the CA offers
+  let chains = client.certificate_chains(&certificate_url).unwrap_or_else(|e| exit::fail(exit::ACME, &format!("could not download the certificate: {}", e)));
+  let certificate_chain = match preferred_chain {
+    None => chains[0].clone(),
+    Some(preferred) => match select_chain(&chains, preferred) {
+      Some(chain) => chain,
+      None => {
+        warn!("no chain issued by {}, using the default chain", preferred);
+        chains[0].clone()
+      }
+    }
+  };
+  info!("got cert: \n{}", certificate_chain);
+
+  logging::set_phase(Some("install"));
+  timings::start("installation");
+  if let Some(ref dir) = output_dir {
+    fs::create_dir_all(dir).unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("could not create {}: {}", dir, e)));
+  }
+  let certificates = sozu_command::certificate::split_certificate_chain(certificate_chain);
+
+  // the placeholders of the issuance in the paths
+  let fingerprint = calculate_fingerprint(certificates[0].as_bytes())
+    .map(|fingerprint| fingerprint.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+    .unwrap_or_default();
+  let (certificate, chain, key) = (issued_path(certificate, &fingerprint), issued_path(chain, &fingerprint), issued_path(key, &fingerprint));
+  let (certificate, chain, key) = (certificate.as_str(), chain.as_str(), key.as_str());
+  let fullchain = fullchain.map(|fullchain| issued_path(&fullchain, &fingerprint));
+
+  // the replaced files are kept, to roll back a broken renewal by copying
+  // them back
+  if let Some(dir) = matches.value_of("backup-dir") {
+    let dir = format!("{}/{}", dir.trim_end_matches('/'), domains[0].replace('*', "_"));
+    let mut paths = vec![certificate, chain, key];
+    paths.extend(fullchain.as_ref().map(String::as_str));
+    match files::backup(&dir, &paths, backups) {
+      Ok(Some(backup)) => info!("saved the previous certificate to {}", backup),
+      Ok(None) => {},
+      Err(e) => exit::fail(exit::FAILURE, &format!("could not back up the previous certificate to {}: {}", dir, e)),
+    }
+  }
+
+  if certbot_layout {
+    let base = matches.value_of("output-dir").expect("the certbot layout requires the output directory");
+    let (chain_pem, fullchain_pem) = (certificates[1..].join("\n"), certificates.join("\n"));
+    let mut versioned = vec![
+      ("cert", certificates[0].as_bytes(), None),
+      ("chain", chain_pem.as_bytes(), None),
+      ("fullchain", fullchain_pem.as_bytes(), None),
+    ];
+    versioned.extend(key_file_pem.as_ref().map(|key_pem| ("privkey", key_pem.as_slice(), Some(key_mode))));
+    match files::write_version(base, &domains[0].replace('*', "_"), &versioned, &owner) {
+      Ok(version) => info!("saved version {} of the certificate in {}/archive", version, base),
+      Err(e) => exit::fail(exit::FAILURE, &format!("could not write the certificate in {}: {}", base, e)),
+    }
   } else {
-    info!("added new certificate");
+    // the directories of the paths with placeholders may be new
+    let write = |path: &str, content: &[u8], mode: Option<u32>| Path::new(path).parent().map_or(Ok(()), fs::create_dir_all)
+      .and_then(|_| files::write(path, content, mode, &owner))
+      .unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("could not write {}: {}", path, e)));
+    write(certificate, certificates[0].as_bytes(), None);
+    write(chain, certificates[1..].join("\n").as_bytes(), None);
+    // the leaf then the intermediates, like certbot's fullchain.pem, for the
+    // other servers sharing the certificate
+    if let Some(ref fullchain) = fullchain {
+      write(fullchain, certificates.join("\n").as_bytes(), None);
+    }
+    if let Some(ref key_pem) = key_file_pem {
+      write(key, key_pem, Some(key_mode));
+    }
+  }
+
+  // alternate chains are saved next to the chain, to switch
+  // trust paths without reissuing
+  for (i, alternate) in chains.iter().skip(1).enumerate() {
+    let path = alternate_chain_path(chain, i + 1);
+    let certificates = sozu_command::certificate::split_certificate_chain(alternate.to_string());
+    match files::write(&path, certificates[1..].join("\n").as_bytes(), None, &owner) {
+      Ok(()) => info!("saved alternate chain to {}", path),
+      Err(e) => error!("could not save alternate chain to {}: {}", path, e),
+    }
+  }
+
+  info!("saved cert and key");
+
+  // for the pinning configurations, the key's pin changes unless it is reused
+  if matches.is_present("spki-pin") || matches.is_present("spki-pin-file") {
+    let pin = certificate::spki_sha256(certificates[0].as_bytes())
+      .unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("could not hash the certificate's public key: {}", e)));
+    let pin = base64::encode(&pin);
+    println!("pin-sha256=\"{}\"", pin);
+    if let Some(path) = certificate_path(matches, domains[0], None, "spki-pin-file", None) {
+      let path = issued_path(&path, &fingerprint);
+      files::write(&path, format!("{}\n", pin).as_bytes(), None, &owner)
+        .unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("could not write {}: {}", path, e)));
+    }
+  }
+
+  // DANE-EE records matching the certificate's public key (usage 3,
+  // selector 1, SHA-256), for each name and port it is served on
+  if matches.is_present("emit-tlsa") {
+    let spki = certificate::spki_sha256(certificates[0].as_bytes())
+      .unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("could not hash the certificate's public key: {}", e)));
+    let spki: String = spki.iter().map(|b| format!("{:02x}", b)).collect();
+    let mut ports: Vec<u16> = match matches.value_of("tlsa-port") {
+      Some(port) => vec![port.parse().unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("invalid TLSA port: {}", e)))],
+      None => https_listeners.iter().map(|listener| listener.port()).collect(),
+    };
+    ports.sort();
+    ports.dedup();
+    for domain in domains.iter().filter(|domain| !domain::is_ip(domain)) {
+      for port in ports.iter() {
+        println!("_{}._tcp.{}. IN TLSA 3 1 1 {}", port, domain, spki);
+      }
+    }
+  }
+
+  let new_fingerprint = Config::load_file_bytes(certificate).ok().and_then(|pem| calculate_fingerprint(&pem));
+  let mut replaced = Vec::new();
+  for listener in https_listeners.iter() {
+    let current_fingerprint = sozu::certificate_fingerprint(&mut channel, listener, domains[0]);
+    // a re-run with the same certificate sends no order
+    let up_to_date = new_fingerprint.is_some() && current_fingerprint == new_fingerprint;
+    // a renewal replaces the certificate sozu currently uses for the
+    // domain, so the swap is atomic and the old one does not linger
+    let old_fingerprint = old_fingerprint.clone().or_else(|| {
+      if current_fingerprint.is_some() && !up_to_date {
+        info!("replacing the certificate sozu uses for {} on {}", domains[0], listener);
+      }
+      current_fingerprint
+    });
+    if up_to_date {
+      info!("the certificate on {} is already up to date", listener);
+    } else {
+      if let Err(e) = channel.add_certificate(listener, &domains, certificate, chain, key, key_passphrase, old_fingerprint.clone()) {
+        exit::fail(exit::SOZU, &format!("could not add new certificate on {}: {}", listener, e));
+      }
+      info!("added new certificate on {}", listener);
+    }
+
+    // renewals keep the existing fronts. With --cert-only, the routes are managed elsewhere
+    let missing = if cert_only { Vec::new() } else { sozu::missing_https_fronts(&mut channel, app_id, listener, &domains, https_path_begin) };
+    let missing: Vec<&str> = missing.iter().map(|hostname| hostname.as_str()).collect();
+    if let Err(errors) = channel.add_https_fronts(listener, app_id, &missing, https_path_begin) {
+      for (hostname, e) in errors {
+        error!("could not add HTTPS front for {} on {}: {}", hostname, listener, e);
+      }
+      exit::fail(exit::SOZU, &format!("could not add the HTTPS fronts on {}", listener));
+    }
+
+    if let Some(old_fingerprint) = old_fingerprint.filter(|_| !up_to_date) {
+      replaced.push((listener, old_fingerprint));
+    }
+  }
+  channel.commit();
+
+  // make sure the previous certificates do not linger in sozu's
+  // store, unless they still serve names the new one does not cover
+  for (listener, old_fingerprint) in replaced {
+    if let Some((_, names)) = sozu::certificate_by_fingerprint(&mut channel, &old_fingerprint) {
+      let uncovered: Vec<&String> = names.iter().filter(|name| !domains.contains(&name.as_str())).collect();
+      if !uncovered.is_empty() {
+        warn!("keeping the previous certificate, it is still used for {:?}", uncovered);
+      } else {
+        match sozu::remove_certificate(&mut channel, listener, old_fingerprint, &names) {
+          Ok(()) => info!("removed the previous certificate on {}", listener),
+          Err(e) => error!("could not remove the previous certificate on {}: {}", listener, e),
+        }
+      }
+    }
+  }
+
+  if let Some(path) = save_state {
+    if let Err(e) = sozu::save_state(&mut channel, path) {
+      error!("could not save sozu's state to {}: {}", path, e);
+    }
+  }
+
+  // the daemon runs the same command again to renew the certificate
+  state.set_managed(&domains, ManagedCertificate {
+    certificate: certificate.to_string(),
+    arguments: line.map(line_arguments).unwrap_or_else(|| env::args().skip(1).collect()),
+    chain: chain.to_string(),
+    key: key.to_string(),
+    fingerprint: new_fingerprint.map(|fingerprint| fingerprint.iter().map(|b| format!("{:02x}", b)).collect()).unwrap_or_default(),
+    email: email.to_string(),
+    directory: directory.to_string(),
+    order_url: order_url.clone(),
+    path_begin: https_path_begin.to_string(),
+    issued_at: Utc::now().to_rfc3339(),
+    renewed_at: None,
+  });
+  // the certificate is installed, the copies are still made, but the run
+  // fails: the daemon would never renew it
+  let recorded = state.save();
+  if !recorded {
+    error!("the certificate is installed but could not be recorded in {}, it will not be renewed", state::STATE_FILE);
+  }
+
+  // the copies for the other consumers of the certificate come last, a
+  // failure leaves the installation in place
+  let key_pem = key_pem.map(|pem| String::from_utf8_lossy(&pem).into_owned());
+  logging::set_phase(Some("storage"));
+  timings::start("storage");
+  if !store_certificate(matches, domains[0], &certificates[0], &certificates[1..].join("\n"), key_pem.as_ref().map(|pem| pem.as_str())) {
+    return exit::STORAGE;
+  }
+  if !recorded {
+    return exit::FAILURE;
   }
 
   info!("DONE");
+  0
 }
 
-fn generate_id() -> String {
-  let s: String = iter::repeat(()).map(|()| thread_rng().sample(Alphanumeric)).take(6).map(|x| x.to_string()).collect();
-  format!("ID-{}", s)
+/// copies the certificate to the storage backends given on the command line
+fn store_certificate(matches: &ArgMatches, domain: &str, certificate: &str, chain: &str, key: Option<&str>) -> bool {
+  let mut stored = true;
+
+  if let Some(address) = matches.value_of("vault-addr") {
+    // the credentials are read from files, the command line shows in ps
+    // and is recorded in the state file for the renewals
+    let auth = match (matches.value_of("vault-token-file"), matches.value_of("vault-role-id"), matches.value_of("vault-secret-id-file")) {
+      (_, Some(role_id), Some(path)) => files::read_passphrase(path)
+        .map(|secret_id| vault::Auth::AppRole { role_id: role_id.to_string(), secret_id: String::from_utf8_lossy(&secret_id).into_owned() }),
+      (Some(path), _, _) => files::read_passphrase(path)
+        .map(|token| vault::Auth::Token(String::from_utf8_lossy(&token).into_owned())),
+      _ => Ok(vault::Auth::Token(env::var("VAULT_TOKEN").unwrap_or_default())),
+    };
+    let auth = match auth {
+      Ok(auth) => auth,
+      Err(e) => {
+        error!("{}", e);
+        return false;
+      }
+    };
+    let vault = vault::Vault {
+      address:    address.to_string(),
+      mount:      matches.value_of("vault-mount").expect("Vault mount has a default value").to_string(),
+      path:       matches.value_of("vault-path").expect("Vault path has a default value").replace("{domain}", domain),
+      kv_version: value_t!(matches, "vault-kv-version", u8).unwrap_or_else(|e| e.exit()),
+      auth,
+    };
+    if let Err(e) = vault.store(certificate, chain, key) {
+      error!("{}", e);
+      stored = false;
+    }
+  }
+
+  if let Some(name) = matches.value_of("kube-secret") {
+    let secret = kubernetes::Secret {
+      // Secret names cannot contain a *
+      name:      name.replace("{domain}", &domain.replace('*', "wildcard")),
+      namespace: matches.value_of("kube-namespace").expect("namespace has a default value").to_string(),
+      context:   matches.value_of("kube-context").map(|context| context.to_string()),
+      kubectl:   matches.value_of("kubectl").expect("kubectl has a default value").to_string(),
+    };
+    if let Err(e) = secret.store(certificate, chain, key) {
+      error!("{}", e);
+      stored = false;
+    }
+  }
+
+  if let Some(name) = matches.value_of("container-secret") {
+    let secrets = container::Secrets {
+      engine: matches.value_of("container-engine").expect("container engine has a default value").to_string(),
+      // secret names cannot contain a *
+      name:   name.replace("{domain}", &domain.replace('*', "wildcard")),
+    };
+    match secrets.store(certificate, chain, key) {
+      // printed for the scripts updating the services
+      Ok(names) => for name in names {
+        println!("{}", name);
+      },
+      Err(e) => {
+        error!("{}", e);
+        stored = false;
+      }
+    }
+  }
+
+  if let Some(bucket) = matches.value_of("s3-bucket") {
+    let region = matches.value_of("s3-region").expect("region has a default value");
+    let passphrase = match matches.value_of("s3-passphrase-file").map(files::read_passphrase) {
+      Some(Ok(passphrase)) => Some(passphrase),
+      Some(Err(e)) => {
+        error!("{}", e);
+        return false;
+      },
+      None => None,
+    };
+    // like the passphrase, the secret key is not given on the command line
+    let secret_key = match matches.value_of("s3-secret-key-file").map(files::read_passphrase) {
+      Some(Ok(secret_key)) => String::from_utf8_lossy(&secret_key).into_owned(),
+      Some(Err(e)) => {
+        error!("{}", e);
+        return false;
+      },
+      None => env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+    };
+    let bucket = s3::Bucket {
+      endpoint:      matches.value_of("s3-endpoint").map(|endpoint| endpoint.to_string())
+        .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region)),
+      bucket:        bucket.to_string(),
+      region:        region.to_string(),
+      prefix:        matches.value_of("s3-prefix").expect("prefix has a default value").replace("{domain}", &domain.replace('*', "_")),
+      access_key:    matches.value_of("s3-access-key").map(|key| key.to_string()).or_else(|| env::var("AWS_ACCESS_KEY_ID").ok()).unwrap_or_default(),
+      secret_key:    secret_key,
+      session_token: env::var("AWS_SESSION_TOKEN").ok(),
+      encryption:    matches.value_of("s3-sse").map(|encryption| encryption.to_string()),
+      kms_key_id:    matches.value_of("s3-kms-key-id").map(|key_id| key_id.to_string()),
+      passphrase,
+    };
+    if let Err(e) = bucket.store(certificate, chain, key) {
+      error!("{}", e);
+      stored = false;
+    }
+  }
+
+  stored
 }
 
-fn generate_app_id(app_id: &str) -> String {
-  let s: String = iter::repeat(()).map(|()| thread_rng().sample(Alphanumeric)).take(6).map(|x| x.to_string()).collect();
-  format!("{}-ACME-{}", app_id, s)
+/// the names of the certificate, from --domain, --domains-file and --alias, or
+/// from a line of --domains-file. They are sent to the CA and sozu in their ASCII form
+fn requested_domains(matches: &ArgMatches, line: Option<&[String]>) -> Vec<String> {
+  let mut ascii_domains: Vec<String> = match line {
+    Some(names) => names.to_vec(),
+    None => matches.values_of("domain").map(|domains| domains
+      .map(|domain| domain::to_ascii(domain).unwrap_or_else(|e| exit::fail(exit::FAILURE, &e)))
+      .collect()).unwrap_or_else(Vec::new),
+  };
+  if let Some(path) = matches.value_of("domains-file").filter(|_| line.is_none()) {
+    for names in domain::read_list(path).unwrap_or_else(|e| exit::fail(exit::FAILURE, &e)) {
+      for name in names {
+        if !ascii_domains.contains(&name) {
+          ascii_domains.push(name);
+        }
+      }
+    }
+  }
+  // aliases come after the domains, they are never the common name
+  for alias in matches.values_of("alias").into_iter().flatten() {
+    let alias = domain::to_ascii(alias).unwrap_or_else(|e| exit::fail(exit::FAILURE, &e));
+    if !ascii_domains.contains(&alias) {
+      ascii_domains.push(alias);
+    }
+  }
+  ascii_domains
+}
+
+/// with --output-dir, the directory of the certificate's files, named after
+/// its first name. The * of a wildcard becomes _
+fn output_dir(matches: &ArgMatches, domain: &str) -> Option<String> {
+  // certbot's live directory, where the links to the last version are
+  let live = if matches.value_of("layout") == Some("certbot") { "/live" } else { "" };
+  matches.value_of("output-dir").map(|dir| format!("{}{}/{}", dir.trim_end_matches('/'), live, domain.replace('*', "_")))
+}
+
+/// the path given with the option, where {domain} is the first domain.
+/// Without it, the file in the output directory. {date} and {fingerprint}
+/// are only known once the certificate is issued, see `issued_path`
+fn certificate_path(matches: &ArgMatches, domain: &str, output_dir: Option<&String>, option: &str,
+  file: Option<&str>) -> Option<String> {
+  matches.value_of(option).map(|path| path.replace("{domain}", &domain.replace('*', "_")))
+    .or_else(|| file.and_then(|file| output_dir.map(|dir| format!("{}/{}", dir, file))))
+}
+
+/// the path of the files of the current certificate: with the placeholders
+/// of the issuance, the one recorded in the state file
+fn current_path(path: &str, recorded: Option<&String>) -> Option<String> {
+  if path.contains("{date}") || path.contains("{fingerprint}") {
+    recorded.filter(|recorded| !recorded.is_empty()).cloned()
+  } else {
+    Some(path.to_string())
+  }
+}
+
+/// the path of the new certificate's file, {date} is the issuance day and
+/// {fingerprint} the SHA-256 fingerprint of the certificate in hexadecimal
+fn issued_path(path: &str, fingerprint: &str) -> String {
+  path.replace("{date}", &Utc::now().format("%Y%m%d").to_string()).replace("{fingerprint}", fingerprint)
+}
+
+/// connects to all the sozu instances, every order goes to each of them.
+/// With --ssh, the sockets are on the remote host, the tunnels must be kept
+/// open while the channel is used
+fn connect_sozu(matches: &ArgMatches) -> (Vec<ssh::Tunnel>, sozu::Sozu) {
+  let sockets: Vec<String> = matches.values_of("config").into_iter().flatten()
+    .map(|config| sozu::command_socket(config).unwrap_or_else(|e| exit::fail(exit::FAILURE, &e)))
+    .chain(matches.values_of("socket").into_iter().flatten().map(|socket| socket.to_string()))
+    .collect();
+  let sozu_timeout = value_t!(matches, "sozu-timeout", u64).unwrap_or_else(|e| e.exit());
+  let sozu_deadline = value_t!(matches, "sozu-deadline", u64).unwrap_or_else(|e| e.exit());
+  sozu::set_timeouts(time::Duration::from_secs(sozu_timeout), time::Duration::from_secs(sozu_deadline));
+
+  let tunnels: Vec<ssh::Tunnel> = match matches.value_of("ssh") {
+    Some(destination) => sockets.iter().map(|socket| ssh::Tunnel::open(destination, socket)
+      .unwrap_or_else(|e| exit::fail(exit::FAILURE, &e))).collect(),
+    None => Vec::new(),
+  };
+  let sockets: Vec<String> = if tunnels.is_empty() {
+    sockets
+  } else {
+    tunnels.iter().filter_map(|tunnel| tunnel.local_socket.clone()).collect()
+  };
+  let channel = sozu::try_connect(&sockets).unwrap_or_else(|e| exit::fail(exit::SOZU, &e));
+  (tunnels, channel)
 }
 
-fn set_up_proxying(channel: &mut Channel<CommandRequest,CommandResponse>, frontend: &SocketAddr, app_id: &str, hostname: &str, path_begin: &str,
-  server_address: SocketAddr) -> bool {
+/// the command line requesting the certificate of a line of --domains-file,
+/// so the daemon renews it on its own
+fn line_arguments(names: &[String]) -> Vec<String> {
+  let mut arguments = Vec::new();
+  let mut skip_value = false;
+  for argument in env::args().skip(1) {
+    if skip_value {
+      skip_value = false;
+    } else if argument == "--domains-file" {
+      skip_value = true;
+    } else if argument != "--cert-per-line" && !argument.starts_with("--domains-file=") {
+      arguments.push(argument.replace("{domain}", &names[0]));
+    }
+  }
+  for name in names.iter() {
+    arguments.push("--domain".to_string());
+    arguments.push(name.clone());
+  }
+  arguments
+}
+
+/// chain (in PEM format) whose topmost certificate is issued by this common name
+fn select_chain(chains: &[String], issuer: &str) -> Option<String> {
+  chains.iter().find(|chain| {
+    sozu_command::certificate::split_certificate_chain(chain.to_string()).last()
+      .and_then(|pem| X509::from_pem(pem.as_bytes()).ok())
+      .map(|cert| cert.issuer_name().entries_by_nid(Nid::COMMONNAME)
+        .any(|entry| entry.data().as_utf8().map(|cn| &*cn == issuer).unwrap_or(false)))
+      .unwrap_or(false)
+  }).cloned()
+}
 
-  order_command(channel, ProxyRequestData::AddHttpFront(HttpFront {
-    address: frontend.clone(),
-    app_id: String::from(app_id),
-    hostname: String::from(hostname),
-    path_begin: String::from(path_begin)
-  })) && order_command(channel, ProxyRequestData::AddBackend(Backend {
-    app_id: String::from(app_id),
-    backend_id: format!("{}-0", app_id),
-    address: server_address,
-    load_balancing_parameters: None,
-    sticky_id: None,
-    backup: None,
-  }))
+/// `chain.pem` gives `chain.alt-1.pem`
+fn alternate_chain_path(chain: &str, index: usize) -> String {
+  format!("{}.alt-{}.pem", chain.trim_end_matches(".pem"), index)
 }
 
-fn remove_proxying(channel: &mut Channel<CommandRequest,CommandResponse>, frontend: &SocketAddr, app_id: &str, hostname: &str, path_begin: &str,
-  server_address: SocketAddr) -> bool {
-  order_command(channel, ProxyRequestData::RemoveHttpFront(HttpFront {
-    address: frontend.clone(),
-    app_id: String::from(app_id),
-    hostname: String::from(hostname),
-    path_begin: String::from(path_begin)
-  })) && order_command(channel, ProxyRequestData::RemoveBackend(RemoveBackend {
-    app_id: String::from(app_id),
-    backend_id: format!("{}-0", app_id),
-    address: server_address,
-  }))
+/// options of a certificate request, for the main command and renew
+fn certificate_args() -> Vec<Arg<'static, 'static>> {
+  let mut args = vec![
+    Arg::with_name("config")
+      .short("c")
+      .long("config")
+      .value_name("FILE")
+      .help("Sets a custom config file (can be repeated to configure several sozu instances)")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+      .required_unless("socket"),
+    Arg::with_name("socket")
+      .long("socket")
+      .value_name("FILE")
+      .help("command socket of a sozu instance, instead of reading it from --config (can be repeated)")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1),
+    Arg::with_name("ssh")
+      .long("ssh")
+      .value_name("[user@]host")
+      .help("sozu runs on this host: its command sockets (from --socket or the local copy of its --config) are forwarded over SSH")
+      .takes_value(true),
+    Arg::with_name("domain")
+      .long("domain")
+      .value_name("domain name")
+      .help("application's domain name (can be repeated, the first one is the certificate's common name)")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+      .required_unless_one(&["discover", "domains-file"]),
+    Arg::with_name("alias")
+      .long("alias")
+      .value_name("hostname")
+      .help("other hostname of the application, added to the certificate after the domains and given its own HTTPS front (can be repeated)")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1),
+    Arg::with_name("domains-file")
+      .long("domains-file")
+      .value_name("FILE")
+      .help("file of hostnames, one certificate per line with its names separated by spaces, # starts a comment. The names are added to --domain")
+      .takes_value(true),
+    Arg::with_name("cert-per-line")
+      .long("cert-per-line")
+      .help("requests a certificate per line of --domains-file instead of one for all the names. {domain} in the file paths is replaced with the first name of the line")
+      .requires("domains-file")
+      .conflicts_with_all(&["discover", "domain", "alias"]),
+    Arg::with_name("discover")
+      .long("discover")
+      .help("also request the certificate for every hostname of the application's fronts in sozu"),
+    Arg::with_name("email")
+      .long("email")
+      .value_name("registration email")
+      .help("registration email")
+      .takes_value(true)
+      .required(true),
+    Arg::with_name("id")
+      .long("id")
+      .value_name("Application id")
+      .help("application identifier")
+      .takes_value(true)
+      .required_unless("https-app-id"),
+    Arg::with_name("http-app-id")
+      .long("http-app-id")
+      .value_name("Application id")
+      .help("dedicated application for the HTTP challenge route, instead of a temporary one. It receives the challenge backend")
+      .takes_value(true),
+    Arg::with_name("https-app-id")
+      .long("https-app-id")
+      .value_name("Application id")
+      .help("application of the HTTPS fronts, when it is not the one of --id")
+      .takes_value(true),
+    Arg::with_name("old-cert")
+      .long("old-certificate")
+      .value_name("previous certificate path")
+      .help("path to the previous certificate")
+      .takes_value(true),
+    Arg::with_name("cert")
+      .long("certificate")
+      .value_name("certificate path")
+      .help("certificate path")
+      .takes_value(true)
+      .required_unless("output-dir"),
+    Arg::with_name("chain")
+      .long("chain")
+      .value_name("certificate chain path")
+      .help("certificate chain path")
+      .takes_value(true)
+      .required_unless("output-dir"),
+    Arg::with_name("key")
+      .long("key")
+      .value_name("key path")
+      .help("key path")
+      .takes_value(true)
+      .required_unless("output-dir"),
+    Arg::with_name("metrics-file")
+      .long("metrics-file")
+      .value_name("FILE")
+      .help("write the result of the run and the expiration of the managed certificates to this node_exporter textfile")
+      .takes_value(true),
+    Arg::with_name("chat-webhook-url")
+      .long("chat-webhook-url")
+      .value_name("URL")
+      .help("post the issued certificates and the failed runs to this Slack or Mattermost incoming webhook")
+      .takes_value(true),
+    Arg::with_name("chat-mention")
+      .long("chat-mention")
+      .value_name("domain=mention")
+      .help("mention on the failures for the domain, like *.example.com=@oncall or *=<!here> (can be repeated)")
+      .requires("chat-webhook-url")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1),
+    Arg::with_name("webhook-url")
+      .long("webhook-url")
+      .value_name("URL")
+      .help("POST a JSON event to this URL when a certificate is issued, a run fails, or a certificate is about to expire")
+      .takes_value(true),
+    Arg::with_name("webhook-secret-file")
+      .long("webhook-secret-file")
+      .value_name("FILE")
+      .help("sign the events with HMAC-SHA256, keyed with the first line of this file, in the X-Sozu-Acme-Signature header")
+      .requires("webhook-url")
+      .takes_value(true),
+    Arg::with_name("webhook-retries")
+      .long("webhook-retries")
+      .value_name("count")
+      .help("attempts after a failed notification, with an exponential backoff")
+      .takes_value(true)
+      .default_value("3"),
+    Arg::with_name("webhook-expiry-days")
+      .long("webhook-expiry-days")
+      .value_name("days")
+      .help("notify at each run while the certificate expires in less than this number of days")
+      .takes_value(true)
+      .default_value("14"),
+    Arg::with_name("spki-pin")
+      .long("spki-pin")
+      .help("print the base64 SHA-256 hash of the new certificate's public key (SPKI), as pin-sha256=\"...\""),
+    Arg::with_name("spki-pin-file")
+      .long("spki-pin-file")
+      .value_name("path")
+      .help("also write the SPKI hash to this file, the placeholders of the certificate paths are replaced")
+      .takes_value(true),
+    Arg::with_name("emit-tlsa")
+      .long("emit-tlsa")
+      .help("print the TLSA records (3 1 1) of the new certificate, for each domain and HTTPS listener port"),
+    Arg::with_name("tlsa-port")
+      .long("tlsa-port")
+      .value_name("port")
+      .help("port of the TLSA records, when the listeners are not reached on their own port")
+      .requires("emit-tlsa")
+      .takes_value(true),
+    Arg::with_name("fullchain")
+      .long("fullchain")
+      .value_name("fullchain path")
+      .help("also write the certificate followed by its chain to this path, fullchain.pem in the output directory by default")
+      .takes_value(true),
+    Arg::with_name("key-mode")
+      .long("key-mode")
+      .value_name("mode")
+      .help("octal permissions of the key file")
+      .takes_value(true)
+      .default_value("600"),
+    Arg::with_name("owner")
+      .long("owner")
+      .value_name("user")
+      .help("user owning the written files, like the user sozu runs as")
+      .takes_value(true),
+    Arg::with_name("group")
+      .long("group")
+      .value_name("group")
+      .help("group owning the written files")
+      .takes_value(true),
+    Arg::with_name("backup-dir")
+      .long("backup-dir")
+      .value_name("DIR")
+      .help("copy the replaced certificate, chain and key to DIR/<first domain>/<date> before writing the new ones")
+      .takes_value(true),
+    Arg::with_name("backups")
+      .long("backups")
+      .value_name("count")
+      .help("number of backups kept for each certificate")
+      .requires("backup-dir")
+      .takes_value(true)
+      .default_value("5"),
+    Arg::with_name("vault-addr")
+      .long("vault-addr")
+      .value_name("URL")
+      .help("also store the certificate, chain and key in this Vault server's KV engine")
+      .takes_value(true),
+    Arg::with_name("vault-mount")
+      .long("vault-mount")
+      .value_name("path")
+      .help("mount path of the KV engine")
+      .takes_value(true)
+      .default_value("secret"),
+    Arg::with_name("vault-path")
+      .long("vault-path")
+      .value_name("path")
+      .help("path of the secret in the KV engine, {domain} is replaced with the first domain")
+      .takes_value(true)
+      .default_value("sozu-acme/{domain}"),
+    Arg::with_name("vault-kv-version")
+      .long("vault-kv-version")
+      .value_name("version")
+      .help("version of the KV engine")
+      .takes_value(true)
+      .possible_values(&["1", "2"])
+      .default_value("2"),
+    Arg::with_name("vault-token-file")
+      .long("vault-token-file")
+      .value_name("FILE")
+      .help("file holding the Vault token, VAULT_TOKEN by default")
+      .takes_value(true),
+    Arg::with_name("vault-role-id")
+      .long("vault-role-id")
+      .value_name("role id")
+      .help("logs in to Vault with this AppRole instead of a token")
+      .takes_value(true)
+      .requires("vault-secret-id-file"),
+    Arg::with_name("vault-secret-id-file")
+      .long("vault-secret-id-file")
+      .value_name("FILE")
+      .help("file holding the secret id of the AppRole")
+      .takes_value(true)
+      .requires("vault-role-id"),
+    Arg::with_name("kube-secret")
+      .long("kube-secret")
+      .value_name("name")
+      .help("also store the certificate in this kubernetes.io/tls Secret, {domain} is replaced with the first domain")
+      .takes_value(true),
+    Arg::with_name("kube-namespace")
+      .long("kube-namespace")
+      .value_name("namespace")
+      .help("namespace of the Secret")
+      .takes_value(true)
+      .default_value("default"),
+    Arg::with_name("kube-context")
+      .long("kube-context")
+      .value_name("context")
+      .help("kubeconfig context of the cluster, the current one by default")
+      .takes_value(true),
+    Arg::with_name("kubectl")
+      .long("kubectl")
+      .value_name("path")
+      .help("kubectl executable, it applies the Secret with its own configuration")
+      .takes_value(true)
+      .default_value("kubectl"),
+    Arg::with_name("container-secret")
+      .long("container-secret")
+      .value_name("name")
+      .help("also store the certificate and key in Docker Swarm or Podman secrets named <name>.crt.<version> and <name>.key.<version>, {domain} is replaced with the first domain")
+      .takes_value(true),
+    Arg::with_name("container-engine")
+      .long("container-engine")
+      .value_name("engine")
+      .help("creates the secrets")
+      .takes_value(true)
+      .possible_values(&["docker", "podman"])
+      .default_value("docker"),
+    Arg::with_name("s3-bucket")
+      .long("s3-bucket")
+      .value_name("bucket")
+      .help("also upload the certificate, chain and key to this S3 compatible bucket")
+      .takes_value(true),
+    Arg::with_name("s3-endpoint")
+      .long("s3-endpoint")
+      .value_name("URL")
+      .help("endpoint of the S3 compatible service, AWS's endpoint for the region by default")
+      .takes_value(true),
+    Arg::with_name("s3-region")
+      .long("s3-region")
+      .value_name("region")
+      .help("region of the bucket")
+      .takes_value(true)
+      .default_value("us-east-1"),
+    Arg::with_name("s3-prefix")
+      .long("s3-prefix")
+      .value_name("prefix")
+      .help("prefix of the objects, {domain} is replaced with the first domain")
+      .takes_value(true)
+      .default_value("{domain}/"),
+    Arg::with_name("s3-access-key")
+      .long("s3-access-key")
+      .value_name("key id")
+      .help("access key id, AWS_ACCESS_KEY_ID by default")
+      .takes_value(true),
+    Arg::with_name("s3-secret-key-file")
+      .long("s3-secret-key-file")
+      .value_name("FILE")
+      .help("file holding the secret access key, AWS_SECRET_ACCESS_KEY by default")
+      .takes_value(true),
+    Arg::with_name("s3-sse")
+      .long("s3-sse")
+      .value_name("algorithm")
+      .help("server side encryption of the objects")
+      .takes_value(true)
+      .possible_values(&["AES256", "aws:kms"]),
+    Arg::with_name("s3-kms-key-id")
+      .long("s3-kms-key-id")
+      .value_name("key id")
+      .help("KMS key of the server side encryption")
+      .takes_value(true)
+      .requires("s3-sse"),
+    Arg::with_name("s3-passphrase-file")
+      .long("s3-passphrase-file")
+      .value_name("FILE")
+      .help("encrypt the objects before the upload with the passphrase of this file, like openssl enc -aes-256-cbc -pbkdf2")
+      .takes_value(true),
+    Arg::with_name("output-dir")
+      .long("output-dir")
+      .value_name("DIR")
+      .help("the certificate, chain and key paths default to cert.pem, chain.pem and key.pem in DIR/<first domain>")
+      .takes_value(true),
+    Arg::with_name("layout")
+      .long("layout")
+      .value_name("layout")
+      .help("certbot writes DIR/archive/<first domain>/certN.pem, etc. and links DIR/live/<first domain>/cert.pem, etc. to the last version")
+      .possible_values(&["flat", "certbot"])
+      .requires_if("certbot", "output-dir")
+      .takes_value(true)
+      .default_value("flat"),
+    Arg::with_name("http")
+      .long("http")
+      .value_name("HTTP frontend address")
+      .help("format: IP:port")
+      .takes_value(true)
+      .required(true),
+    Arg::with_name("https")
+      .long("https")
+      .value_name("HTTPS frontend address")
+      .help("format: IP:port")
+      .takes_value(true)
+      .required_unless("https-listener"),
+    Arg::with_name("https-listener")
+      .long("https-listener")
+      .value_name("HTTPS listener address")
+      .help("HTTPS listener receiving the certificate and fronts, instead of --https (can be repeated, format: IP:port)")
+      .takes_value(true)
+      .multiple(true)
+      .number_of_values(1)
+      .conflicts_with("https"),
+    Arg::with_name("save-state")
+      .long("save-state")
+      .value_name("FILE")
+      .help("have sozu save its state to this file once the certificate is installed")
+      .takes_value(true),
+    Arg::with_name("renew-days")
+      .long("renew-days")
+      .value_name("days")
+      .help("only renew the current certificate when it expires in less than this number of days")
+      .takes_value(true)
+      .default_value("30"),
+    Arg::with_name("schedule")
+      .long("schedule")
+      .value_name("cron expression")
+      .help("when the daemon checks this certificate, like \"0 3 * * *\" (minute, hour, day of month, month, day of week)")
+      .takes_value(true)
+      .validator(|expression| schedule::Schedule::parse(&expression).map(|_| ())),
+    Arg::with_name("force-renewal")
+      .long("force-renewal")
+      .help("renew the current certificate even if it is not due, after a key compromise or to change its chain"),
+    Arg::with_name("create-app")
+      .long("create-app")
+      .help("create the application in sozu with default settings if it does not exist"),
+    Arg::with_name("cert-only")
+      .long("cert-only")
+      .help("only install the certificate, without creating HTTPS fronts")
+      .conflicts_with("https-path-begin"),
+    Arg::with_name("https-path-begin")
+      .long("https-path-begin")
+      .value_name("path prefix")
+      .help("path prefix of the HTTPS fronts, to attach the certificate to an existing path-scoped route")
+      .takes_value(true),
+    Arg::with_name("challenge")
+      .long("challenge")
+      .value_name("challenge type")
+      .help("ACME challenge used to validate the domain (wildcard domains always use dns-01)")
+      .takes_value(true)
+      .possible_values(&["http-01", "dns-01"])
+      .default_value("http-01"),
+    Arg::with_name("directory-url")
+      .long("directory-url")
+      .value_name("ACME directory URL")
+      .help("directory of the ACME certificate authority (default: Let's Encrypt)")
+      .takes_value(true),
+    Arg::with_name("staging")
+      .long("staging")
+      .help("use the Let's Encrypt staging environment, for testing")
+      .conflicts_with("directory-url"),
+    Arg::with_name("preferred-chain")
+      .long("preferred-chain")
+      .value_name("issuer common name")
+      .help("if the CA offers several chains, use the one whose topmost certificate is issued by this name")
+      .takes_value(true),
+    Arg::with_name("key-type")
+      .long("key-type")
+      .value_name("key type")
+      .help("type of the certificate's private key")
+      .takes_value(true)
+      .possible_values(&["ecdsa", "rsa"])
+      .default_value("ecdsa"),
+    Arg::with_name("curve")
+      .long("curve")
+      .value_name("elliptic curve")
+      .help("curve of the ECDSA private key")
+      .takes_value(true)
+      .possible_values(&["p256", "p384"])
+      .default_value("p384"),
+    Arg::with_name("key-format")
+      .long("key-format")
+      .value_name("format")
+      .help("encoding of the written private key: pkcs8, pkcs1 (RSA keys) or sec1 (ECDSA keys)")
+      .takes_value(true)
+      .possible_values(&["pkcs8", "pkcs1", "sec1"])
+      .default_value("pkcs8"),
+    Arg::with_name("key-passphrase-file")
+      .long("key-passphrase-file")
+      .value_name("FILE")
+      .help("write the private key as encrypted PKCS#8, with the passphrase of this file. sozu gets it decrypted")
+      .takes_value(true),
+    Arg::with_name("reuse-key")
+      .long("reuse-key")
+      .help("sign the new certificate with the private key already stored at the key path, if there is one"),
+    Arg::with_name("csr")
+      .long("csr")
+      .value_name("CSR path")
+      .help("submit this certificate signing request instead of generating a key. The matching private key must already be at the key path for sozu")
+      .takes_value(true)
+      .conflicts_with_all(&["reuse-key", "must-staple"]),
+    Arg::with_name("must-staple")
+      .long("must-staple")
+      .help("request the OCSP Must-Staple extension in the certificate"),
+    Arg::with_name("rate-limit-wait")
+      .long("rate-limit-wait")
+      .value_name("seconds")
+      .help("when the CA rate limits a request, wait and retry if it asks to wait less than this")
+      .takes_value(true)
+      .default_value("120"),
+    Arg::with_name("validation-timeout")
+      .long("validation-timeout")
+      .value_name("seconds")
+      .help("how long to wait for the CA to validate each challenge, and to issue the certificate")
+      .takes_value(true)
+      .default_value("120"),
+    Arg::with_name("sozu-timeout")
+      .long("sozu-timeout")
+      .value_name("seconds")
+      .help("how long sozu has to answer each order")
+      .takes_value(true)
+      .default_value("10"),
+    Arg::with_name("sozu-deadline")
+      .long("sozu-deadline")
+      .value_name("seconds")
+      .help("how long an order to sozu can take, reconnections included")
+      .takes_value(true)
+      .default_value("60"),
+    Arg::with_name("profile")
+      .long("profile")
+      .value_name("certificate profile")
+      .help("certificate profile offered by the CA (ex: shortlived). Renewals keep the profile of the previous order")
+      .takes_value(true),
+    Arg::with_name("agree-tos")
+      .long("agree-tos")
+      .help("agree to the CA's terms of service when registering a new account"),
+    Arg::with_name("eab-kid")
+      .long("eab-kid")
+      .value_name("key identifier")
+      .help("key identifier of the external account binding, for the CAs that require one to register an account")
+      .takes_value(true)
+      .requires("eab-hmac-key-file"),
+    Arg::with_name("eab-hmac-key-file")
+      .long("eab-hmac-key-file")
+      .value_name("file")
+      .help("file containing the base64url MAC key of the external account binding")
+      .takes_value(true)
+      .requires("eab-kid"),
+    Arg::with_name("skip-caa-check")
+      .long("skip-caa-check")
+      .help("do not check the domains' CAA records before ordering"),
+    Arg::with_name("dry-run")
+      .long("dry-run")
+      .help("order from the staging CA (or --directory-url) and print what would be done, without writing files or changing sozu's configuration"),
+    Arg::with_name("dns-hook")
+      .long("dns-hook")
+      .value_name("DNS hook command")
+      .help("command called as `<hook> add|remove <record name> <record value>` to publish the dns-01 TXT record. Without it, the record is displayed and the tool waits for confirmation")
+      .takes_value(true),
+  ];
+  args.extend(account_key_args());
+  args
 }
 
-fn add_certificate(channel: &mut Channel<CommandRequest,CommandResponse>,
-  frontend: &SocketAddr, hostname: &str,
-  certificate_path: &str, chain_path: &str, key_path: &str,
-  old_fingerprint: Option<Vec<u8>>) -> bool {
+/// the certificate request options, which come from the state file with --managed
+fn renew_args() -> Vec<Arg<'static, 'static>> {
+  let mut args: Vec<Arg<'static, 'static>> = certificate_args().into_iter()
+    .map(|arg| if arg.is_set(ArgSettings::Required) { arg.required_unless("managed") } else { arg })
+    .collect();
+  args.push(Arg::with_name("managed")
+    .long("managed")
+    .value_name("domain name")
+    .help("renew the certificate of the state file covering this domain, with the options it was installed with")
+    .takes_value(true));
+  args
+}
 
-  let certificate = match Config::load_file(certificate_path) {
+/// runs renew with the options recorded for the certificate, returns its exit status
+fn renew_managed(domain: &str) -> i32 {
+  let domain = match domain::to_ascii(domain) {
+    Ok(domain) => domain,
     Err(e) => {
-      error!("could not load certificate: {:?}", e);
-      return false;
-    },
-    Ok(c) => c,
+      error!("{}", e);
+      return exit::FAILURE;
+    }
   };
-  let key = match Config::load_file(key_path) {
+  let state = State::load();
+  let (_, managed) = match state.managed(&domain) {
+    Some(managed) => managed,
+    None => {
+      error!("no certificate for {} in {}", domain, state::STATE_FILE);
+      return exit::FAILURE;
+    }
+  };
+
+  let status = env::current_exe()
+    .and_then(|executable| process::Command::new(executable).args(daemon::renewal_arguments(managed, None)).status());
+  match status {
+    Ok(status) => status.code().unwrap_or(exit::FAILURE),
     Err(e) => {
-      error!("could not load key: {:?}", e);
-      return false;
+      error!("could not run sozu-acme: {}", e);
+      exit::FAILURE
+    }
+  }
+}
+
+/// shared by the subcommands
+fn lock_timeout_arg() -> Arg<'static, 'static> {
+  Arg::with_name("lock-timeout")
+    .long("lock-timeout")
+    .value_name("seconds")
+    .help("how long to wait for another run in the same directory to finish, instead of failing at once")
+    .takes_value(true)
+    .global(true)
+}
+
+/// takes the lock of the current directory, waiting up to --lock-timeout, or exits
+fn run_lock(matches: &ArgMatches) -> lock::RunLock {
+  let wait = matches.value_of("lock-timeout").map(|timeout| timeout.parse::<u64>().expect("invalid lock timeout")).unwrap_or(0);
+  lock::acquire(time::Duration::from_secs(wait)).unwrap_or_else(|e| {
+    error!("{}", e);
+    process::exit(exit::FAILURE);
+  })
+}
+
+/// how the account key is encrypted at rest, for the commands using it
+fn account_key_args() -> Vec<Arg<'static, 'static>> {
+  vec![
+    Arg::with_name("account-passphrase-file")
+      .long("account-passphrase-file")
+      .value_name("FILE")
+      .help("encrypt the ACME account key with the passphrase of this file")
+      .takes_value(true),
+    Arg::with_name("account-age-identity")
+      .long("account-age-identity")
+      .value_name("FILE")
+      .help("encrypt the ACME account key with the age command, to the recipients of this identity file")
+      .conflicts_with("account-passphrase-file")
+      .takes_value(true),
+  ]
+}
+
+/// the account keys are read and written with the encryption of the options
+fn set_account_encryption(matches: &ArgMatches) -> bool {
+  let encryption = match (matches.value_of("account-passphrase-file"), matches.value_of("account-age-identity")) {
+    (Some(path), _) => match files::read_passphrase(path) {
+      Ok(passphrase) => account::Encryption::Passphrase(passphrase),
+      Err(e) => {
+        error!("{}", e);
+        return false;
+      }
     },
-    Ok(k) => k,
+    (_, Some(identity)) => account::Encryption::Age(identity.to_string()),
+    (None, None) => account::Encryption::None,
   };
-  let certificate_chain = match Config::load_file(chain_path).map(split_certificate_chain) {
-    Err(e) => {
-      error!("could not load certificate chain: {:?}", e);
-      return false;
+  account::set_encryption(encryption);
+  true
+}
+
+/// options selecting the ACME account, for subcommands
+fn acme_args() -> Vec<Arg<'static, 'static>> {
+  let mut args = vec![
+    Arg::with_name("email")
+      .long("email")
+      .value_name("registration email")
+      .help("registration email of the account")
+      .takes_value(true)
+      .required(true),
+    Arg::with_name("directory-url")
+      .long("directory-url")
+      .value_name("ACME directory URL")
+      .help("directory of the ACME certificate authority (default: Let's Encrypt)")
+      .takes_value(true),
+    Arg::with_name("staging")
+      .long("staging")
+      .help("use the Let's Encrypt staging environment, for testing")
+      .conflicts_with("directory-url"),
+  ];
+  args.extend(account_key_args());
+  args
+}
+
+fn revoke_command(matches: &ArgMatches) -> i32 {
+  if !set_account_encryption(matches) {
+    return exit::FAILURE;
+  }
+  let email  = matches.value_of("email").expect("required registration email");
+  let reason = revoke::reason_code(matches.value_of("reason").expect("revocation reason has a default value"));
+  let https  = matches.value_of("https").map(|https| https.parse::<SocketAddr>().expect("invalid HTTPS frontend address format"));
+  let mut channel = match matches.value_of("config").map(|config| sozu::command_socket(config).and_then(|socket| sozu::try_connect(&[socket]))) {
+    Some(Ok(channel)) => Some(channel),
+    Some(Err(e)) => {
+      error!("{}", e);
+      return exit::SOZU;
     },
-    Ok(c) => c,
+    None => None,
   };
+  let mut directory = directory_url(matches).to_string();
 
-  match old_fingerprint {
-    None => return order_command(channel, ProxyRequestData::AddCertificate(AddCertificate {
-      front: frontend.clone(),
-      certificate: CertificateAndKey {
-        certificate,
-        certificate_chain,
-        key
+  let (certificate, names) = match (matches.value_of("cert"), matches.value_of("domain")) {
+    (Some(path), _) => match Config::load_file(path) {
+      Ok(certificate) => {
+        let names = revoke::certificate_names(&certificate);
+        (certificate, names)
       },
-      names: vec!(hostname.to_string()),
-    })),
-    Some(f) => return order_command(channel, ProxyRequestData::ReplaceCertificate(ReplaceCertificate {
-      front: frontend.clone(),
-      new_certificate: CertificateAndKey {
-        certificate,
-        certificate_chain,
-        key
+      Err(e) => {
+        error!("could not load certificate: {:?}", e);
+        return exit::FAILURE;
+      }
+    },
+    (None, Some(domain)) if channel.is_none() => {
+      let domain = match domain::to_ascii(domain) {
+        Ok(domain) => domain,
+        Err(e) => {
+          error!("{}", e);
+          return exit::FAILURE;
+        }
+      };
+      let state = State::load();
+      let managed = match state.managed(&domain) {
+        Some((_, managed)) => managed,
+        None => {
+          error!("no certificate for {} in {}", domain, state::STATE_FILE);
+          return exit::FAILURE;
+        }
+      };
+      if !matches.is_present("directory-url") && !matches.is_present("staging") && !managed.directory.is_empty() {
+        directory = managed.directory.clone();
+      }
+      match Config::load_file(&managed.certificate) {
+        Ok(certificate) => {
+          let names = revoke::certificate_names(&certificate);
+          (certificate, names)
+        },
+        Err(e) => {
+          error!("could not load certificate {}: {:?}", managed.certificate, e);
+          return exit::FAILURE;
+        }
+      }
+    },
+    (None, Some(domain)) => {
+      let channel = channel.as_mut().expect("checked above");
+      let https = https.as_ref().expect("the HTTPS frontend is required with --config");
+      match sozu::certificate_fingerprint(channel, https, domain)
+        .and_then(|fingerprint| sozu::certificate_by_fingerprint(channel, &fingerprint)) {
+        Some(certificate) => certificate,
+        None => {
+          error!("sozu has no certificate for {} on {}", domain, https);
+          return exit::FAILURE;
+        }
+      }
+    },
+    (None, None) => unreachable!(),
+  };
+
+  if !revoke::revoke(&directory, email, &certificate, reason) {
+    return exit::ACME;
+  }
+
+  match (channel.as_mut(), https) {
+    (Some(channel), Some(https)) =>
+      if revoke::remove_from_sozu(channel, &https, matches.value_of("id"), &recorded_path_begin(&names), &certificate, &names) {
+        0
+      } else {
+        exit::SOZU
       },
-      old_fingerprint: CertFingerprint(f),
-      old_names: vec!(hostname.to_string()),
-      new_names: vec!(hostname.to_string()),
-    })),
+    _ => 0,
   }
 }
 
-fn order_command(channel: &mut Channel<CommandRequest,CommandResponse>, order: ProxyRequestData) -> bool {
-  let id = generate_id();
-  channel.write_message(&CommandRequest::new(
-    id.clone(),
-    CommandRequestData::Proxy(order.clone()),
-    None,
-  ));
+/// path the HTTPS fronts of the names were added with, recorded when the
+/// certificate was installed, empty for the certificates installed before
+fn recorded_path_begin(names: &[String]) -> String {
+  let state = State::load();
+  names.iter().filter_map(|name| state.managed(name)).next()
+    .map(|(_, managed)| managed.path_begin.clone())
+    .unwrap_or_default()
+}
 
-  loop {
-    match channel.read_message() {
-      None          => error!("the proxy didn't answer"),
-      Some(message) => {
-        if id != message.id {
-          panic!("received message with invalid id: {:?}", message);
+/// removes the certificate sozu uses for the domain, optionally revoking it
+/// and deleting the local files
+fn delete_command(matches: &ArgMatches) -> i32 {
+  let domain = match domain::to_ascii(matches.value_of("domain").expect("required domain")) {
+    Ok(domain) => domain,
+    Err(e) => {
+      error!("{}", e);
+      return exit::FAILURE;
+    }
+  };
+  let https  = matches.value_of("https").expect("required HTTPS frontend address").parse::<SocketAddr>().expect("invalid HTTPS frontend address format");
+  let config = matches.value_of("config").expect("required config file");
+  let mut channel = match sozu::command_socket(config).and_then(|socket| sozu::try_connect(&[socket])) {
+    Ok(channel) => channel,
+    Err(e) => {
+      error!("{}", e);
+      return exit::SOZU;
+    }
+  };
+
+  let (certificate, names) = match sozu::certificate_fingerprint(&mut channel, &https, &domain)
+    .and_then(|fingerprint| sozu::certificate_by_fingerprint(&mut channel, &fingerprint)) {
+    Some(certificate) => certificate,
+    None => {
+      error!("sozu has no certificate for {} on {}", domain, https);
+      return exit::FAILURE;
+    }
+  };
+
+  if matches.is_present("revoke") {
+    if !set_account_encryption(matches) {
+      return exit::FAILURE;
+    }
+    let email = matches.value_of("email").expect("required registration email");
+    if !revoke::revoke(directory_url(matches), email, &certificate, revoke::reason_code("cessationOfOperation")) {
+      return exit::ACME;
+    }
+  }
+
+  if !revoke::remove_from_sozu(&mut channel, &https, matches.value_of("id"), &recorded_path_begin(&names), &certificate, &names) {
+    return exit::SOZU;
+  }
+  info!("removed the certificate for {} from sozu", names.join(", "));
+
+  let mut code = 0;
+  for path in ["cert", "chain", "key"].iter().filter_map(|file| matches.value_of(file)) {
+    match fs::remove_file(path) {
+      Ok(()) => info!("deleted {}", path),
+      Err(e) => {
+        error!("could not delete {}: {}", path, e);
+        code = exit::FAILURE;
+      }
+    }
+  }
+
+  code
+}
+
+/// prints the frontend, domain, fingerprint and expiration date of sozu's certificates
+fn list_command(matches: &ArgMatches) -> i32 {
+  let config = match matches.value_of("config") {
+    Some(config) => config,
+    None => return if list_managed(matches.is_present("json")) { 0 } else { exit::FAILURE },
+  };
+  let mut channel = match sozu::command_socket(config).and_then(|socket| sozu::try_connect(&[socket])) {
+    Ok(channel) => channel,
+    Err(e) => {
+      error!("{}", e);
+      return exit::SOZU;
+    }
+  };
+
+  let certificates = match sozu::certificates(&mut channel) {
+    Some(certificates) => certificates,
+    None => {
+      error!("could not query sozu's certificates");
+      return exit::SOZU;
+    }
+  };
+
+  let mut entries = Vec::new();
+  for (front, domain, fingerprint) in certificates {
+    let cert = sozu::certificate_by_fingerprint(&mut channel, &fingerprint)
+      .and_then(|(pem, _)| X509::from_pem(pem.as_bytes()).ok());
+    let not_after = cert.as_ref().map(|cert| cert.not_after().to_string())
+      .unwrap_or_else(|| "unknown".to_string());
+    let issuer = cert.as_ref()
+      .and_then(|cert| cert.issuer_name().entries_by_nid(Nid::COMMONNAME).next()
+        .and_then(|entry| entry.data().as_utf8().ok()).map(|cn| cn.to_string()))
+      .unwrap_or_else(|| "unknown".to_string());
+    let fingerprint: Vec<String> = fingerprint.iter().map(|b| format!("{:02x}", b)).collect();
+
+    if matches.is_present("json") {
+      entries.push(json!({
+        "frontend": front.to_string(),
+        "hostname": domain,
+        "fingerprint": fingerprint.join(""),
+        "issuer": issuer,
+        "not_after": not_after,
+      }));
+    } else {
+      println!("{}\t{}\t{}\t{}\t{}", front, domain, fingerprint.join(""), issuer, not_after);
+    }
+  }
+
+  if matches.is_present("json") {
+    println!("{}", serde_json::Value::Array(entries));
+  }
+
+  0
+}
+
+/// prints the orders a certificate request would send to sozu, computed
+/// from the current fronts and certificates: `+` adds, `~` replaces, `+/-` is
+/// added for the validation then removed. Neither sozu nor the CA are
+/// changed, so the authorizations the CA already has are not known
+fn plan_command(matches: &ArgMatches) -> i32 {
+  let app_id      = matches.value_of("https-app-id").or_else(|| matches.value_of("id")).expect("required application id");
+  let http_app_id = matches.value_of("http-app-id");
+  let http        = matches.value_of("http").expect("required HTTP frontend address").parse::<SocketAddr>()
+    .unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("invalid HTTP frontend address: {}", e)));
+  let https_listeners: Vec<SocketAddr> = matches.values_of("https-listener").or_else(|| matches.values_of("https"))
+    .expect("required HTTPS frontend address")
+    .map(|https| https.parse::<SocketAddr>().unwrap_or_else(|e| exit::fail(exit::FAILURE, &format!("invalid HTTPS frontend address: {}", e))))
+    .collect();
+  let https_path_begin = matches.value_of("https-path-begin").unwrap_or("");
+  let challenge_type = matches.value_of("challenge").expect("challenge type has a default value");
+  let renew_days  = value_t!(matches, "renew-days", u32).unwrap_or_else(|e| e.exit());
+
+  let lines: Vec<Option<Vec<String>>> = if matches.is_present("cert-per-line") {
+    let path = matches.value_of("domains-file").expect("required domains file");
+    domain::read_list(path).unwrap_or_else(|e| exit::fail(exit::FAILURE, &e)).into_iter().map(Some).collect()
+  } else {
+    vec![None]
+  };
+
+  let (_tunnels, mut channel) = connect_sozu(matches);
+  let mut changes: Vec<(&str, String)> = Vec::new();
+  if matches.is_present("create-app") && !sozu::application_exists(&mut channel, app_id) {
+    changes.push(("+", format!("AddApplication {}", app_id)));
+  }
+
+  for line in lines.iter() {
+    let line = line.as_ref().map(|names| &names[..]);
+    let mut ascii_domains = requested_domains(matches, line);
+    if matches.is_present("discover") {
+      for hostname in sozu::application_hostnames(&mut channel, app_id) {
+        if !ascii_domains.contains(&hostname) {
+          ascii_domains.push(hostname);
         }
-        match message.status {
-          CommandStatus::Processing => {
-            // do nothing here
-            // for other messages, we would loop over read_message
-            // until an error or ok message was sent
-          },
-          CommandStatus::Error => {
-            error!("could not execute order: {}", message.message);
-            return false;
-          },
-          CommandStatus::Ok => {
-            match order {
-              ProxyRequestData::AddBackend(_) => info!("backend added : {}", message.message),
-              ProxyRequestData::RemoveBackend(_) => info!("backend removed : {} ", message.message),
-              ProxyRequestData::AddCertificate(_) => info!("certificate added: {}", message.message),
-              ProxyRequestData::RemoveCertificate(_) => info!("certificate removed: {}", message.message),
-              ProxyRequestData::AddHttpFront(_) => info!("front added: {}", message.message),
-              ProxyRequestData::RemoveHttpFront(_) => info!("front removed: {}", message.message),
-              _ => {
-                // do nothing for now
-              }
+      }
+    }
+    if ascii_domains.is_empty() {
+      exit::fail(exit::FAILURE, &format!("no hostname found for the application {}", app_id));
+    }
+    let domains: Vec<&str> = ascii_domains.iter().map(|domain| domain.as_str()).collect();
+
+    let output_dir = output_dir(matches, domains[0]);
+    let path = |option: &str, file: Option<&str>| certificate_path(matches, domains[0], output_dir.as_ref(), option, file);
+    let old_certificate = path("old-cert", None).and_then(|path| Config::load_file_bytes(&path).ok());
+    let old_fingerprint = old_certificate.as_ref().and_then(|file| calculate_fingerprint(file));
+    let managed = State::load().managed(domains[0]).map(|(_, managed)| managed.certificate.clone());
+    let current_certificate = old_certificate.or_else(|| path("cert", Some("cert.pem"))
+      .and_then(|path| current_path(&path, managed.as_ref()))
+      .and_then(|path| Config::load_file_bytes(&path).ok()));
+
+    // the renewal window of the CA (ARI) is not asked for, only the expiration
+    if let Some(current_certificate) = current_certificate.as_ref().filter(|_| !matches.is_present("force-renewal")) {
+      let names = revoke::certificate_names(&String::from_utf8_lossy(current_certificate));
+      let covered = domains.iter().all(|domain| names.iter().any(|name| name == domain));
+      if covered && !renewal::expires_within(current_certificate, renew_days) {
+        println!("the certificate for {} is not due for renewal", domains.join(", "));
+        continue;
+      }
+    }
+
+    let challenge_app = http_app_id.unwrap_or("<temporary application>");
+    let mut http_challenge = false;
+    for domain in domains.iter() {
+      let challenge_type = if domain.starts_with("*.") { "dns-01" } else { challenge_type };
+      match challenge_type {
+        "dns-01" => {},
+        _ => {
+          http_challenge = true;
+          changes.push(("+/-", format!("AddHttpFront {}/.well-known/acme-challenge/<token> -> {} on {}", domain, challenge_app, http)));
+        },
+      }
+    }
+    if http_challenge {
+      changes.push(("+/-", format!("AddBackend {} -> the challenge server", challenge_app)));
+    }
+
+    for listener in https_listeners.iter() {
+      match old_fingerprint.clone().or_else(|| sozu::certificate_fingerprint(&mut channel, listener, domains[0])) {
+        Some(fingerprint) => {
+          changes.push(("~", format!("ReplaceCertificate {} on {} with a certificate for {}",
+            fingerprint.iter().map(|b| format!("{:02x}", b)).collect::<String>(), listener, domains.join(", "))));
+          // the previous certificate is removed unless it serves other names
+          if let Some((_, names)) = sozu::certificate_by_fingerprint(&mut channel, &fingerprint) {
+            let uncovered: Vec<&str> = names.iter().map(|name| name.as_str()).filter(|name| !domains.contains(name)).collect();
+            if !uncovered.is_empty() {
+              println!("the previous certificate on {} is kept, it is still used for {}", listener, uncovered.join(", "));
             }
-            return true;
           }
-        }
+        },
+        None => changes.push(("+", format!("AddCertificate for {} on {}", domains.join(", "), listener))),
+      }
+
+      let missing = if matches.is_present("cert-only") { Vec::new() } else { sozu::missing_https_fronts(&mut channel, app_id, listener, &domains, https_path_begin) };
+      for hostname in missing.iter() {
+        changes.push(("+", format!("AddHttpsFront {}{} -> {} on {}", hostname, https_path_begin, app_id, listener)));
       }
     }
   }
+
+  if changes.is_empty() {
+    println!("no changes");
+    return 0;
+  }
+  for &(kind, ref change) in changes.iter() {
+    println!("  {:<3} {}", kind, change);
+  }
+  let count = |kind: &str| changes.iter().filter(|&&(k, _)| k == kind).count();
+  println!("\nplan: {} to add, {} to replace, {} to add during the validation", count("+"), count("~"), count("+/-"));
+  0
+}
+
+/// checks what a certificate request would use: the sozu configurations and
+/// sockets, the domains, the email and the file paths. Prints a line per
+/// check, nothing is sent to the CA
+fn check_command(matches: &ArgMatches) -> bool {
+  let mut checks: Vec<(String, Result<(), String>)> = Vec::new();
+
+  let mut sockets: Vec<String> = matches.values_of("socket").into_iter().flatten().map(|socket| socket.to_string()).collect();
+  for config in matches.values_of("config").into_iter().flatten() {
+    match Config::load_from_path(config) {
+      Ok(config) => sockets.push(config.command_socket),
+      Err(e) => checks.push((format!("sozu configuration {}", config), Err(e.to_string()))),
+    }
+  }
+  // with --ssh, the sockets are on the remote host
+  if !matches.is_present("ssh") {
+    for socket in sockets {
+      let result = if Path::new(&socket).exists() {
+        sozu::try_connect(&[socket.clone()]).map(|_| ())
+      } else {
+        Err("it does not exist".to_string())
+      };
+      checks.push((format!("sozu command socket {}", socket), result));
+    }
+  }
+
+  let mut domains = Vec::new();
+  for name in matches.values_of("domain").into_iter().flatten().chain(matches.values_of("alias").into_iter().flatten()) {
+    match domain::to_ascii(name) {
+      Ok(name) => domains.push(name),
+      Err(e) => checks.push((format!("domain {}", name), Err(e))),
+    }
+  }
+  if let Some(path) = matches.value_of("domains-file") {
+    match domain::read_list(path) {
+      Ok(lines) => domains.extend(lines.into_iter().flatten()),
+      Err(e) => checks.push((format!("domains file {}", path), Err(e))),
+    }
+  }
+  if !domains.is_empty() {
+    checks.push((format!("domains {}", domains.join(", ")), Ok(())));
+  }
+
+  let email = matches.value_of("email").expect("required registration email");
+  let valid_email = match email.rfind('@') {
+    Some(at) if at > 0 && !email.contains(char::is_whitespace) => {
+      let host = &email[at + 1..];
+      host.contains('.') && !domain::is_ip(host) && domain::to_ascii(host).is_ok()
+    },
+    _ => false,
+  };
+  checks.push((format!("email {}", email), if valid_email { Ok(()) } else { Err("it is not a valid address".to_string()) }));
+
+  for &(option, name) in [("http", "HTTP frontend"), ("https", "HTTPS frontend"), ("https-listener", "HTTPS listener")].iter() {
+    for address in matches.values_of(option).into_iter().flatten() {
+      checks.push((format!("{} {}", name, address), address.parse::<SocketAddr>().map(|_| ()).map_err(|e| e.to_string())));
+    }
+  }
+
+  // the paths may only be known once the domains are, or the certificate
+  // issued: the placeholders and the output directory are checked up to the
+  // part that does not depend on them
+  let mut paths: Vec<&str> = ["cert", "chain", "key", "fullchain"].iter().filter_map(|option| matches.value_of(option)).collect();
+  paths.extend(matches.value_of("output-dir"));
+  paths.extend(matches.value_of("backup-dir"));
+  paths.extend(matches.value_of("audit-log"));
+  for path in paths {
+    checks.push((format!("path {}", path), writable(path.split('{').next().unwrap_or(path))));
+  }
+  if let Some(path) = matches.value_of("csr") {
+    checks.push((format!("CSR {}", path), fs::metadata(path).map(|_| ()).map_err(|e| e.to_string())));
+  }
+  if let Some(path) = matches.value_of("key-passphrase-file") {
+    checks.push((format!("key passphrase file {}", path), files::read_passphrase(path).map(|_| ())));
+  }
+  if let Some(path) = matches.value_of("account-passphrase-file") {
+    checks.push((format!("account passphrase file {}", path), files::read_passphrase(path).map(|_| ())));
+  }
+  if let Some(path) = matches.value_of("vault-token-file") {
+    checks.push((format!("Vault token file {}", path), files::read_passphrase(path).map(|_| ())));
+  }
+  if let Some(path) = matches.value_of("vault-secret-id-file") {
+    checks.push((format!("Vault secret id file {}", path), files::read_passphrase(path).map(|_| ())));
+  }
+  if let Some(path) = matches.value_of("s3-secret-key-file") {
+    checks.push((format!("S3 secret key file {}", path), files::read_passphrase(path).map(|_| ())));
+  }
+  if let Some(path) = matches.value_of("eab-hmac-key-file") {
+    checks.push((format!("external account MAC key file {}", path), files::read_passphrase(path).map(|_| ())));
+  }
+  if let Some(path) = matches.value_of("webhook-secret-file") {
+    checks.push((format!("webhook secret file {}", path), files::read_passphrase(path).map(|_| ())));
+  }
+  if let Some(path) = matches.value_of("account-age-identity") {
+    checks.push((format!("account age identity {}", path), fs::metadata(path).map(|_| ()).map_err(|e| e.to_string())));
+  }
+
+  for &(ref name, ref result) in checks.iter() {
+    match *result {
+      Ok(()) => println!("ok\t{}", name),
+      Err(ref e) => println!("failed\t{}: {}", name, e),
+    }
+  }
+  checks.iter().all(|&(_, ref result)| result.is_ok())
+}
+
+/// whether the file can be written, or created in the closest existing directory
+fn writable(path: &str) -> Result<(), String> {
+  let mut path = Path::new(path);
+  while !path.exists() {
+    path = match path.parent() {
+      Some(parent) if !parent.as_os_str().is_empty() => parent,
+      _ => Path::new("."),
+    };
+  }
+
+  let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+  if unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } == 0 {
+    Ok(())
+  } else {
+    Err(format!("{} is not writable: {}", path.display(), io::Error::last_os_error()))
+  }
+}
+
+/// prints the expiration date of the certificates of the state file, and
+/// whether they are due for renewal
+fn status_command(renew_days: u32, json: bool) {
+  let state = State::load();
+
+  let mut entries = Vec::new();
+  for (domains, managed) in state.certificates.iter() {
+    let certificate = fs::read(&managed.certificate).ok();
+    let cert = certificate.as_ref().and_then(|pem| X509::from_pem(pem).ok());
+    let not_after = cert.as_ref().map(|cert| cert.not_after().to_string()).unwrap_or_else(|| "unknown".to_string());
+    let days_left = cert.as_ref()
+      .and_then(|cert| Asn1Time::days_from_now(0).ok().and_then(|now| now.diff(cert.not_after()).ok()))
+      .map(|diff| diff.days);
+    // a missing certificate is issued again
+    let due = certificate.map(|certificate| renewal::expires_within(&certificate, renew_days)).unwrap_or(true);
+
+    if json {
+      entries.push(json!({
+        "domains": domains.split(',').collect::<Vec<_>>(),
+        "certificate": managed.certificate,
+        "not_after": not_after,
+        "days_left": days_left,
+        "due": due,
+      }));
+    } else {
+      let days_left = days_left.map(|days| days.to_string()).unwrap_or_else(|| "-".to_string());
+      println!("{}\t{}\t{}\t{}", domains, not_after, days_left, if due { "due" } else { "ok" });
+    }
+  }
+
+  if json {
+    println!("{}", serde_json::Value::Array(entries));
+  }
+}
+
+/// prints the certificates of the state file, with their paths and dates
+fn list_managed(json: bool) -> bool {
+  let state = State::load();
+
+  let mut entries = Vec::new();
+  for (domains, managed) in state.certificates.iter() {
+    let not_after = fs::read(&managed.certificate).ok()
+      .and_then(|pem| X509::from_pem(&pem).ok())
+      .map(|cert| cert.not_after().to_string())
+      .unwrap_or_else(|| "unknown".to_string());
+    let renewed_at = managed.renewed_at.clone().unwrap_or_else(|| "-".to_string());
+
+    if json {
+      entries.push(json!({
+        "domains": domains.split(',').collect::<Vec<_>>(),
+        "certificate": managed.certificate,
+        "chain": managed.chain,
+        "key": managed.key,
+        "fingerprint": managed.fingerprint,
+        "email": managed.email,
+        "directory": managed.directory,
+        "order_url": managed.order_url,
+        "issued_at": managed.issued_at,
+        "renewed_at": managed.renewed_at,
+        "not_after": not_after,
+      }));
+    } else {
+      println!("{}\t{}\t{}\t{}\t{}\t{}", domains, managed.certificate, managed.fingerprint, managed.issued_at, renewed_at, not_after);
+    }
+  }
+
+  if json {
+    println!("{}", serde_json::Value::Array(entries));
+  }
+
+  true
+}
+
+/// ACME directory selected by the --directory-url and --staging options
+fn directory_url<'a>(matches: &'a ArgMatches) -> &'a str {
+  match matches.value_of("directory-url") {
+    Some(url) => url,
+    None if matches.is_present("staging") => acme::LETS_ENCRYPT_STAGING,
+    None => acme::LETS_ENCRYPT,
+  }
 }
@@ -0,0 +1,120 @@
+//! node_exporter textfile with the result of the last run of each certificate
+//! and the expiration of the managed certificates, so Prometheus can alert on
+//! failed or overdue renewals
+
+use std::{fs::{self, OpenOptions}, os::unix::io::AsRawFd, time::Duration, collections::BTreeMap};
+use chrono::Utc;
+use libc;
+use openssl::{asn1::Asn1Time, x509::X509};
+
+use exit;
+use files;
+use state::State;
+use timings;
+
+/// the metrics of the runs, by name, with their help text
+const RUN_METRICS: &[(&str, &str)] = &[
+  ("sozu_acme_last_run_success", "whether the last run for the certificate succeeded, a certificate not due counts as success"),
+  ("sozu_acme_last_run_exit_code", "exit status of the last run for the certificate"),
+  ("sozu_acme_last_run_timestamp_seconds", "end of the last run for the certificate"),
+  ("sozu_acme_last_run_duration_seconds", "duration of the last run for the certificate"),
+  (PHASE_METRIC, "time spent in each phase of the last run for the certificate"),
+];
+
+/// with a phase label, like the timings printed at the end of the run
+const PHASE_METRIC: &str = "sozu_acme_last_run_phase_duration_seconds";
+
+const EXPIRY_METRIC: &str = "sozu_acme_cert_expiry_timestamp";
+
+/// a certificate request of this invocation
+pub struct Run {
+  /// the first domain of the certificate
+  pub certificate: String,
+  pub code:        i32,
+  pub duration:    Duration,
+  /// account, order, challenges, validation, signing, installation, storage
+  pub phases:      Vec<(&'static str, Duration)>,
+}
+
+/// replaces the samples of these runs in the file, keeps those of the other
+/// certificates, and lists the expiration dates of the state file. The file
+/// is replaced atomically, like node_exporter expects
+pub fn write(path: &str, runs: &[Run]) -> Result<(), String> {
+  // the runs of a batch write the file in parallel
+  let lock_path = format!("{}.lock", path);
+  let lock = OpenOptions::new().write(true).create(true).open(&lock_path)
+    .map_err(|e| format!("could not open {}: {}", lock_path, e))?;
+  if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+    return Err(format!("could not lock {}", lock_path));
+  }
+
+  let mut samples: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+  let previous = fs::read_to_string(path).unwrap_or_default();
+  for line in previous.lines().filter(|line| !line.starts_with('#')) {
+    let metric = RUN_METRICS.iter().map(|&(metric, _)| metric)
+      .find(|metric| line.starts_with(&format!("{}{{", metric)));
+    if let Some(metric) = metric {
+      if !runs.iter().any(|run| about(line, &run.certificate)) {
+        samples.entry(metric).or_insert_with(Vec::new).push(line.to_string());
+      }
+    }
+  }
+
+  let now = Utc::now().timestamp();
+  for run in runs {
+    let label = format!("{{certificate=\"{}\"}}", escape(&run.certificate));
+    let success = run.code == 0 || run.code == exit::NOT_DUE;
+    let values = [
+      (if success { 1 } else { 0 }).to_string(),
+      run.code.to_string(),
+      now.to_string(),
+      format!("{:.3}", timings::seconds(run.duration)),
+    ];
+    for (&(metric, _), value) in RUN_METRICS.iter().zip(values.iter()) {
+      samples.entry(metric).or_insert_with(Vec::new).push(format!("{}{} {}", metric, label, value));
+    }
+    for &(phase, duration) in run.phases.iter() {
+      samples.entry(PHASE_METRIC).or_insert_with(Vec::new).push(format!("{}{{certificate=\"{}\",phase=\"{}\"}} {:.3}",
+        PHASE_METRIC, escape(&run.certificate), phase, timings::seconds(duration)));
+    }
+  }
+
+  let mut content = String::new();
+  for &(metric, help) in RUN_METRICS.iter() {
+    if let Some(lines) = samples.get(metric) {
+      content.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", metric, help, metric));
+      for line in lines {
+        content.push_str(line);
+        content.push('\n');
+      }
+    }
+  }
+  content.push_str(&format!("# HELP {} expiration of the certificate of the state file\n# TYPE {} gauge\n",
+    EXPIRY_METRIC, EXPIRY_METRIC));
+  for (domains, managed) in State::load().certificates.iter() {
+    if let Some(expiry) = expiry(&managed.certificate) {
+      let certificate = domains.split(',').next().unwrap_or(domains);
+      content.push_str(&format!("{}{{certificate=\"{}\"}} {}\n", EXPIRY_METRIC, escape(certificate), expiry));
+    }
+  }
+
+  files::write(path, content.as_bytes(), None, &files::Owner { uid: None, gid: None })
+    .map_err(|e| format!("could not write {}: {}", path, e))
+}
+
+/// the certificate's notAfter, in seconds since the epoch
+fn expiry(path: &str) -> Option<i64> {
+  let certificate = fs::read(path).ok().and_then(|pem| X509::from_pem(&pem).ok())?;
+  let diff = Asn1Time::from_unix(0).ok()?.diff(certificate.not_after()).ok()?;
+  Some(i64::from(diff.days) * 86400 + i64::from(diff.secs))
+}
+
+/// whether the sample is labelled with the certificate, with or without a phase
+fn about(line: &str, certificate: &str) -> bool {
+  let label = format!("{{certificate=\"{}\"", escape(certificate));
+  line.contains(&format!("{}}}", label)) || line.contains(&format!("{},", label))
+}
+
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,207 @@
+//! stand-in for sozu's command socket, to run the whole flow without a proxy
+
+use std::{
+  fs, process,
+  collections::{BTreeMap, HashMap},
+  net::SocketAddr,
+  os::unix::net::UnixListener,
+};
+use mio_uds::UnixStream;
+use serde_json;
+use sozu_command::channel::Channel;
+use sozu_command::{
+  certificate::calculate_fingerprint,
+  command::{CommandRequestData, CommandRequest, CommandResponse, CommandResponseData, CommandStatus, RunState, WorkerInfo},
+  proxy::{ProxyRequestData, Application, Backend, HttpFront, CertificateAndKey, Query, QueryAnswer,
+    QueryAnswerApplication, QueryAnswerCertificate, QueryApplicationType, QueryCertificateType},
+};
+
+use revoke;
+
+/// the mock has a single worker
+const WORKER_ID: u32 = 0;
+
+/// configuration built from the orders, enough to answer the queries of a run
+#[derive(Default)]
+struct MockState {
+  applications: BTreeMap<String, MockApplication>,
+  /// frontend, fingerprint, certificate (in PEM format) and names
+  certificates: Vec<(SocketAddr, Vec<u8>, String, Vec<String>)>,
+}
+
+#[derive(Default)]
+struct MockApplication {
+  configuration: Option<Application>,
+  backends:      Vec<Backend>,
+  http_fronts:   Vec<HttpFront>,
+  https_fronts:  Vec<HttpFront>,
+}
+
+/// listens on the socket and answers like a sozu with one worker. The orders
+/// are printed on the standard output, one JSON object per line
+pub fn run(socket: &str) -> bool {
+  let _ = fs::remove_file(socket);
+  let listener = match UnixListener::bind(socket) {
+    Ok(listener) => listener,
+    Err(e) => {
+      error!("could not listen on {}: {}", socket, e);
+      return false;
+    }
+  };
+  info!("mock proxy listening on {}", socket);
+
+  let mut state = MockState::default();
+  for stream in listener.incoming() {
+    let stream = match stream.and_then(UnixStream::from_stream) {
+      Ok(stream) => stream,
+      Err(e) => {
+        error!("could not accept a connection: {}", e);
+        continue;
+      }
+    };
+
+    let mut channel: Channel<CommandResponse,CommandRequest> = Channel::new(stream, 10000, 20000);
+    channel.set_blocking(true);
+    // the connection is closed when the read fails
+    while let Some(request) = channel.read_message() {
+      for answer in state.handle(request) {
+        if !channel.write_message(&answer) {
+          break;
+        }
+      }
+    }
+    debug!("client disconnected");
+  }
+
+  true
+}
+
+impl MockState {
+  fn handle(&mut self, request: CommandRequest) -> Vec<CommandResponse> {
+    let id = request.id;
+    match request.data {
+      CommandRequestData::ListWorkers => vec![answer(&id, CommandStatus::Ok, "", Some(CommandResponseData::Workers(vec![
+        WorkerInfo { id: WORKER_ID, pid: process::id() as i32, run_state: RunState::Running },
+      ])))],
+      CommandRequestData::SaveState { .. } => vec![answer(&id, CommandStatus::Ok, "state saved", None)],
+      CommandRequestData::Proxy(ProxyRequestData::Query(query)) => {
+        let mut answers = BTreeMap::new();
+        answers.insert(WORKER_ID.to_string(), self.query(query));
+        vec![answer(&id, CommandStatus::Ok, "", Some(CommandResponseData::Query(answers)))]
+      },
+      CommandRequestData::Proxy(order) => {
+        match serde_json::to_string(&without_key(&order)) {
+          Ok(line) => println!("{}", line),
+          Err(e) => error!("could not serialize the order: {}", e),
+        }
+        match self.apply(order) {
+          Ok(()) => vec![answer(&id, CommandStatus::Processing, "", None), answer(&id, CommandStatus::Ok, "", None)],
+          Err(message) => vec![answer(&id, CommandStatus::Error, &message, None)],
+        }
+      },
+      data => vec![answer(&id, CommandStatus::Error, &format!("unsupported request: {:?}", data), None)],
+    }
+  }
+
+  fn apply(&mut self, order: ProxyRequestData) -> Result<(), String> {
+    match order {
+      ProxyRequestData::AddApplication(application) => {
+        self.application(&application.app_id).configuration = Some(application);
+      },
+      ProxyRequestData::RemoveApplication(app_id) => {
+        self.applications.remove(&app_id);
+      },
+      ProxyRequestData::AddBackend(backend) => self.application(&backend.app_id).backends.push(backend),
+      ProxyRequestData::RemoveBackend(backend) => self.application(&backend.app_id).backends
+        .retain(|b| b.backend_id != backend.backend_id || b.address != backend.address),
+      ProxyRequestData::AddHttpFront(front) => self.application(&front.app_id).http_fronts.push(front),
+      ProxyRequestData::RemoveHttpFront(front) => self.application(&front.app_id).http_fronts.retain(|f| *f != front),
+      ProxyRequestData::AddHttpsFront(front) => self.application(&front.app_id).https_fronts.push(front),
+      ProxyRequestData::RemoveHttpsFront(front) => self.application(&front.app_id).https_fronts.retain(|f| *f != front),
+      ProxyRequestData::AddCertificate(add) => self.add_certificate(add.front, add.certificate, add.names)?,
+      ProxyRequestData::ReplaceCertificate(replace) => {
+        self.remove_certificate(&replace.front, &replace.old_fingerprint.0);
+        self.add_certificate(replace.front, replace.new_certificate, replace.new_names)?;
+      },
+      ProxyRequestData::RemoveCertificate(remove) => self.remove_certificate(&remove.front, &remove.fingerprint.0),
+      _ => {},
+    }
+
+    Ok(())
+  }
+
+  fn application(&mut self, app_id: &str) -> &mut MockApplication {
+    self.applications.entry(app_id.to_string()).or_insert_with(MockApplication::default)
+  }
+
+  fn add_certificate(&mut self, front: SocketAddr, certificate: CertificateAndKey, names: Vec<String>) -> Result<(), String> {
+    let fingerprint = calculate_fingerprint(certificate.certificate.as_bytes())
+      .ok_or_else(|| "could not parse the certificate".to_string())?;
+    let names = if names.is_empty() { revoke::certificate_names(&certificate.certificate) } else { names };
+    self.remove_certificate(&front, &fingerprint);
+    self.certificates.push((front, fingerprint, certificate.certificate, names));
+    Ok(())
+  }
+
+  fn remove_certificate(&mut self, front: &SocketAddr, fingerprint: &[u8]) {
+    self.certificates.retain(|&(ref f, ref fp, _, _)| f != front || &fp[..] != fingerprint);
+  }
+
+  fn query(&self, query: Query) -> QueryAnswer {
+    match query {
+      Query::Applications(QueryApplicationType::AppId(app_id)) => QueryAnswer::Applications(vec![QueryAnswerApplication {
+        app_id: app_id.clone(),
+        configuration: self.applications.get(&app_id).and_then(|application| application.configuration.clone()),
+        http_frontends: self.applications.get(&app_id).map(|application| application.http_fronts.clone()).unwrap_or_else(Vec::new),
+        https_frontends: self.applications.get(&app_id).map(|application| application.https_fronts.clone()).unwrap_or_else(Vec::new),
+        tcp_frontends: Vec::new(),
+        backends: self.applications.get(&app_id).map(|application| application.backends.clone()).unwrap_or_else(Vec::new),
+      }]),
+      Query::ApplicationsHashes => QueryAnswer::ApplicationsHashes(
+        self.applications.keys().map(|app_id| (app_id.clone(), 0)).collect()
+      ),
+      Query::Certificates(QueryCertificateType::All) => {
+        let mut fronts: HashMap<SocketAddr, BTreeMap<String, Vec<u8>>> = HashMap::new();
+        for &(front, ref fingerprint, _, ref names) in self.certificates.iter() {
+          for name in names.iter() {
+            fronts.entry(front).or_insert_with(BTreeMap::new).insert(name.clone(), fingerprint.clone());
+          }
+        }
+        QueryAnswer::Certificates(QueryAnswerCertificate::All(fronts))
+      },
+      Query::Certificates(QueryCertificateType::Domain(domain)) => {
+        let mut fronts: HashMap<SocketAddr, Option<(String, Vec<u8>)>> = HashMap::new();
+        for &(front, ref fingerprint, _, ref names) in self.certificates.iter() {
+          if names.contains(&domain) {
+            fronts.insert(front, Some((domain.clone(), fingerprint.clone())));
+          }
+        }
+        QueryAnswer::Certificates(QueryAnswerCertificate::Domain(fronts))
+      },
+      Query::Certificates(QueryCertificateType::Fingerprint(fingerprint)) => QueryAnswer::Certificates(QueryAnswerCertificate::Fingerprint(
+        self.certificates.iter().find(|&&(_, ref fp, _, _)| *fp == fingerprint)
+          .map(|&(_, _, ref certificate, ref names)| (certificate.clone(), names.clone()))
+      )),
+      query => {
+        warn!("unsupported query: {:?}", query);
+        QueryAnswer::Applications(Vec::new())
+      },
+    }
+  }
+}
+
+fn answer(id: &str, status: CommandStatus, message: &str, data: Option<CommandResponseData>) -> CommandResponse {
+  CommandResponse::new(id.to_string(), status, message.to_string(), data)
+}
+
+/// the order as printed, the private keys would end up in the CI logs. The
+/// certificates are kept, they are public
+fn without_key(order: &ProxyRequestData) -> ProxyRequestData {
+  let mut order = order.clone();
+  match order {
+    ProxyRequestData::AddCertificate(ref mut add) => add.certificate.key.clear(),
+    ProxyRequestData::ReplaceCertificate(ref mut replace) => replace.new_certificate.key.clear(),
+    _ => {},
+  }
+  order
+}
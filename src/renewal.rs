@@ -0,0 +1,181 @@
+//! decides whether an existing certificate must be renewed
+
+use chrono::{DateTime, Utc};
+use openssl::{asn1::Asn1Time, x509::X509};
+
+use acme;
+use certificate;
+
+/// id-ce-authorityKeyIdentifier (2.5.29.35) as encoded in DER
+const AUTHORITY_KEY_ID_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x23];
+
+/// asks the CA for the certificate's suggested renewal window (ARI), and
+/// tells whether we are past its start. None if the CA does not support
+/// ARI, or does not answer
+pub fn ari_window_open(directory_url: &str, certificate: &[u8]) -> Option<bool> {
+  let cert = match X509::from_pem(certificate) {
+    Ok(cert) => cert,
+    Err(e) => {
+      error!("could not parse the previous certificate: {}", e);
+      return None;
+    }
+  };
+
+  let (key_id, serial) = match certificate_id(&cert) {
+    Some(id) => id,
+    None => {
+      warn!("the previous certificate has no authority key identifier, cannot query renewal information");
+      return None;
+    }
+  };
+
+  let info = match acme::directory(directory_url).and_then(|directory| acme::renewal_info(&directory, &key_id, &serial)) {
+    Ok(Some(info)) => info,
+    Ok(None) => {
+      debug!("the CA does not provide renewal information");
+      return None;
+    },
+    Err(e) => {
+      warn!("could not get renewal information: {}", e);
+      return None;
+    }
+  };
+
+  let start = match DateTime::parse_from_rfc3339(&info.suggested_window.start) {
+    Ok(start) => start.with_timezone(&Utc),
+    Err(e) => {
+      warn!("invalid renewal window start {}: {}", info.suggested_window.start, e);
+      return None;
+    }
+  };
+
+  if let Some(ref url) = info.explanation_url {
+    info!("the CA explains its renewal window at {}", url);
+  }
+
+  if Utc::now() < start {
+    info!("the CA suggests renewing between {} and {}, renewal not needed yet",
+      info.suggested_window.start, info.suggested_window.end);
+    Some(false)
+  } else {
+    info!("renewal window opened on {}", info.suggested_window.start);
+    Some(true)
+  }
+}
+
+/// whether the certificate (in PEM format) expires in less than this number
+/// of days. An unreadable certificate must be replaced
+pub fn expires_within(certificate: &[u8], days: u32) -> bool {
+  let cert = match X509::from_pem(certificate) {
+    Ok(cert) => cert,
+    Err(e) => {
+      error!("could not parse the certificate: {}", e);
+      return true;
+    }
+  };
+
+  match Asn1Time::days_from_now(days) {
+    Ok(limit) => cert.not_after() < limit,
+    Err(e) => {
+      error!("could not compute the renewal date: {}", e);
+      true
+    }
+  }
+}
+
+/// ARI certificate identifier parts: the authority key identifier and the DER encoded serial number
+fn certificate_id(cert: &X509) -> Option<(Vec<u8>, Vec<u8>)> {
+  let der = cert.to_der().ok()?;
+  let key_id = authority_key_id(&der)?;
+
+  let mut serial = cert.serial_number().to_bn().ok()?.to_vec();
+  // the serial is a positive INTEGER, keep the leading zero of its encoding
+  if serial.first().map(|b| b & 0x80 != 0).unwrap_or(true) {
+    serial.insert(0, 0);
+  }
+
+  Some((key_id, serial))
+}
+
+/// finds the keyIdentifier field of the authority key identifier extension
+fn authority_key_id(der: &[u8]) -> Option<Vec<u8>> {
+  let extension = certificate::extension(der, AUTHORITY_KEY_ID_OID)?;
+  // the AuthorityKeyIdentifier SEQUENCE, starting with keyIdentifier [0] IMPLICIT OCTET STRING
+  let (_, sequence, _) = certificate::der_element(extension)?;
+  match certificate::der_element(sequence)? {
+    (0x80, key_id, _) => Some(key_id.to_vec()),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::{
+    bn::BigNum,
+    hash::MessageDigest,
+    nid::Nid,
+    x509::{X509Builder, X509NameBuilder, extension::{AuthorityKeyIdentifier, SubjectKeyIdentifier}},
+  };
+
+  /// id-ce-subjectKeyIdentifier (2.5.29.14)
+  const SUBJECT_KEY_ID_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x0e];
+
+  /// self signed certificate, valid for this number of days
+  fn self_signed(serial: u32, days: u32, authority_key_id: bool) -> X509 {
+    let key = certificate::generate_key("ecdsa", "p256");
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "example.com").unwrap();
+    let name = name.build();
+
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(days).unwrap()).unwrap();
+    if authority_key_id {
+      let subject_key_id = SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None)).unwrap();
+      builder.append_extension(subject_key_id).unwrap();
+      let authority_key_id = AuthorityKeyIdentifier::new().keyid(true).build(&builder.x509v3_context(None, None)).unwrap();
+      builder.append_extension(authority_key_id).unwrap();
+    }
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    builder.build()
+  }
+
+  #[test]
+  fn reads_the_certificate_id() {
+    let cert = self_signed(0x80ff, 30, true);
+    let der = cert.to_der().unwrap();
+
+    // self signed: the authority key identifier is the subject key identifier
+    let subject_key_id = certificate::extension(&der, SUBJECT_KEY_ID_OID)
+      .and_then(certificate::der_element)
+      .map(|(_, key_id, _)| key_id.to_vec())
+      .unwrap();
+    assert_eq!(subject_key_id.len(), 20);
+    assert_eq!(authority_key_id(&der), Some(subject_key_id.clone()));
+
+    // a leading zero keeps the serial positive
+    assert_eq!(certificate_id(&cert), Some((subject_key_id, vec![0x00, 0x80, 0xff])));
+    assert_eq!(certificate_id(&self_signed(0x7f, 30, true)).map(|(_, serial)| serial), Some(vec![0x7f]));
+  }
+
+  #[test]
+  fn needs_the_authority_key_id() {
+    let cert = self_signed(1, 30, false);
+    assert_eq!(authority_key_id(&cert.to_der().unwrap()), None);
+    assert_eq!(certificate_id(&cert), None);
+  }
+
+  #[test]
+  fn compares_the_expiration_date() {
+    let pem = self_signed(1, 30, false).to_pem().unwrap();
+    assert!(!expires_within(&pem, 10));
+    assert!(expires_within(&pem, 60));
+    assert!(expires_within(b"not a certificate", 10));
+  }
+}
@@ -0,0 +1,99 @@
+//! certificate revocation
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use openssl::x509::X509;
+use sozu_command::certificate::calculate_fingerprint;
+
+use account;
+use sozu::{self, Sozu};
+
+/// RFC 5280 revocation reason codes
+pub fn reason_code(reason: &str) -> u8 {
+  match reason {
+    "keyCompromise"        => 1,
+    "affiliationChanged"   => 3,
+    "superseded"           => 4,
+    "cessationOfOperation" => 5,
+    _                      => 0,
+  }
+}
+
+/// DNS names of the certificate (in PEM format)
+pub fn certificate_names(certificate: &str) -> Vec<String> {
+  X509::from_pem(certificate.as_bytes()).ok()
+    .and_then(|cert| cert.subject_alt_names())
+    .map(|names| names.iter().filter_map(|name| {
+      name.dnsname().map(|n| n.to_string()).or_else(|| name.ipaddress().and_then(ip_to_string))
+    }).collect())
+    .unwrap_or_else(Vec::new)
+}
+
+/// IP address SAN, in network byte order
+fn ip_to_string(bytes: &[u8]) -> Option<String> {
+  match bytes.len() {
+    4 => Some(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).to_string()),
+    16 => {
+      let mut octets = [0u8; 16];
+      octets.copy_from_slice(bytes);
+      Some(Ipv6Addr::from(octets).to_string())
+    },
+    _ => None,
+  }
+}
+
+/// revokes the certificate (in PEM format) with the CA
+pub fn revoke(directory_url: &str, email: &str, certificate: &str, reason: u8) -> bool {
+  let der = match X509::from_pem(certificate.as_bytes()).and_then(|cert| cert.to_der()) {
+    Ok(der) => der,
+    Err(e) => {
+      error!("could not parse the certificate: {}", e);
+      return false;
+    }
+  };
+
+  let mut client = match account::client(directory_url, email) {
+    Some(client) => client,
+    None => return false,
+  };
+
+  match client.revoke(&der, reason) {
+    Ok(()) => {
+      info!("certificate revoked");
+      true
+    },
+    Err(e) => {
+      error!("could not revoke the certificate: {}", e);
+      false
+    }
+  }
+}
+
+/// removes the certificate from the HTTPS frontend, along with the application's
+/// HTTPS fronts for its names if an application id is given
+pub fn remove_from_sozu(channel: &mut Sozu, https: &SocketAddr, app_id: Option<&str>, path_begin: &str,
+  certificate: &str, names: &[String]) -> bool {
+  let fingerprint = match calculate_fingerprint(certificate.as_bytes()) {
+    Some(f) => f,
+    None => {
+      error!("could not calculate the certificate fingerprint");
+      return false;
+    }
+  };
+
+  let mut ok = true;
+  if let Some(app_id) = app_id {
+    for name in names.iter() {
+      if let Err(e) = sozu::remove_https_front(channel, https, app_id, name, path_begin) {
+        error!("could not remove the HTTPS front for {}: {}", name, e);
+        ok = false;
+      }
+    }
+  }
+
+  if let Err(e) = sozu::remove_certificate(channel, https, fingerprint, names) {
+    error!("could not remove the certificate: {}", e);
+    ok = false;
+  }
+
+  ok
+}
@@ -0,0 +1,149 @@
+//! uploads the issued certificate to an S3 compatible bucket, where the other
+//! sozu hosts fetch the renewed certificates. The requests are signed with
+//! AWS Signature Version 4
+
+use chrono::Utc;
+use openssl::{
+  error::ErrorStack,
+  hash::{hash, MessageDigest},
+  pkcs5::pbkdf2_hmac,
+  pkey::PKey,
+  rand::rand_bytes,
+  sign::Signer,
+  symm::{self, Cipher},
+};
+use ureq;
+
+/// iterations of `openssl enc -pbkdf2`, so the objects can be decrypted with it
+const PBKDF2_ITERATIONS: usize = 10000;
+
+pub struct Bucket {
+  /// like https://s3.eu-west-1.amazonaws.com, the bucket is in the path
+  pub endpoint:      String,
+  pub bucket:        String,
+  pub region:        String,
+  /// prepended to the names of the objects
+  pub prefix:        String,
+  pub access_key:    String,
+  pub secret_key:    String,
+  /// for temporary credentials
+  pub session_token: Option<String>,
+  /// server side encryption: AES256 or aws:kms
+  pub encryption:    Option<String>,
+  pub kms_key_id:    Option<String>,
+  /// encrypts the objects before the upload, like `openssl enc -aes-256-cbc -pbkdf2`
+  pub passphrase:    Option<Vec<u8>>,
+}
+
+impl Bucket {
+  /// uploads `cert.pem`, `chain.pem`, `fullchain.pem` and `key.pem` under
+  /// the prefix, with the `.enc` suffix when encrypted. Without key (with a
+  /// CSR), `key.pem` is not uploaded
+  pub fn store(&self, certificate: &str, chain: &str, key: Option<&str>) -> Result<(), String> {
+    let fullchain = format!("{}\n{}", certificate.trim_end(), chain);
+    let mut objects = vec![("cert.pem", certificate), ("chain.pem", chain), ("fullchain.pem", fullchain.as_str())];
+    objects.extend(key.map(|key| ("key.pem", key)));
+
+    for (name, content) in objects {
+      let (name, body) = match self.passphrase {
+        Some(ref passphrase) => (format!("{}{}.enc", self.prefix, name),
+          encrypt(passphrase, content.as_bytes()).map_err(|e| format!("could not encrypt {}: {}", name, e))?),
+        None => (format!("{}{}", self.prefix, name), content.as_bytes().to_vec()),
+      };
+      self.put(&name, &body)?;
+    }
+
+    info!("stored the certificate in s3://{}/{}", self.bucket, self.prefix);
+    Ok(())
+  }
+
+  fn put(&self, name: &str, body: &[u8]) -> Result<(), String> {
+    let endpoint = self.endpoint.trim_end_matches('/');
+    let host = endpoint.splitn(2, "://").last().unwrap_or(endpoint);
+    let path = format!("/{}/{}", self.bucket, uri_encode(name));
+    let now = Utc::now();
+    let date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let day = now.format("%Y%m%d").to_string();
+    let payload_hash = hex(&sha256(body));
+
+    // the signed headers, sorted by name
+    let mut headers = vec![
+      ("host", host.to_string()),
+      ("x-amz-content-sha256", payload_hash.clone()),
+      ("x-amz-date", date.clone()),
+    ];
+    if let Some(ref token) = self.session_token {
+      headers.push(("x-amz-security-token", token.clone()));
+    }
+    if let Some(ref encryption) = self.encryption {
+      headers.push(("x-amz-server-side-encryption", encryption.clone()));
+    }
+    if let Some(ref key_id) = self.kms_key_id {
+      headers.push(("x-amz-server-side-encryption-aws-kms-key-id", key_id.clone()));
+    }
+    headers.sort();
+
+    let signed_headers = headers.iter().map(|&(name, _)| name).collect::<Vec<_>>().join(";");
+    let canonical_request = format!("PUT\n{}\n\n{}\n{}\n{}", path,
+      headers.iter().map(|&(name, ref value)| format!("{}:{}\n", name, value.trim())).collect::<String>(),
+      signed_headers, payload_hash);
+    let scope = format!("{}/{}/s3/aws4_request", day, self.region);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", date, scope, hex(&sha256(canonical_request.as_bytes())));
+
+    let signature = [day.as_str(), self.region.as_str(), "s3", "aws4_request"].iter()
+      .try_fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, part| hmac(&key, part.as_bytes()))
+      .and_then(|key| hmac(&key, string_to_sign.as_bytes()))
+      .map_err(|e| format!("could not sign the request: {}", e))?;
+    let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+      self.access_key, scope, signed_headers, hex(&signature));
+
+    let mut request = ureq::put(&format!("{}{}", endpoint, path));
+    for &(name, ref value) in headers.iter().filter(|&&(name, _)| name != "host") {
+      request.set(name, value);
+    }
+    let res = request.set("Authorization", &authorization).send_bytes(body);
+    if res.synthetic() || res.error() {
+      let status = res.status_line().to_string();
+      return Err(format!("could not upload {} to the bucket {}: {}: {}", name, self.bucket, status, res.into_string().unwrap_or_default()));
+    }
+    debug!("uploaded s3://{}/{}", self.bucket, name);
+    Ok(())
+  }
+}
+
+/// the format of `openssl enc -aes-256-cbc -pbkdf2 -salt`: `Salted__`, the
+/// salt, then the ciphertext. The key and IV are derived from the passphrase
+fn encrypt(passphrase: &[u8], data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+  let mut salt = [0u8; 8];
+  rand_bytes(&mut salt)?;
+  let mut key_iv = [0u8; 48];
+  pbkdf2_hmac(passphrase, &salt, PBKDF2_ITERATIONS, MessageDigest::sha256(), &mut key_iv)?;
+
+  let mut encrypted = b"Salted__".to_vec();
+  encrypted.extend_from_slice(&salt);
+  encrypted.extend(symm::encrypt(Cipher::aes_256_cbc(), &key_iv[..32], Some(&key_iv[32..]), data)?);
+  Ok(encrypted)
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+  hash(MessageDigest::sha256(), data).expect("could not hash").to_vec()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+  let key = PKey::hmac(key)?;
+  let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+  signer.update(data)?;
+  signer.sign_to_vec()
+}
+
+fn hex(data: &[u8]) -> String {
+  data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// the object name in the path, the separators are kept
+fn uri_encode(name: &str) -> String {
+  name.bytes().map(|b| match b {
+    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (b as char).to_string(),
+    _ => format!("%{:02X}", b),
+  }).collect()
+}
@@ -0,0 +1,181 @@
+//! cron-like schedules for the daemon's renewal attempts
+
+use std::fmt;
+use chrono::{DateTime, Datelike, Local, Timelike};
+
+/// the five fields of a crontab line: minute, hour, day of month, month and
+/// day of week (0 or 7 is Sunday). Each field accepts `*`, values, ranges
+/// (`1-5`), lists (`1,15`) and steps (`*/10`, `0-30/5`), in local time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+  expression:    String,
+  minutes:       Vec<bool>,
+  hours:         Vec<bool>,
+  days:          Vec<bool>,
+  months:        Vec<bool>,
+  weekdays:      Vec<bool>,
+  /// cron matches either day field when both are restricted, a field
+  /// starting with `*` is not, even with a step
+  any_day:       bool,
+  any_weekday:   bool,
+}
+
+impl Schedule {
+  pub fn parse(expression: &str) -> Result<Schedule, String> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    if fields.len() != 5 {
+      return Err(format!("invalid schedule {:?}: expected 5 fields, found {}", expression, fields.len()));
+    }
+
+    let mut weekdays = parse_field(fields[4], 0, 7)?;
+    // 7 is another name for Sunday
+    if weekdays[7] {
+      weekdays[0] = true;
+    }
+
+    Ok(Schedule {
+      expression:  expression.to_string(),
+      minutes:     parse_field(fields[0], 0, 59)?,
+      hours:       parse_field(fields[1], 0, 23)?,
+      days:        parse_field(fields[2], 1, 31)?,
+      months:      parse_field(fields[3], 1, 12)?,
+      weekdays,
+      any_day:     fields[2].starts_with('*'),
+      any_weekday: fields[4].starts_with('*'),
+    })
+  }
+
+  /// whether the minute of this date is part of the schedule
+  pub fn matches(&self, date: &DateTime<Local>) -> bool {
+    let day = self.days[date.day() as usize];
+    let weekday = self.weekdays[date.weekday().num_days_from_sunday() as usize];
+    let day_matches = match (self.any_day, self.any_weekday) {
+      (false, false) => day || weekday,
+      _ => day && weekday,
+    };
+
+    self.minutes[date.minute() as usize] && self.hours[date.hour() as usize]
+      && self.months[date.month() as usize] && day_matches
+  }
+}
+
+impl fmt::Display for Schedule {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.expression)
+  }
+}
+
+/// values allowed by the field, indexed by value
+fn parse_field(field: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+  let mut values = vec![false; max + 1];
+
+  for part in field.split(',') {
+    let (range, step) = match part.find('/') {
+      Some(i) => (&part[..i], parse_number(&part[i + 1..])?),
+      None => (part, 1),
+    };
+    if step == 0 {
+      return Err(format!("invalid step in {:?}", part));
+    }
+
+    let (start, end) = if range == "*" {
+      (min, max)
+    } else if let Some(i) = range.find('-') {
+      (parse_number(&range[..i])?, parse_number(&range[i + 1..])?)
+    } else {
+      let value = parse_number(range)?;
+      // `5/10` starts at 5 and goes to the end of the range
+      (value, if step > 1 { max } else { value })
+    };
+    if start < min || end > max || start > end {
+      return Err(format!("{:?} is out of the {}-{} range", part, min, max));
+    }
+
+    for value in (start..end + 1).step_by(step) {
+      values[value] = true;
+    }
+  }
+
+  Ok(values)
+}
+
+fn parse_number(s: &str) -> Result<usize, String> {
+  s.parse::<usize>().map_err(|_| format!("invalid number {:?}", s))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+
+  fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+    // March 2021 starts on a Monday
+    Local.ymd(2021, 3, day).and_hms(hour, minute, 0)
+  }
+
+  #[test]
+  fn ranges_and_lists() {
+    let schedule = Schedule::parse("0,30 9-17 * * *").unwrap();
+    assert!(schedule.matches(&at(1, 9, 0)));
+    assert!(schedule.matches(&at(1, 17, 30)));
+    assert!(!schedule.matches(&at(1, 8, 30)));
+    assert!(!schedule.matches(&at(1, 18, 0)));
+    assert!(!schedule.matches(&at(1, 12, 15)));
+  }
+
+  #[test]
+  fn steps() {
+    let schedule = Schedule::parse("*/15 * * * *").unwrap();
+    assert!(schedule.matches(&at(1, 0, 0)));
+    assert!(schedule.matches(&at(1, 0, 45)));
+    assert!(!schedule.matches(&at(1, 0, 10)));
+
+    let schedule = Schedule::parse("0-30/10 * * * *").unwrap();
+    assert!(schedule.matches(&at(1, 0, 30)));
+    assert!(!schedule.matches(&at(1, 0, 40)));
+
+    let schedule = Schedule::parse("5/20 * * * *").unwrap();
+    assert!(schedule.matches(&at(1, 0, 5)));
+    assert!(schedule.matches(&at(1, 0, 45)));
+    assert!(!schedule.matches(&at(1, 0, 0)));
+  }
+
+  #[test]
+  fn seven_is_sunday() {
+    let schedule = Schedule::parse("0 3 * * 7").unwrap();
+    assert!(schedule.matches(&at(7, 3, 0)));
+    assert!(!schedule.matches(&at(1, 3, 0)));
+    assert_eq!(schedule.weekdays[..7], Schedule::parse("0 3 * * 0").unwrap().weekdays[..7]);
+  }
+
+  #[test]
+  fn restricted_day_fields_match_either() {
+    // the 1st of the month or any Monday
+    let schedule = Schedule::parse("0 3 1 * 1").unwrap();
+    assert!(schedule.matches(&at(1, 3, 0)));
+    assert!(schedule.matches(&at(8, 3, 0)));
+    assert!(schedule.matches(&Local.ymd(2021, 4, 1).and_hms(3, 0, 0)));
+    assert!(!schedule.matches(&at(2, 3, 0)));
+
+    // a step on the day of month still counts as `*`: the Mondays on odd days
+    let schedule = Schedule::parse("0 3 */2 * 1").unwrap();
+    assert!(schedule.matches(&at(1, 3, 0)));
+    assert!(schedule.matches(&at(15, 3, 0)));
+    assert!(!schedule.matches(&at(8, 3, 0)));
+    assert!(!schedule.matches(&at(3, 3, 0)));
+
+    // an unrestricted day of month leaves the day of week alone
+    let schedule = Schedule::parse("0 3 * * 1").unwrap();
+    assert!(schedule.matches(&at(15, 3, 0)));
+    assert!(!schedule.matches(&at(2, 3, 0)));
+  }
+
+  #[test]
+  fn invalid_expressions() {
+    assert!(Schedule::parse("0 3 * *").is_err());
+    assert!(Schedule::parse("60 3 * * *").is_err());
+    assert!(Schedule::parse("0 3 0 * *").is_err());
+    assert!(Schedule::parse("*/0 3 * * *").is_err());
+    assert!(Schedule::parse("0 5-3 * * *").is_err());
+  }
+}
@@ -0,0 +1,202 @@
+//! options read from SOZU_ACME_* environment variables and sozu-acme.toml, so
+//! long invocations do not have to be encoded in shell scripts
+
+use std::{env, fs, io};
+use toml::{self, Value};
+
+use batch;
+use lock;
+
+/// read from the current directory, like the state file and the account keys
+pub const SETTINGS_FILE: &str = "sozu-acme.toml";
+
+/// subcommands that can have a table of options in the file
+const SUBCOMMANDS: &[&str] = &["issue", "check", "plan", "status", "account", "list", "revoke", "delete", "renew", "batch", "daemon", "ctl", "mock-proxy"];
+
+/// prefix of the environment variables setting options
+pub const ENV_PREFIX: &str = "SOZU_ACME_";
+
+/// options that can be given several times
+const REPEATABLE: &[&str] = &["config", "socket", "domain", "alias", "https-listener", "chat-mention"];
+
+/// options whose own name ends in `-file`, their variables are not read
+/// through the `_FILE` suffix: `SOZU_ACME_DOMAINS_FILE` is the path given
+/// to `--domains-file`
+const FILE_OPTIONS: &[&str] = &["account-passphrase-file", "domains-file", "eab-hmac-key-file", "key-passphrase-file",
+  "metrics-file", "s3-passphrase-file", "s3-secret-key-file", "spki-pin-file", "vault-secret-id-file", "vault-token-file",
+  "webhook-secret-file"];
+
+/// other ways of setting an option on the command line
+const ALIASES: &[(&str, &str)] = &[("config", "-c")];
+
+/// the command line, completed with the options of the environment and of
+/// the file that it does not set. The command line overrides the
+/// environment, which overrides the file
+pub fn complete(mut arguments: Vec<String>) -> Result<Vec<String>, String> {
+  let subcommand = arguments.get(1)
+    .map(|argument| if argument == "list-certs" { "list".to_string() } else { argument.clone() })
+    .filter(|argument| SUBCOMMANDS.contains(&argument.as_str()));
+  let subcommand = subcommand.as_ref().map(|subcommand| subcommand.as_str());
+
+  let environment = environment_options(subcommand)?;
+  let file = file_options(subcommand)?;
+
+  let mut added = Vec::new();
+  for &(ref source, ref options) in [(ENV_PREFIX, environment), (SETTINGS_FILE, file)].iter() {
+    for &(ref key, ref value) in options.iter() {
+      if !is_set(&arguments, key) && !is_set(&added, key) {
+        added.extend(batch::option_arguments(key, value).map_err(|e| format!("{}: {}", source, e))?);
+      }
+    }
+  }
+  // the values can be secrets read from files
+  debug!("options from the environment and {}: {}", SETTINGS_FILE,
+    added.iter().filter(|argument| argument.starts_with("--")).cloned().collect::<Vec<_>>().join(" "));
+  arguments.extend(added);
+  Ok(arguments)
+}
+
+/// whether the options without subcommand or table apply
+fn top_level(subcommand: Option<&str>) -> bool {
+  match subcommand {
+    None | Some("issue") | Some("check") | Some("plan") | Some("renew") => true,
+    _ => false,
+  }
+}
+
+/// the keys at the top of the file are options of `issue`, `check`, `plan` and `renew`, a
+/// table named after a subcommand holds its options. The keys and values
+/// are written like in a batch file
+fn file_options(subcommand: Option<&str>) -> Result<Vec<(String, Value)>, String> {
+  let data = match fs::read_to_string(SETTINGS_FILE) {
+    Ok(data) => data,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(e) => return Err(format!("could not read {}: {}", SETTINGS_FILE, e)),
+  };
+  let file: toml::value::Table = toml::from_str(&data).map_err(|e| format!("could not parse {}: {}", SETTINGS_FILE, e))?;
+
+  let mut options = Vec::new();
+  if top_level(subcommand) {
+    options.extend(file.iter().filter(|&(key, _)| !SUBCOMMANDS.contains(&key.as_str())).map(|(key, value)| (key.clone(), value.clone())));
+  }
+  if let Some(subcommand) = subcommand {
+    match file.get(subcommand) {
+      Some(&Value::Table(ref table)) => options.extend(table.iter().map(|(key, value)| (key.clone(), value.clone()))),
+      Some(_) => return Err(format!("{}: {} must be a table", SETTINGS_FILE, subcommand)),
+      None => {},
+    }
+  }
+  Ok(options)
+}
+
+/// `SOZU_ACME_EMAIL` sets `--email` of `issue` and `renew`,
+/// `SOZU_ACME_REVOKE__EMAIL` sets it for `revoke`. `true` and `false` set or
+/// leave a flag, the repeatable options take comma separated values. With the
+/// `_FILE` suffix, the value is read from that file, for injected secrets
+fn environment_options(subcommand: Option<&str>) -> Result<Vec<(String, Value)>, String> {
+  let mut options = Vec::new();
+  for (name, value) in env::vars_os() {
+    let (name, value) = match (name.into_string(), value.into_string()) {
+      (Ok(name), Ok(value)) => (name, value),
+      _ => continue,
+    };
+    if !name.starts_with(ENV_PREFIX) || name == lock::LOCK_HELD_VARIABLE {
+      continue;
+    }
+
+    let (table, key) = match name[ENV_PREFIX.len()..].find("__") {
+      Some(i) => (Some(name[ENV_PREFIX.len()..ENV_PREFIX.len() + i].to_lowercase().replace('_', "-")), &name[ENV_PREFIX.len() + i + 2..]),
+      None => (None, &name[ENV_PREFIX.len()..]),
+    };
+    let applies = match table {
+      Some(ref table) => Some(table.as_str()) == subcommand,
+      None => top_level(subcommand),
+    };
+    if !applies {
+      continue;
+    }
+
+    let (key, value) = variable_option(key, value).map_err(|e| format!("{}: {}", name, e))?;
+
+    let value = match value.as_str() {
+      "true" => Value::Boolean(true),
+      "false" => Value::Boolean(false),
+      _ if REPEATABLE.contains(&key.as_str()) => Value::Array(value.split(',').map(|v| Value::String(v.trim().to_string())).collect()),
+      _ => Value::String(value.clone()),
+    };
+    options.push((key, value));
+  }
+
+  options.sort_by(|a, b| a.0.cmp(&b.0));
+  Ok(options)
+}
+
+/// the option set by the variable named `SOZU_ACME_<key>`, and its value.
+/// With the `_FILE` suffix, the value is read from that file, unless the
+/// option itself ends in `-file`
+fn variable_option(key: &str, value: String) -> Result<(String, String), String> {
+  let option = key.to_lowercase().replace('_', "-");
+  if !option.ends_with("-file") || FILE_OPTIONS.contains(&option.as_str()) {
+    return Ok((option, value));
+  }
+
+  let contents = fs::read_to_string(&value).map_err(|e| format!("could not read {}: {}", value, e))?;
+  Ok((option[..option.len() - "-file".len()].to_string(), contents.trim().to_string()))
+}
+
+/// whether the arguments set the option
+fn is_set(arguments: &[String], key: &str) -> bool {
+  let mut names = vec![format!("--{}", key)];
+  names.extend(ALIASES.iter().filter(|&&(option, _)| option == key).map(|&(_, alias)| alias.to_string()));
+
+  arguments.iter().any(|argument| names.iter().any(|name| argument == name || argument.starts_with(&format!("{}=", name))))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{io::Write, process};
+
+  #[test]
+  fn file_options_keep_their_path() {
+    assert_eq!(variable_option("EAB_HMAC_KEY_FILE", "/run/secrets/hmac".to_string()),
+      Ok(("eab-hmac-key-file".to_string(), "/run/secrets/hmac".to_string())));
+    assert_eq!(variable_option("DOMAINS_FILE", "hosts.txt".to_string()),
+      Ok(("domains-file".to_string(), "hosts.txt".to_string())));
+    assert_eq!(variable_option("EMAIL", "admin@example.com".to_string()),
+      Ok(("email".to_string(), "admin@example.com".to_string())));
+  }
+
+  #[test]
+  fn file_suffix_reads_the_value() {
+    let path = env::temp_dir().join(format!("sozu-acme-settings-test-{}", process::id()));
+    fs::File::create(&path).and_then(|mut file| file.write_all(b"admin@example.com\n")).unwrap();
+    let option = variable_option("EMAIL_FILE", path.to_string_lossy().into_owned());
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(option, Ok(("email".to_string(), "admin@example.com".to_string())));
+    assert!(variable_option("EMAIL_FILE", "/nonexistent/sozu-acme".to_string()).is_err());
+  }
+
+  #[test]
+  fn reads_the_subcommand_variables() {
+    // only this test sets variables for mock-proxy
+    env::set_var("SOZU_ACME_MOCK_PROXY__DOMAIN", "example.com, www.example.com");
+    env::set_var("SOZU_ACME_MOCK_PROXY__STAGING", "true");
+    env::set_var("SOZU_ACME_MOCK_PROXY__EAB_HMAC_KEY_FILE", "/run/secrets/hmac");
+    env::set_var("SOZU_ACME_MOCK_PROXY__EMAIL_FILE", "/nonexistent/sozu-acme");
+    let failed = environment_options(Some("mock-proxy"));
+    env::remove_var("SOZU_ACME_MOCK_PROXY__EMAIL_FILE");
+    let options = environment_options(Some("mock-proxy"));
+    for name in ["SOZU_ACME_MOCK_PROXY__DOMAIN", "SOZU_ACME_MOCK_PROXY__STAGING", "SOZU_ACME_MOCK_PROXY__EAB_HMAC_KEY_FILE"].iter() {
+      env::remove_var(name);
+    }
+
+    assert!(failed.unwrap_err().starts_with("SOZU_ACME_MOCK_PROXY__EMAIL_FILE: "));
+    assert_eq!(options, Ok(vec![
+      ("domain".to_string(), Value::Array(vec![Value::String("example.com".to_string()), Value::String("www.example.com".to_string())])),
+      ("eab-hmac-key-file".to_string(), Value::String("/run/secrets/hmac".to_string())),
+      ("staging".to_string(), Value::Boolean(true)),
+    ]));
+  }
+}
@@ -0,0 +1,54 @@
+//! signal handling, the handlers only set flags checked by the main loops
+
+use std::{mem, ptr, sync::atomic::{AtomicBool, Ordering}};
+use libc;
+
+use exit;
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_reload(_: libc::c_int) {
+  RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// SIGHUP asks for the configuration to be read again
+pub fn handle_reload() {
+  unsafe {
+    libc::signal(libc::SIGHUP, on_reload as libc::sighandler_t);
+  }
+}
+
+/// asks for a reload like SIGHUP does
+pub fn request_reload() {
+  RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// whether SIGHUP was received since the last call
+pub fn reload_requested() -> bool {
+  RELOAD.swap(false, Ordering::SeqCst)
+}
+
+extern "C" fn on_shutdown(_: libc::c_int) {
+  SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// SIGINT and SIGTERM stop the run at the next check_shutdown, so the changes
+/// made to sozu are undone. A second signal terminates the process at once
+pub fn handle_shutdown() {
+  unsafe {
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = on_shutdown as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART | libc::SA_RESETHAND;
+    libc::sigemptyset(&mut action.sa_mask);
+    libc::sigaction(libc::SIGINT, &action, ptr::null_mut());
+    libc::sigaction(libc::SIGTERM, &action, ptr::null_mut());
+  }
+}
+
+/// unwinds if the run was interrupted, called between the steps that wait
+pub fn check_shutdown() {
+  if SHUTDOWN.load(Ordering::SeqCst) {
+    exit::fail(exit::INTERRUPTED, "interrupted, undoing the changes made to sozu");
+  }
+}
@@ -0,0 +1,1046 @@
+//! sozu command channel helpers
+
+use std::{
+  cmp, fmt, io, iter, thread,
+  cell::Cell,
+  io::Read,
+  os::unix::{io::{AsRawFd, FromRawFd, IntoRawFd}, net::UnixStream as StdUnixStream},
+  collections::BTreeMap,
+  net::SocketAddr,
+  ops::{Deref, DerefMut},
+  time::{Duration, Instant},
+};
+use mio_uds::UnixStream;
+use serde_json;
+use rand::{thread_rng, Rng, distributions::Alphanumeric};
+use sozu_command::channel::Channel;
+use sozu_command::{
+  config::Config,
+  certificate::{calculate_fingerprint, split_certificate_chain},
+  command::{CommandRequestData, CommandRequest, CommandResponse, CommandResponseData, CommandStatus, RunState},
+  proxy::{ProxyRequestData, Application, Backend, HttpFront, CertificateAndKey, CertFingerprint, LoadBalancingAlgorithms,
+    AddCertificate, RemoveBackend, ReplaceCertificate,
+    RemoveCertificate, Query, QueryAnswer, QueryAnswerCertificate, QueryApplicationType, QueryCertificateType},
+};
+
+use signal;
+use audit;
+use logging;
+use certificate;
+
+/// how long we wait for sozu's workers to apply a configuration change
+const CONFIGURATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// attempts to reconnect to the command socket before giving up, the delay
+/// doubles between attempts, up to MAX_RECONNECT_DELAY
+const RECONNECT_ATTEMPTS: u32 = 10;
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// size of the messages exchanged with sozu
+const MAX_MESSAGE_SIZE: usize = 20000;
+
+/// orders written before reading their answers, bounded so the answers
+/// do not fill the socket buffers while we are still writing
+const PIPELINE_DEPTH: usize = 64;
+
+thread_local! {
+  /// order timeout and order deadline
+  static TIMEOUTS: Cell<(Duration, Duration)> = Cell::new((Duration::from_secs(10), Duration::from_secs(60)));
+}
+
+#[derive(Debug, Clone)]
+pub enum SozuError {
+  /// the connection was lost and we could not reconnect
+  Disconnected,
+  /// sozu did not answer within the order timeout
+  Timeout(Duration),
+  /// sozu was still processing the order at the deadline
+  Deadline,
+  /// sozu answered with an error
+  Refused(String),
+  /// the certificate files could not be loaded
+  Certificate(String),
+  /// the proxy does not speak the command protocol of sozu 0.11
+  UnsupportedProtocol,
+  /// the order failed on some of the proxies, by command socket
+  Proxies(Vec<(String, SozuError)>),
+  /// some of the running workers did not answer, by id
+  Workers(Vec<String>),
+}
+
+impl fmt::Display for SozuError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      SozuError::Disconnected => write!(f, "lost the connection to sozu"),
+      SozuError::Timeout(timeout) => write!(f, "sozu did not answer in {} seconds", timeout.as_secs()),
+      SozuError::Deadline => write!(f, "sozu did not complete the order before the deadline"),
+      SozuError::Refused(ref message) => write!(f, "sozu refused the order: {}", message),
+      SozuError::Certificate(ref message) => write!(f, "{}", message),
+      SozuError::UnsupportedProtocol => write!(f, "the proxy does not speak the command protocol of sozu 0.11, newer versions are not supported"),
+      SozuError::Proxies(ref errors) => {
+        let errors: Vec<String> = errors.iter().map(|&(ref socket, ref e)| format!("{}: {}", socket, e)).collect();
+        write!(f, "failed on {}", errors.join(", "))
+      },
+      SozuError::Workers(ref workers) => write!(f, "no answer from the workers {}", workers.join(", ")),
+    }
+  }
+}
+
+/// command sockets of the proxies receiving the orders: usually one, or
+/// every member of an HA group, which all get the same configuration
+pub struct Sozu {
+  proxies: Vec<Proxy>,
+  /// the orders are printed instead of being sent, the queries are still answered
+  dry_run: bool,
+}
+
+struct Proxy {
+  /// path of the command socket, to reconnect if sozu restarts
+  socket:  String,
+  channel: Connection,
+  /// ids of the running workers, which must all answer the queries
+  workers: Vec<String>,
+}
+
+/// command socket defined in sozu's configuration file
+pub fn command_socket(config_file: &str) -> Result<String, String> {
+  Config::load_from_path(config_file)
+    .map(|config| config.command_socket)
+    .map_err(|e| format!("could not parse the configuration file {}: {}", config_file, e))
+}
+
+/// connects to the command sockets
+pub fn try_connect(sockets: &[String]) -> Result<Sozu, String> {
+  let proxies = sockets.iter().map(|socket| {
+    let mut channel = open(socket).map_err(|e| format!("could not connect to the command unix socket: {}: {}", socket, e))?;
+    let workers = check_protocol(&mut channel).map_err(|e| format!("{}: {}", socket, e))?;
+    Ok(Proxy { socket: socket.clone(), channel, workers })
+  }).collect::<Result<Vec<_>, String>>()?;
+
+  Ok(Sozu { proxies, dry_run: false })
+}
+
+impl Sozu {
+  /// with dry_run, the configuration of the proxies is left untouched
+  pub fn set_dry_run(&mut self, dry_run: bool) {
+    self.dry_run = dry_run;
+  }
+}
+
+/// what an order would have done, for dry runs. Certificates are not printed, they carry the private key
+fn print_dry_run(order: &ProxyRequestData) {
+  match *order {
+    ProxyRequestData::AddCertificate(ref add) =>
+      println!("would add a certificate for {} on {}", add.names.join(", "), add.front),
+    ProxyRequestData::ReplaceCertificate(ref replace) =>
+      println!("would replace the certificate {} with a certificate for {} on {}",
+        replace.old_fingerprint.0.iter().map(|b| format!("{:02x}", b)).collect::<String>(), replace.new_names.join(", "), replace.front),
+    _ => match serde_json::to_string(order) {
+      Ok(order) => println!("would send {}", order),
+      Err(_) => println!("would send {:?}", order),
+    },
+  }
+}
+
+/// the channel writes the requests. The answers are read from a clone of
+/// its socket: the channel gives nothing when a read fails, which does not
+/// tell a slow proxy from a closed connection or an answer it cannot parse
+struct Connection {
+  channel: Channel<CommandRequest,CommandResponse>,
+  socket:  StdUnixStream,
+  /// what was read past the last answer
+  buffer:  Vec<u8>,
+}
+
+impl Connection {
+  fn write_message(&mut self, request: &CommandRequest) -> bool {
+    self.channel.write_message(request)
+  }
+
+  /// the next answer, the messages end with a null byte. Fails with
+  /// WouldBlock or TimedOut when sozu did not answer within the order
+  /// timeout, UnexpectedEof when it closed the connection, and InvalidData
+  /// when the answer is not one of sozu 0.11
+  fn read_message(&mut self) -> io::Result<CommandResponse> {
+    loop {
+      if let Some(end) = self.buffer.iter().position(|&b| b == 0) {
+        let message: Vec<u8> = self.buffer.drain(..end + 1).collect();
+        return serde_json::from_slice(&message[..end]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+      }
+      if self.buffer.len() >= MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the answer is too large"));
+      }
+
+      let mut data = [0; 4096];
+      match self.socket.read(&mut data) {
+        Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sozu closed the connection")),
+        Ok(read) => self.buffer.extend_from_slice(&data[..read]),
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+        Err(e) => return Err(e),
+      }
+    }
+  }
+}
+
+/// why a read got no answer. A closed connection is reconnected by the callers
+fn read_error(error: &io::Error) -> SozuError {
+  match error.kind() {
+    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => SozuError::Timeout(TIMEOUTS.with(|timeouts| timeouts.get().0)),
+    io::ErrorKind::InvalidData => SozuError::UnsupportedProtocol,
+    _ => SozuError::Disconnected,
+  }
+}
+
+/// only the JSON command protocol of sozu 0.11 is supported. Newer proxies
+/// answer in another format, which cannot be parsed: this is detected with
+/// a harmless request, instead of failing on the first order. Returns the
+/// ids of the running workers
+fn check_protocol(channel: &mut Connection) -> Result<Vec<String>, SozuError> {
+  let id = generate_id();
+  if !channel.write_message(&CommandRequest::new(id.clone(), CommandRequestData::ListWorkers, None)) {
+    return Err(SozuError::Disconnected);
+  }
+
+  match channel.read_message() {
+    Ok(message) => if message.id == id {
+      debug!("sozu speaks the command protocol version {}", message.version);
+      match message.data {
+        Some(CommandResponseData::Workers(workers)) => Ok(workers.iter()
+          .filter(|worker| worker.run_state == RunState::Running)
+          .map(|worker| worker.id.to_string())
+          .collect()),
+        _ => Ok(Vec::new()),
+      }
+    } else {
+      Err(SozuError::UnsupportedProtocol)
+    },
+    Err(e) => Err(read_error(&e)),
+  }
+}
+
+/// sets how long sozu has to answer an order, and how long an order can take
+/// with its processing notifications and reconnections. Applies to the next connections
+pub fn set_timeouts(order_timeout: Duration, order_deadline: Duration) {
+  TIMEOUTS.with(|timeouts| timeouts.set((order_timeout, order_deadline)));
+}
+
+fn open(path: &str) -> io::Result<Connection> {
+  let stream = UnixStream::connect(path)?;
+
+  // the answers are read in blocking mode, the timeout is set on the socket
+  // so a read gives up if sozu does not answer
+  let timeout = TIMEOUTS.with(|timeouts| timeouts.get().0);
+  let std_stream = unsafe { StdUnixStream::from_raw_fd(stream.as_raw_fd()) };
+  let socket = std_stream.try_clone();
+  std_stream.into_raw_fd();
+  let socket = socket?;
+  socket.set_read_timeout(Some(timeout))?;
+
+  let mut channel: Channel<CommandRequest,CommandResponse> = Channel::new(stream, 10000, MAX_MESSAGE_SIZE);
+  channel.set_blocking(true);
+  Ok(Connection { channel, socket, buffer: Vec::new() })
+}
+
+/// command channel that records the changes made to sozu's configuration.
+/// Unless committed, they are undone when it is dropped, including when
+/// unwinding from a panic, so a failed run does not leave a challenge
+/// backend or a partial installation behind
+pub struct Transaction {
+  channel: Sozu,
+  undo:    Vec<Undo>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Undo {
+  Application(String),
+  Backend(String, String, SocketAddr),
+  HttpFront(SocketAddr, String, String, String),
+  HttpsFront(SocketAddr, String, String, String),
+  Certificate(SocketAddr, Vec<u8>, Vec<String>),
+}
+
+impl Transaction {
+  pub fn new(channel: Sozu) -> Transaction {
+    Transaction { channel, undo: Vec::new() }
+  }
+
+  pub fn add_application(&mut self, app_id: &str) -> Result<(), SozuError> {
+    add_application(&mut self.channel, app_id)?;
+    self.undo.push(Undo::Application(app_id.to_string()));
+    Ok(())
+  }
+
+  pub fn set_up_proxying(&mut self, app_id: &str, backend_id: &str, server_address: SocketAddr) -> Result<(), SozuError> {
+    set_up_proxying(&mut self.channel, app_id, backend_id, server_address)?;
+    self.undo.push(Undo::Backend(app_id.to_string(), backend_id.to_string(), server_address));
+    Ok(())
+  }
+
+  pub fn remove_proxying(&mut self, app_id: &str, backend_id: &str, server_address: SocketAddr) -> Result<(), SozuError> {
+    remove_proxying(&mut self.channel, app_id, backend_id, server_address)?;
+    let undo = Undo::Backend(app_id.to_string(), backend_id.to_string(), server_address);
+    self.undo.retain(|u| *u != undo);
+    Ok(())
+  }
+
+  pub fn add_challenge_front(&mut self, frontend: &SocketAddr, app_id: &str, hostname: &str, path_begin: &str) -> Result<(), SozuError> {
+    add_challenge_front(&mut self.channel, frontend, app_id, hostname, path_begin)?;
+    self.undo.push(Undo::HttpFront(frontend.clone(), app_id.to_string(), hostname.to_string(), path_begin.to_string()));
+    Ok(())
+  }
+
+  pub fn remove_challenge_front(&mut self, frontend: &SocketAddr, app_id: &str, hostname: &str, path_begin: &str) -> Result<(), SozuError> {
+    remove_challenge_front(&mut self.channel, frontend, app_id, hostname, path_begin)?;
+    let undo = Undo::HttpFront(frontend.clone(), app_id.to_string(), hostname.to_string(), path_begin.to_string());
+    self.undo.retain(|u| *u != undo);
+    Ok(())
+  }
+
+  pub fn remove_certificate(&mut self, frontend: &SocketAddr, fingerprint: Vec<u8>, names: &[String]) -> Result<(), SozuError> {
+    remove_certificate(&mut self.channel, frontend, fingerprint.clone(), names)?;
+    self.undo.retain(|u| match *u {
+      Undo::Certificate(ref f, ref fp, _) => f != frontend || *fp != fingerprint,
+      _ => true,
+    });
+    Ok(())
+  }
+
+  /// a replaced certificate cannot be restored, only the new one is removed on rollback
+  pub fn add_certificate(&mut self, frontend: &SocketAddr, hostnames: &[&str], certificate_path: &str, chain_path: &str,
+    key_path: &str, key_passphrase: Option<&[u8]>, old_fingerprint: Option<Vec<u8>>) -> Result<(), SozuError> {
+    add_certificate(&mut self.channel, frontend, hostnames, certificate_path, chain_path, key_path, key_passphrase, old_fingerprint)?;
+    if let Some(fingerprint) = Config::load_file_bytes(certificate_path).ok().and_then(|pem| calculate_fingerprint(&pem)) {
+      self.undo.push(Undo::Certificate(frontend.clone(), fingerprint, hostnames.iter().map(|h| h.to_string()).collect()));
+    }
+    Ok(())
+  }
+
+  /// the fronts are sent in one batch, those that were added are rolled back
+  /// on failure. The errors are by hostname
+  pub fn add_https_fronts(&mut self, frontend: &SocketAddr, app_id: &str, hostnames: &[&str],
+    path_begin: &str) -> Result<(), Vec<(String, SozuError)>> {
+    let orders: Vec<ProxyRequestData> = hostnames.iter().map(|hostname| https_front_order(frontend, app_id, hostname, path_begin, true)).collect();
+    let mut errors = Vec::new();
+
+    for (hostname, result) in hostnames.iter().zip(order_commands(&mut self.channel, &orders)) {
+      match result {
+        Ok(()) => self.undo.push(Undo::HttpsFront(frontend.clone(), app_id.to_string(), hostname.to_string(), path_begin.to_string())),
+        Err(e) => errors.push((hostname.to_string(), e)),
+      }
+    }
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+  }
+
+  /// keeps the changes
+  pub fn commit(&mut self) {
+    self.undo.clear();
+  }
+
+  /// undoes the recorded changes, the latest first
+  pub fn rollback(&mut self) {
+    if !self.undo.is_empty() {
+      warn!("rolling back {} changes to sozu's configuration", self.undo.len());
+    }
+
+    while let Some(undo) = self.undo.pop() {
+      let result = match undo {
+        Undo::Application(ref app_id) => remove_application(&mut self.channel, app_id),
+        Undo::Backend(ref app_id, ref backend_id, address) => remove_proxying(&mut self.channel, app_id, backend_id, address),
+        Undo::HttpFront(ref frontend, ref app_id, ref hostname, ref path_begin) =>
+          remove_challenge_front(&mut self.channel, frontend, app_id, hostname, path_begin),
+        Undo::HttpsFront(ref frontend, ref app_id, ref hostname, ref path_begin) =>
+          remove_https_front(&mut self.channel, frontend, app_id, hostname, path_begin),
+        Undo::Certificate(ref frontend, ref fingerprint, ref names) => remove_certificate(&mut self.channel, frontend, fingerprint.clone(), names),
+      };
+      if let Err(e) = result {
+        error!("could not undo {:?}: {}", undo, e);
+      }
+    }
+  }
+}
+
+impl Deref for Transaction {
+  type Target = Sozu;
+
+  fn deref(&self) -> &Self::Target {
+    &self.channel
+  }
+}
+
+impl DerefMut for Transaction {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.channel
+  }
+}
+
+impl Drop for Transaction {
+  fn drop(&mut self) {
+    self.rollback();
+  }
+}
+
+pub fn generate_id() -> String {
+  let s: String = iter::repeat(()).map(|()| thread_rng().sample(Alphanumeric)).take(6).map(char::from).collect();
+  format!("ID-{}", s)
+}
+
+pub fn generate_app_id(app_id: &str) -> String {
+  let s: String = iter::repeat(()).map(|()| thread_rng().sample(Alphanumeric)).take(6).map(char::from).collect();
+  format!("{}-ACME-{}", app_id, s)
+}
+
+/// the challenge backend id is random, so it cannot be mistaken for a real backend
+pub fn generate_backend_id() -> String {
+  let s: String = iter::repeat(()).map(|()| thread_rng().sample(Alphanumeric)).take(12).map(char::from).collect();
+  format!("acme-challenge-{}", s)
+}
+
+/// whether every worker knows the application's configuration
+pub fn application_exists(channel: &mut Sozu, app_id: &str) -> bool {
+  let answers = match query_command(channel, Query::Applications(QueryApplicationType::AppId(app_id.to_string()))) {
+    Some(answers) => answers,
+    None => return false,
+  };
+
+  !answers.is_empty() && answers.values().all(|answer| match *answer {
+    QueryAnswer::Applications(ref applications) => applications.iter().any(|application| application.configuration.is_some()),
+    _ => false,
+  })
+}
+
+/// declares the application with sozu's defaults: round robin, no sticky
+/// sessions, no HTTPS redirection, no proxy protocol
+pub fn add_application(channel: &mut Sozu, app_id: &str) -> Result<(), SozuError> {
+  order_command(channel, ProxyRequestData::AddApplication(Application {
+    app_id: String::from(app_id),
+    sticky_session: false,
+    https_redirect: false,
+    proxy_protocol: None,
+    load_balancing_policy: LoadBalancingAlgorithms::default(),
+    answer_503: None,
+  }))
+}
+
+pub fn remove_application(channel: &mut Sozu, app_id: &str) -> Result<(), SozuError> {
+  order_command(channel, ProxyRequestData::RemoveApplication(String::from(app_id)))
+}
+
+pub fn set_up_proxying(channel: &mut Sozu, app_id: &str, backend_id: &str,
+  server_address: SocketAddr) -> Result<(), SozuError> {
+  order_command(channel, ProxyRequestData::AddBackend(Backend {
+    app_id: String::from(app_id),
+    backend_id: String::from(backend_id),
+    address: server_address,
+    load_balancing_parameters: None,
+    sticky_id: None,
+    backup: None,
+  }))
+}
+
+pub fn remove_proxying(channel: &mut Sozu, app_id: &str, backend_id: &str,
+  server_address: SocketAddr) -> Result<(), SozuError> {
+  order_command(channel, ProxyRequestData::RemoveBackend(RemoveBackend {
+    app_id: String::from(app_id),
+    backend_id: String::from(backend_id),
+    address: server_address,
+  }))
+}
+
+pub fn add_challenge_front(channel: &mut Sozu, frontend: &SocketAddr, app_id: &str, hostname: &str,
+  path_begin: &str) -> Result<(), SozuError> {
+  order_command(channel, ProxyRequestData::AddHttpFront(HttpFront {
+    address: frontend.clone(),
+    app_id: String::from(app_id),
+    hostname: String::from(hostname),
+    path_begin: String::from(path_begin)
+  }))
+}
+
+pub fn remove_challenge_front(channel: &mut Sozu, frontend: &SocketAddr, app_id: &str, hostname: &str,
+  path_begin: &str) -> Result<(), SozuError> {
+  order_command(channel, ProxyRequestData::RemoveHttpFront(HttpFront {
+    address: frontend.clone(),
+    app_id: String::from(app_id),
+    hostname: String::from(hostname),
+    path_begin: String::from(path_begin)
+  }))
+}
+
+pub fn add_certificate(channel: &mut Sozu,
+  frontend: &SocketAddr, hostnames: &[&str],
+  certificate_path: &str, chain_path: &str, key_path: &str, key_passphrase: Option<&[u8]>,
+  old_fingerprint: Option<Vec<u8>>) -> Result<(), SozuError> {
+
+  let certificate = Config::load_file(certificate_path)
+    .map_err(|e| SozuError::Certificate(format!("could not load certificate: {:?}", e)))?;
+  // sozu cannot read an encrypted key, it gets it decrypted
+  let key = match key_passphrase {
+    None => Config::load_file(key_path).map_err(|e| format!("{:?}", e)),
+    Some(passphrase) => certificate::load_key(key_path, Some(passphrase))
+      .and_then(|pkey| pkey.ok_or_else(|| format!("{} does not exist", key_path)))
+      .and_then(|pkey| certificate::key_to_pem(&pkey, "pkcs8", None))
+      .map(|pem| String::from_utf8_lossy(&pem).into_owned()),
+  }.map_err(|e| SozuError::Certificate(format!("could not load key: {}", e)))?;
+  let certificate_chain = Config::load_file(chain_path).map(split_certificate_chain)
+    .map_err(|e| SozuError::Certificate(format!("could not load certificate chain: {:?}", e)))?;
+
+  match old_fingerprint {
+    None => return order_command(channel, ProxyRequestData::AddCertificate(AddCertificate {
+      front: frontend.clone(),
+      certificate: CertificateAndKey {
+        certificate,
+        certificate_chain,
+        key
+      },
+      names: hostnames.iter().map(|h| h.to_string()).collect(),
+    })),
+    Some(f) => return order_command(channel, ProxyRequestData::ReplaceCertificate(ReplaceCertificate {
+      front: frontend.clone(),
+      new_certificate: CertificateAndKey {
+        certificate,
+        certificate_chain,
+        key
+      },
+      old_fingerprint: CertFingerprint(f),
+      old_names: hostnames.iter().map(|h| h.to_string()).collect(),
+      new_names: hostnames.iter().map(|h| h.to_string()).collect(),
+    })),
+  }
+}
+
+fn https_front_order(frontend: &SocketAddr, app_id: &str, hostname: &str, path_begin: &str, add: bool) -> ProxyRequestData {
+  let front = HttpFront {
+    address: frontend.clone(),
+    app_id: String::from(app_id),
+    hostname: String::from(hostname),
+    path_begin: String::from(path_begin),
+  };
+
+  if add { ProxyRequestData::AddHttpsFront(front) } else { ProxyRequestData::RemoveHttpsFront(front) }
+}
+
+/// whether every worker routes the challenge path to the application's backend
+pub fn challenge_front_ready(channel: &mut Sozu, frontend: &SocketAddr, app_id: &str,
+  hostname: &str, path_begin: &str) -> bool {
+  let answers = match query_command(channel, Query::Applications(QueryApplicationType::AppId(app_id.to_string()))) {
+    Some(answers) => answers,
+    None => return false,
+  };
+
+  !answers.is_empty() && answers.values().all(|answer| match *answer {
+    QueryAnswer::Applications(ref applications) => applications.iter().any(|application| {
+      !application.backends.is_empty() && application.http_frontends.iter().any(|front| {
+        front.address == *frontend && front.hostname == hostname && front.path_begin == path_begin
+      })
+    }),
+    _ => false,
+  })
+}
+
+/// polls sozu's state until the configuration change is visible, as orders
+/// are acknowledged before every worker applied them
+pub fn wait_for_configuration<F>(channel: &mut Sozu, mut applied: F) -> bool
+  where F: FnMut(&mut Sozu) -> bool {
+  let deadline = Instant::now() + CONFIGURATION_TIMEOUT;
+
+  loop {
+    signal::check_shutdown();
+    if applied(channel) {
+      return true;
+    }
+    if Instant::now() >= deadline {
+      error!("sozu did not apply the configuration after {} seconds", CONFIGURATION_TIMEOUT.as_secs());
+      return false;
+    }
+    thread::sleep(Duration::from_millis(50));
+  }
+}
+
+/// hostname and path of the application's HTTPS fronts on this listener
+pub fn application_https_fronts(channel: &mut Sozu, app_id: &str, frontend: &SocketAddr) -> Vec<(String, String)> {
+  let mut fronts = Vec::new();
+
+  let answers = match query_command(channel, Query::Applications(QueryApplicationType::AppId(app_id.to_string()))) {
+    Some(answers) => answers,
+    None => return fronts,
+  };
+
+  for answer in answers.values() {
+    if let QueryAnswer::Applications(ref applications) = *answer {
+      for application in applications.iter() {
+        for front in application.https_frontends.iter().filter(|front| front.address == *frontend) {
+          let front = (front.hostname.clone(), front.path_begin.clone());
+          if !fronts.contains(&front) {
+            fronts.push(front);
+          }
+        }
+      }
+    }
+  }
+
+  fronts
+}
+
+/// application id, listener and hostname of every HTTPS front
+pub fn https_fronts(channel: &mut Sozu) -> Option<Vec<(String, SocketAddr, String)>> {
+  let answers = query_command(channel, Query::ApplicationsHashes)?;
+
+  let mut app_ids: Vec<String> = Vec::new();
+  for answer in answers.values() {
+    if let QueryAnswer::ApplicationsHashes(ref hashes) = *answer {
+      for app_id in hashes.keys() {
+        if !app_ids.contains(app_id) {
+          app_ids.push(app_id.clone());
+        }
+      }
+    }
+  }
+
+  let mut fronts = Vec::new();
+  for app_id in app_ids {
+    let answers = query_command(channel, Query::Applications(QueryApplicationType::AppId(app_id.clone())))?;
+    for answer in answers.values() {
+      if let QueryAnswer::Applications(ref applications) = *answer {
+        for front in applications.iter().flat_map(|application| application.https_frontends.iter()) {
+          let front = (app_id.clone(), front.address, front.hostname.clone());
+          if !fronts.contains(&front) {
+            fronts.push(front);
+          }
+        }
+      }
+    }
+  }
+
+  Some(fronts)
+}
+
+/// hostnames of the application's HTTP and HTTPS fronts
+pub fn application_hostnames(channel: &mut Sozu, app_id: &str) -> Vec<String> {
+  let mut hostnames = Vec::new();
+
+  let answers = match query_command(channel, Query::Applications(QueryApplicationType::AppId(app_id.to_string()))) {
+    Some(answers) => answers,
+    None => return hostnames,
+  };
+
+  for answer in answers.values() {
+    if let QueryAnswer::Applications(ref applications) = *answer {
+      for application in applications.iter() {
+        for front in application.http_frontends.iter().chain(application.https_frontends.iter()) {
+          if !hostnames.contains(&front.hostname) {
+            hostnames.push(front.hostname.clone());
+          }
+        }
+      }
+    }
+  }
+
+  hostnames
+}
+
+/// hostnames of the application's fronts that are covered by the wildcard domain
+pub fn hostnames_under_wildcard(channel: &mut Sozu, app_id: &str, wildcard: &str) -> Vec<String> {
+  let suffix = &wildcard[1..];
+  let mut hostnames = Vec::new();
+
+  let answers = match query_command(channel, Query::Applications(QueryApplicationType::AppId(app_id.to_string()))) {
+    Some(answers) => answers,
+    None => return hostnames,
+  };
+
+  for answer in answers.values() {
+    if let QueryAnswer::Applications(ref applications) = *answer {
+      for application in applications.iter() {
+        for front in application.http_frontends.iter().chain(application.https_frontends.iter()) {
+          let label = front.hostname.trim_end_matches(suffix);
+          if front.hostname.ends_with(suffix) && !label.is_empty() && !label.contains('.')
+            && !hostnames.contains(&front.hostname) {
+            hostnames.push(front.hostname.clone());
+          }
+        }
+      }
+    }
+  }
+
+  hostnames
+}
+
+/// the hostnames of the HTTPS fronts the certificate of these domains needs
+/// on the frontend, that the application does not have yet: one front per
+/// domain, and a wildcard certificate is attached to every hostname of the
+/// application it covers. Used by the runs, the dry runs and the plans alike
+pub fn missing_https_fronts(channel: &mut Sozu, app_id: &str, frontend: &SocketAddr, domains: &[&str], path_begin: &str) -> Vec<String> {
+  let mut hostnames = Vec::new();
+  for domain in domains.iter() {
+    if domain.starts_with("*.") {
+      hostnames.extend(hostnames_under_wildcard(channel, app_id, domain));
+    } else {
+      hostnames.push(domain.to_string());
+    }
+  }
+
+  let existing = application_https_fronts(channel, app_id, frontend);
+  hostnames.into_iter()
+    .filter(|hostname| !existing.contains(&(hostname.clone(), path_begin.to_string())))
+    .collect()
+}
+
+pub fn remove_https_front(channel: &mut Sozu, frontend: &SocketAddr, app_id: &str, hostname: &str,
+  path_begin: &str) -> Result<(), SozuError> {
+  order_command(channel, https_front_order(frontend, app_id, hostname, path_begin, false))
+}
+
+pub fn remove_certificate(channel: &mut Sozu, frontend: &SocketAddr, fingerprint: Vec<u8>,
+  hostnames: &[String]) -> Result<(), SozuError> {
+  order_command(channel, ProxyRequestData::RemoveCertificate(RemoveCertificate {
+    front: frontend.clone(),
+    fingerprint: CertFingerprint(fingerprint),
+    names: hostnames.to_vec(),
+  }))
+}
+
+/// fingerprint of the certificate sozu uses for this domain on the frontend
+pub fn certificate_fingerprint(channel: &mut Sozu, frontend: &SocketAddr, domain: &str) -> Option<Vec<u8>> {
+  let answers = query_command(channel, Query::Certificates(QueryCertificateType::Domain(domain.to_string())))?;
+
+  for answer in answers.values() {
+    if let QueryAnswer::Certificates(QueryAnswerCertificate::Domain(ref fronts)) = *answer {
+      if let Some(&Some((_, ref fingerprint))) = fronts.get(frontend) {
+        return Some(fingerprint.clone());
+      }
+    }
+  }
+
+  None
+}
+
+/// every certificate sozu knows: frontend, domain and fingerprint
+pub fn certificates(channel: &mut Sozu) -> Option<Vec<(SocketAddr, String, Vec<u8>)>> {
+  let answers = query_command(channel, Query::Certificates(QueryCertificateType::All))?;
+
+  // each worker answers with its own view, they should be identical
+  let mut certificates = Vec::new();
+  for answer in answers.values() {
+    if let QueryAnswer::Certificates(QueryAnswerCertificate::All(ref fronts)) = *answer {
+      for (front, domains) in fronts.iter() {
+        for (domain, fingerprint) in domains.iter() {
+          let certificate = (front.clone(), domain.clone(), fingerprint.clone());
+          if !certificates.contains(&certificate) {
+            certificates.push(certificate);
+          }
+        }
+      }
+    }
+  }
+
+  certificates.sort_by(|a, b| (a.0.to_string(), &a.1).cmp(&(b.0.to_string(), &b.1)));
+  Some(certificates)
+}
+
+/// certificate (in PEM format) and names for a fingerprint
+pub fn certificate_by_fingerprint(channel: &mut Sozu, fingerprint: &[u8]) -> Option<(String, Vec<String>)> {
+  let answers = query_command(channel, Query::Certificates(QueryCertificateType::Fingerprint(fingerprint.to_vec())))?;
+
+  for answer in answers.values() {
+    if let QueryAnswer::Certificates(QueryAnswerCertificate::Fingerprint(Some(ref certificate))) = *answer {
+      return Some(certificate.clone());
+    }
+  }
+
+  None
+}
+
+/// the answers of every worker of every proxy, the workers of the
+/// proxies are told apart by prefixing their id with the command socket.
+/// It fails if one of the running workers did not answer
+pub fn query_command(channel: &mut Sozu, query: Query) -> Option<BTreeMap<String, QueryAnswer>> {
+  let several = channel.proxies.len() > 1;
+  let mut all_answers = BTreeMap::new();
+
+  for proxy in channel.proxies.iter_mut() {
+    let result = execute(proxy, CommandRequestData::Proxy(ProxyRequestData::Query(query.clone())));
+    let (socket, workers) = (&proxy.socket, &proxy.workers);
+    let message = match result {
+      Ok(message) => message,
+      Err(e) => {
+        error!("could not execute query on {}: {}", socket, e);
+        return None;
+      }
+    };
+
+    match message.status {
+      CommandStatus::Error => {
+        error!("could not execute query on {}: {}", socket, message.message);
+        return None;
+      },
+      _ => match message.data {
+        Some(CommandResponseData::Query(answers)) => {
+          let missing: Vec<String> = workers.iter().filter(|worker| !answers.contains_key(*worker)).cloned().collect();
+          if !missing.is_empty() {
+            error!("could not execute query on {}: {}", socket, SozuError::Workers(missing));
+            return None;
+          }
+          for (worker, answer) in answers {
+            let key = if several { format!("{} {}", socket, worker) } else { worker };
+            all_answers.insert(key, answer);
+          }
+        },
+        data => {
+          error!("unexpected answer to query on {}: {:?}", socket, data);
+          return None;
+        }
+      }
+    }
+  }
+
+  Some(all_answers)
+}
+
+/// sends the order to every proxy, it succeeds if all of them applied it
+pub fn order_command(channel: &mut Sozu, order: ProxyRequestData) -> Result<(), SozuError> {
+  if channel.dry_run {
+    print_dry_run(&order);
+    return Ok(());
+  }
+
+  let results = broadcast(channel, CommandRequestData::Proxy(order.clone())).into_iter().map(|(socket, result)| {
+    let result = order_result(&socket, &order, result);
+    (socket, result)
+  }).collect();
+
+  merge_results(results)
+}
+
+/// sends the orders to every proxy, without waiting for each answer before
+/// writing the next order. There is one result per order
+pub fn order_commands(channel: &mut Sozu, orders: &[ProxyRequestData]) -> Vec<Result<(), SozuError>> {
+  if channel.dry_run {
+    return orders.iter().map(|order| {
+      print_dry_run(order);
+      Ok(())
+    }).collect();
+  }
+
+  let requests: Vec<CommandRequestData> = orders.iter().map(|order| CommandRequestData::Proxy(order.clone())).collect();
+  let mut results: Vec<Vec<(String, Result<(), SozuError>)>> = orders.iter().map(|_| Vec::new()).collect();
+
+  for proxy in channel.proxies.iter_mut() {
+    let mut answers = Vec::new();
+    for batch in requests.chunks(PIPELINE_DEPTH) {
+      answers.extend(execute_pipelined(proxy, batch));
+    }
+    for ((order, answer), order_results) in orders.iter().zip(answers).zip(results.iter_mut()) {
+      order_results.push((proxy.socket.clone(), order_result(&proxy.socket, order, answer)));
+    }
+  }
+
+  results.into_iter().map(merge_results).collect()
+}
+
+fn order_result(socket: &str, order: &ProxyRequestData, answer: Result<CommandResponse, SozuError>) -> Result<(), SozuError> {
+  answer.and_then(|message| logging::with_order_id(&message.id.clone(), || match message.status {
+    CommandStatus::Error => Err(SozuError::Refused(message.message)),
+    _ => {
+      match *order {
+        ProxyRequestData::AddApplication(_) => info!("application added on {}: {}", socket, message.message),
+        ProxyRequestData::RemoveApplication(_) => info!("application removed on {}: {}", socket, message.message),
+        ProxyRequestData::AddBackend(_) => info!("backend added on {}: {}", socket, message.message),
+        ProxyRequestData::RemoveBackend(_) => info!("backend removed on {}: {} ", socket, message.message),
+        ProxyRequestData::AddCertificate(_) => info!("certificate added on {}: {}", socket, message.message),
+        ProxyRequestData::RemoveCertificate(_) => info!("certificate removed on {}: {}", socket, message.message),
+        ProxyRequestData::AddHttpFront(_) => info!("front added on {}: {}", socket, message.message),
+        ProxyRequestData::RemoveHttpFront(_) => info!("front removed on {}: {}", socket, message.message),
+        _ => {
+          // do nothing for now
+        }
+      }
+      Ok(())
+    }
+  }))
+}
+
+/// has every proxy write its current configuration to this path, so the
+/// changes survive a restart
+pub fn save_state(channel: &mut Sozu, path: &str) -> Result<(), SozuError> {
+  if channel.dry_run {
+    println!("would have sozu save its state to {}", path);
+    return Ok(());
+  }
+
+  let results = broadcast(channel, CommandRequestData::SaveState { path: path.to_string() }).into_iter().map(|(socket, result)| {
+    let result = result.and_then(|message| match message.status {
+      CommandStatus::Error => Err(SozuError::Refused(message.message)),
+      _ => {
+        info!("state saved on {}: {}", socket, message.message);
+        Ok(())
+      }
+    });
+    (socket, result)
+  }).collect();
+
+  merge_results(results)
+}
+
+fn broadcast(channel: &mut Sozu, data: CommandRequestData) -> Vec<(String, Result<CommandResponse, SozuError>)> {
+  channel.proxies.iter_mut().map(|proxy| (proxy.socket.clone(), execute(proxy, data.clone()))).collect()
+}
+
+/// with a single proxy, its error is returned as is
+fn merge_results(results: Vec<(String, Result<(), SozuError>)>) -> Result<(), SozuError> {
+  let total = results.len();
+  let mut errors: Vec<(String, SozuError)> = results.into_iter()
+    .filter_map(|(socket, result)| result.err().map(|e| (socket, e)))
+    .collect();
+
+  match errors.len() {
+    0 => Ok(()),
+    1 if total == 1 => Err(errors.remove(0).1),
+    _ => Err(SozuError::Proxies(errors)),
+  }
+}
+
+/// sends the request and returns sozu's final answer, skipping the
+/// processing notifications. If the connection is lost (sozu restarted
+/// or was upgraded), the request is sent again on a new connection.
+/// sozu must answer within the order timeout, and the whole exchange,
+/// reconnections included, must end before the order deadline
+fn execute(proxy: &mut Proxy, data: CommandRequestData) -> Result<CommandResponse, SozuError> {
+  let mut id = String::new();
+  let result = exchange(proxy, &data, &mut id);
+  audit::record(&proxy.socket, &id, &data, &result);
+  result
+}
+
+/// the exchange of execute, id is the one of the last request sent
+fn exchange(proxy: &mut Proxy, data: &CommandRequestData, id: &mut String) -> Result<CommandResponse, SozuError> {
+  let deadline = Instant::now() + TIMEOUTS.with(|timeouts| timeouts.get().1);
+
+  loop {
+    *id = generate_id();
+    if proxy.channel.write_message(&CommandRequest::new(id.clone(), data.clone(), None)) {
+      loop {
+        let message = match proxy.channel.read_message() {
+          Ok(message) => message,
+          Err(e) => match read_error(&e) {
+            SozuError::Disconnected => break,
+            error => return Err(error),
+          },
+        };
+
+        // the late answer to a request that timed out, or the answer of
+        // another worker to a previous order
+        if *id != message.id {
+          late_answer(&proxy.socket, message);
+          continue;
+        }
+        match message.status {
+          CommandStatus::Processing => if Instant::now() >= deadline {
+            return Err(SozuError::Deadline);
+          },
+          _ => return Ok(message),
+        }
+      }
+    }
+
+    if Instant::now() >= deadline || !reconnect(proxy) {
+      return Err(SozuError::Disconnected);
+    }
+  }
+}
+
+/// like execute, for a batch of requests: they are all written before the
+/// answers are read, which are matched to them by id. The requests left
+/// unanswered when the connection is lost are sent again one by one
+fn execute_pipelined(proxy: &mut Proxy, requests: &[CommandRequestData]) -> Vec<Result<CommandResponse, SozuError>> {
+  let deadline = Instant::now() + TIMEOUTS.with(|timeouts| timeouts.get().1);
+  let mut results: Vec<Option<Result<CommandResponse, SozuError>>> = requests.iter().map(|_| None).collect();
+
+  let mut ids = Vec::new();
+  for data in requests.iter() {
+    let id = generate_id();
+    if !proxy.channel.write_message(&CommandRequest::new(id.clone(), data.clone(), None)) {
+      break;
+    }
+    ids.push(id);
+  }
+
+  let mut pending = ids.len();
+  while pending > 0 {
+    let message = match proxy.channel.read_message() {
+      Ok(message) => message,
+      // the requests left are sent again after a reconnection
+      Err(e) => {
+        match read_error(&e) {
+          SozuError::Disconnected => {},
+          error => for result in results.iter_mut().take(ids.len()).filter(|result| result.is_none()) {
+            *result = Some(Err(error.clone()));
+          },
+        }
+        break;
+      },
+    };
+
+    let index = match ids.iter().position(|id| *id == message.id) {
+      Some(index) if results[index].is_none() => index,
+      _ => {
+        late_answer(&proxy.socket, message);
+        continue;
+      }
+    };
+    match message.status {
+      CommandStatus::Processing => if Instant::now() >= deadline {
+        results[index] = Some(Err(SozuError::Deadline));
+        pending -= 1;
+      },
+      _ => {
+        results[index] = Some(Ok(message));
+        pending -= 1;
+      }
+    }
+  }
+
+  requests.iter().zip(results).enumerate().map(|(index, (data, result))| match result {
+    Some(result) => {
+      audit::record(&proxy.socket, &ids[index], data, &result);
+      result
+    },
+    None => execute(proxy, data.clone()),
+  }).collect()
+}
+
+/// a worker can answer after the order was acknowledged, its failure must
+/// not go unnoticed: the configuration is not the same on every worker
+fn late_answer(socket: &str, message: CommandResponse) {
+  match message.status {
+    CommandStatus::Error => error!("a worker of {} failed to apply the order {}: {}", socket, message.id, message.message),
+    _ => warn!("ignoring the answer to a previous request: {:?}", message),
+  }
+}
+
+/// replaces the channel with a new connection to the command socket,
+/// retrying with exponential backoff
+fn reconnect(proxy: &mut Proxy) -> bool {
+  let mut delay = Duration::from_millis(100);
+  for _ in 0..RECONNECT_ATTEMPTS {
+    warn!("lost the connection to {}, reconnecting in {}ms", proxy.socket, delay.as_millis());
+    thread::sleep(delay);
+
+    match open(&proxy.socket) {
+      Ok(mut channel) => {
+        info!("reconnected to {}", proxy.socket);
+        // the workers may have changed if sozu was upgraded
+        if let Ok(workers) = check_protocol(&mut channel) {
+          proxy.workers = workers;
+        }
+        proxy.channel = channel;
+        return true;
+      },
+      Err(e) => debug!("could not connect to {}: {}", proxy.socket, e),
+    }
+    delay = cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+  }
+
+  error!("could not reconnect to {} after {} attempts", proxy.socket, RECONNECT_ATTEMPTS);
+  false
+}
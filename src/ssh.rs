@@ -0,0 +1,91 @@
+//! access to a remote sozu through SSH tunnels: its command socket is
+//! forwarded locally, and the HTTP challenge server is forwarded to it
+
+use std::{
+  fs, iter, thread,
+  env,
+  io::{BufRead, BufReader},
+  net::SocketAddr,
+  path::Path,
+  process::{Child, Command, Stdio},
+  time::{Duration, Instant},
+};
+use rand::{thread_rng, Rng, distributions::Alphanumeric};
+
+/// how long we wait for ssh to set up the forwarding
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// ssh process holding a forwarding. The process is stopped, and the
+/// local socket removed, when it is dropped
+pub struct Tunnel {
+  child:            Child,
+  pub local_socket: Option<String>,
+}
+
+impl Tunnel {
+  /// forwards the command socket at this path on `destination` (`[user@]host`)
+  /// to a local socket
+  pub fn open(destination: &str, remote_socket: &str) -> Result<Tunnel, String> {
+    let suffix: String = iter::repeat(()).map(|()| thread_rng().sample(Alphanumeric)).take(8).map(char::from).collect();
+    let local_socket = env::temp_dir().join(format!("sozu-acme-{}.sock", suffix)).to_string_lossy().to_string();
+
+    let child = spawn(destination, &["-L".to_string(), format!("{}:{}", local_socket, remote_socket)])?;
+    let mut tunnel = Tunnel { child, local_socket: Some(local_socket.clone()) };
+
+    let deadline = Instant::now() + TUNNEL_TIMEOUT;
+    while !Path::new(&local_socket).exists() {
+      if let Ok(Some(status)) = tunnel.child.try_wait() {
+        return Err(format!("ssh exited with {} before forwarding {}", status, remote_socket));
+      }
+      if Instant::now() >= deadline {
+        return Err(format!("ssh did not forward {} after {} seconds", remote_socket, TUNNEL_TIMEOUT.as_secs()));
+      }
+      thread::sleep(Duration::from_millis(100));
+    }
+
+    info!("forwarding {}:{} to {}", destination, remote_socket, local_socket);
+    Ok(tunnel)
+  }
+
+  /// makes the local server reachable at the same address on `destination`,
+  /// for the backend sozu forwards the HTTP challenges to
+  pub fn reverse(destination: &str, address: &SocketAddr) -> Result<Tunnel, String> {
+    let mut child = spawn(destination, &["-R".to_string(), format!("{}:{}", address, address)])?;
+
+    // the remote command only runs once the forwarding is set up
+    let ready = child.stdout.take().and_then(|stdout| BufReader::new(stdout).lines().next()).and_then(|line| line.ok());
+    let tunnel = Tunnel { child, local_socket: None };
+    if ready.as_ref().map(|line| line.as_str()) != Some("ready") {
+      return Err(format!("ssh could not forward {} to {}", address, destination));
+    }
+
+    info!("forwarding {} to {}", address, destination);
+    Ok(tunnel)
+  }
+}
+
+/// the remote command waits for the end of its input: if we exit without
+/// dropping the tunnel, the pipe closes and ssh stops too
+fn spawn(destination: &str, forward: &[String]) -> Result<Child, String> {
+  Command::new("ssh")
+    .arg("-T")
+    .args(&["-o", "ExitOnForwardFailure=yes"])
+    .args(&["-o", "StreamLocalBindUnlink=yes"])
+    .args(forward)
+    .arg(destination)
+    .arg("echo ready; cat > /dev/null")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .map_err(|e| format!("could not run ssh: {}", e))
+}
+
+impl Drop for Tunnel {
+  fn drop(&mut self) {
+    let _ = self.child.kill();
+    let _ = self.child.wait();
+    if let Some(ref local_socket) = self.local_socket {
+      let _ = fs::remove_file(local_socket);
+    }
+  }
+}
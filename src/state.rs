@@ -0,0 +1,199 @@
+//! state persisted between runs, in the current directory next to the account keys
+
+use std::{fs::{self, File, OpenOptions}, io, thread, collections::BTreeMap, os::unix::io::AsRawFd, time::{Duration, Instant}};
+use libc;
+use serde_json;
+
+use files;
+
+pub const STATE_FILE: &str = "sozu-acme-state.json";
+
+/// held while the state file is written, as batch runs update it concurrently
+const LOCK_FILE: &str = "sozu-acme-state.json.lock";
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+  /// URL of the orders not finalized yet, by domain set
+  #[serde(default)]
+  pub orders:   BTreeMap<String, String>,
+  /// certificate profile requested for the domain set, kept for renewals
+  #[serde(default)]
+  pub profiles: BTreeMap<String, String>,
+  /// certificates installed by a previous run, renewed by the daemon
+  #[serde(default)]
+  pub certificates: BTreeMap<String, ManagedCertificate>,
+  /// domain sets changed by this run, the others are saved as they are on disk
+  #[serde(skip)]
+  changed: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ManagedCertificate {
+  /// path of the certificate
+  pub certificate: String,
+  /// command line of the run that installed it
+  pub arguments:   Vec<String>,
+  #[serde(default)]
+  pub chain:       String,
+  #[serde(default)]
+  pub key:         String,
+  /// SHA-256 fingerprint, in hexadecimal
+  #[serde(default)]
+  pub fingerprint: String,
+  /// account the certificate was ordered with
+  #[serde(default)]
+  pub email:       String,
+  #[serde(default)]
+  pub directory:   String,
+  #[serde(default)]
+  pub order_url:   String,
+  /// path the HTTPS fronts were added with, to remove them
+  #[serde(default)]
+  pub path_begin:  String,
+  /// first issuance, RFC 3339
+  #[serde(default)]
+  pub issued_at:   String,
+  /// latest renewal, RFC 3339
+  #[serde(default)]
+  pub renewed_at:  Option<String>,
+}
+
+impl State {
+  /// an absent or unreadable state file gives an empty state
+  pub fn load() -> State {
+    match fs::read_to_string(STATE_FILE) {
+      Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+        warn!("could not parse {}: {}", STATE_FILE, e);
+        State::default()
+      }),
+      Err(_) => State::default(),
+    }
+  }
+
+  /// only the domain sets changed by this run are written, over the current
+  /// content of the file, so concurrent runs do not undo each other
+  pub fn save(&self) -> bool {
+    let _lock = match Lock::acquire() {
+      Some(lock) => lock,
+      None => {
+        error!("could not lock {}", STATE_FILE);
+        return false;
+      }
+    };
+
+    let mut state = State::load();
+    for key in self.changed.iter() {
+      copy_entry(&self.orders, &mut state.orders, key);
+      copy_entry(&self.profiles, &mut state.profiles, key);
+      copy_entry(&self.certificates, &mut state.certificates, key);
+    }
+
+    let data = match serde_json::to_string_pretty(&state) {
+      Ok(data) => data,
+      Err(e) => {
+        error!("could not serialize the state: {}", e);
+        return false;
+      }
+    };
+
+    // replaced atomically so a crash cannot leave a truncated state, and
+    // only readable by its owner: it holds the command lines of the runs
+    match files::write(STATE_FILE, data.as_bytes(), Some(0o600), &files::Owner { uid: None, gid: None }) {
+      Ok(()) => true,
+      Err(e) => {
+        error!("could not save {}: {}", STATE_FILE, e);
+        false
+      }
+    }
+  }
+
+  pub fn pending_order(&self, domains: &[&str]) -> Option<&String> {
+    self.orders.get(&domain_set(domains))
+  }
+
+  pub fn set_pending_order(&mut self, domains: &[&str], order_url: &str) {
+    self.changed.push(domain_set(domains));
+    self.orders.insert(domain_set(domains), order_url.to_string());
+  }
+
+  pub fn profile(&self, domains: &[&str]) -> Option<&String> {
+    self.profiles.get(&domain_set(domains))
+  }
+
+  pub fn set_profile(&mut self, domains: &[&str], profile: &str) {
+    self.changed.push(domain_set(domains));
+    self.profiles.insert(domain_set(domains), profile.to_string());
+  }
+
+  /// a renewal keeps the date of the first issuance
+  pub fn set_managed(&mut self, domains: &[&str], mut certificate: ManagedCertificate) {
+    if let Some(previous) = self.certificates.get(&domain_set(domains)) {
+      if !previous.issued_at.is_empty() {
+        certificate.renewed_at = Some(certificate.issued_at.clone());
+        certificate.issued_at = previous.issued_at.clone();
+      }
+    }
+    self.changed.push(domain_set(domains));
+    self.certificates.insert(domain_set(domains), certificate);
+  }
+
+  /// domain set and certificate covering this domain
+  pub fn managed(&self, domain: &str) -> Option<(&String, &ManagedCertificate)> {
+    let domain = domain.to_lowercase();
+    self.certificates.iter().find(|&(domains, _)| domains.split(',').any(|d| d == domain))
+  }
+
+  pub fn remove_pending_order(&mut self, domains: &[&str]) {
+    self.changed.push(domain_set(domains));
+    self.orders.remove(&domain_set(domains));
+  }
+}
+
+fn copy_entry<T: Clone>(from: &BTreeMap<String, T>, to: &mut BTreeMap<String, T>, key: &str) {
+  match from.get(key) {
+    Some(value) => { to.insert(key.to_string(), value.clone()); },
+    None => { to.remove(key); },
+  }
+}
+
+/// flock on the lock file, released when dropped, or by the kernel when the
+/// process dies, so a killed run does not block the next ones
+struct Lock {
+  _file: File,
+}
+
+impl Lock {
+  fn acquire() -> Option<Lock> {
+    let file = match OpenOptions::new().write(true).create(true).open(LOCK_FILE) {
+      Ok(file) => file,
+      Err(e) => {
+        error!("could not open {}: {}", LOCK_FILE, e);
+        return None;
+      }
+    };
+
+    let deadline = Instant::now() + LOCK_TIMEOUT;
+    while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+      let error = io::Error::last_os_error();
+      if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+        error!("could not lock {}: {}", LOCK_FILE, error);
+        return None;
+      }
+      if Instant::now() >= deadline {
+        error!("{} is still locked after {} seconds", LOCK_FILE, LOCK_TIMEOUT.as_secs());
+        return None;
+      }
+      thread::sleep(Duration::from_millis(50));
+    }
+    Some(Lock { _file: file })
+  }
+}
+
+/// the order of the domains does not matter to the CA
+fn domain_set(domains: &[&str]) -> String {
+  let mut domains: Vec<String> = domains.iter().map(|d| d.to_lowercase()).collect();
+  domains.sort();
+  domains.dedup();
+  domains.join(",")
+}
@@ -0,0 +1,36 @@
+//! systemd service notifications (sd_notify), for the daemon
+
+use std::{env, process, os::unix::net::UnixDatagram, time::Duration};
+
+/// sends the state (`READY=1`, `WATCHDOG=1`, `STATUS=...`) to systemd, if the
+/// service runs with Type=notify. Abstract socket addresses are not supported
+pub fn notify(state: &str) {
+  let path = match env::var("NOTIFY_SOCKET") {
+    Ok(path) => path,
+    Err(_) => return,
+  };
+  if path.starts_with('@') {
+    debug!("abstract notification socket {} not supported", path);
+    return;
+  }
+
+  let result = UnixDatagram::unbound().and_then(|socket| socket.send_to(state.as_bytes(), &path));
+  if let Err(e) = result {
+    warn!("could not notify systemd on {}: {}", path, e);
+  }
+}
+
+/// how often the watchdog must be notified: half of the WatchdogSec of the
+/// service, if it applies to this process
+pub fn watchdog_interval() -> Option<Duration> {
+  if let Ok(pid) = env::var("WATCHDOG_PID") {
+    if pid.parse::<u32>().ok() != Some(process::id()) {
+      return None;
+    }
+  }
+
+  env::var("WATCHDOG_USEC").ok()
+    .and_then(|usec| usec.parse::<u64>().ok())
+    .filter(|usec| *usec > 0)
+    .map(|usec| Duration::from_micros(usec / 2))
+}
@@ -0,0 +1,54 @@
+//! how long each phase of a certificate request took, to tell a slow CA
+//! from a slow DNS hook or a slow proxy
+
+use std::{cell::RefCell, time::{Duration, Instant}};
+
+#[derive(Default)]
+struct Timings {
+  current: Option<(&'static str, Instant)>,
+  /// in the order the phases started, a phase entered again adds to its time
+  phases:  Vec<(&'static str, Duration)>,
+}
+
+thread_local! {
+  static TIMINGS: RefCell<Timings> = RefCell::new(Timings::default());
+}
+
+/// ends the current phase and starts this one: account, order, challenges,
+/// validation, signing, installation, storage
+pub fn start(phase: &'static str) {
+  TIMINGS.with(|timings| {
+    let mut timings = timings.borrow_mut();
+    stop(&mut timings);
+    timings.current = Some((phase, Instant::now()));
+  });
+}
+
+/// the phases of the run, then starts over for the next one
+pub fn take() -> Vec<(&'static str, Duration)> {
+  TIMINGS.with(|timings| {
+    let mut timings = timings.borrow_mut();
+    stop(&mut timings);
+    timings.phases.drain(..).collect()
+  })
+}
+
+fn stop(timings: &mut Timings) {
+  if let Some((phase, started)) = timings.current.take() {
+    let elapsed = started.elapsed();
+    match timings.phases.iter_mut().find(|&&mut (name, _)| name == phase) {
+      Some(&mut (_, ref mut total)) => *total += elapsed,
+      None => timings.phases.push((phase, elapsed)),
+    }
+  }
+}
+
+pub fn seconds(duration: Duration) -> f64 {
+  duration.as_secs() as f64 + f64::from(duration.subsec_millis()) / 1000.0
+}
+
+/// `account 0.412s, order 0.803s, ...`
+pub fn summary(phases: &[(&'static str, Duration)]) -> String {
+  phases.iter().map(|&(phase, duration)| format!("{} {:.3}s", phase, seconds(duration)))
+    .collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,73 @@
+//! copies the issued certificate to HashiCorp Vault's KV secrets engine, for
+//! the fleets that distribute their secrets through Vault
+
+use ureq;
+use serde_json::{self, Value};
+
+pub enum Auth {
+  Token(String),
+  /// logs in with the AppRole auth method before each write
+  AppRole { role_id: String, secret_id: String },
+}
+
+pub struct Vault {
+  /// like https://vault.example.com:8200
+  pub address:    String,
+  /// mount path of the KV engine
+  pub mount:      String,
+  /// path of the secret in the engine
+  pub path:       String,
+  /// 1 or 2, the KV engines have different APIs
+  pub kv_version: u8,
+  pub auth:       Auth,
+}
+
+impl Vault {
+  /// writes the PEM files in the secret's `certificate`, `chain`, `fullchain`
+  /// and `key` keys. Without key (with a CSR), `key` is not written
+  pub fn store(&self, certificate: &str, chain: &str, key: Option<&str>) -> Result<(), String> {
+    let token = match self.auth {
+      Auth::Token(ref token) => token.clone(),
+      Auth::AppRole { ref role_id, ref secret_id } => self.login(role_id, secret_id)?,
+    };
+
+    let mut data = json!({
+      "certificate": certificate,
+      "chain": chain,
+      "fullchain": format!("{}\n{}", certificate.trim_end(), chain),
+    });
+    if let Some(key) = key {
+      data["key"] = Value::String(key.to_string());
+    }
+
+    let mount = self.mount.trim_matches('/');
+    let path = self.path.trim_matches('/');
+    let (url, body) = if self.kv_version == 1 {
+      (format!("{}/v1/{}/{}", self.address.trim_end_matches('/'), mount, path), data)
+    } else {
+      (format!("{}/v1/{}/data/{}", self.address.trim_end_matches('/'), mount, path), json!({ "data": data }))
+    };
+
+    let res = ureq::post(&url).set("X-Vault-Token", &token).send_string(&body.to_string());
+    if res.synthetic() || res.error() {
+      let status = res.status_line().to_string();
+      return Err(format!("could not write {} to Vault: {}: {}", path, status, res.into_string().unwrap_or_default()));
+    }
+    info!("stored the certificate in Vault at {}/{}", mount, path);
+    Ok(())
+  }
+
+  /// the client token of the AppRole
+  fn login(&self, role_id: &str, secret_id: &str) -> Result<String, String> {
+    let url = format!("{}/v1/auth/approle/login", self.address.trim_end_matches('/'));
+    let res = ureq::post(&url).send_string(&json!({ "role_id": role_id, "secret_id": secret_id }).to_string());
+    if res.synthetic() || res.error() {
+      return Err(format!("could not log in to Vault with the AppRole {}: {}", role_id, res.status_line()));
+    }
+
+    let body: Value = res.into_string().map_err(|e| e.to_string())
+      .and_then(|body| serde_json::from_str(&body).map_err(|e| e.to_string()))?;
+    body["auth"]["client_token"].as_str().map(|token| token.to_string())
+      .ok_or_else(|| "the Vault login answer has no client token".to_string())
+  }
+}
@@ -0,0 +1,97 @@
+//! notices the changes of the daemon's batch file, for the orchestration
+//! tools that rewrite files instead of sending SIGHUP
+
+use std::{thread, path::Path, time::Duration};
+
+use signal;
+
+/// writes often come in bursts, the reload waits for them to settle
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// watches the file in a thread, with inotify: the events of the file's
+/// directory are read, so a file replaced by a rename is noticed too.
+/// A change requests a reload like SIGHUP
+#[cfg(target_os = "linux")]
+pub fn watch(path: &str) -> Result<(), String> {
+  use std::{io, mem, ptr, ffi::CString, os::unix::ffi::OsStrExt};
+  use libc;
+
+  let path = Path::new(path).to_path_buf();
+  let directory = match path.parent() {
+    Some(directory) if !directory.as_os_str().is_empty() => directory.to_path_buf(),
+    _ => Path::new(".").to_path_buf(),
+  };
+  let name = path.file_name().ok_or_else(|| format!("{} is not a file", path.display()))?.as_bytes().to_vec();
+  let c_directory = CString::new(directory.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+
+  let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+  if fd < 0 {
+    return Err(format!("could not watch {}: {}", path.display(), io::Error::last_os_error()));
+  }
+  let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_DELETE;
+  if unsafe { libc::inotify_add_watch(fd, c_directory.as_ptr(), mask) } < 0 {
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(fd); }
+    return Err(format!("could not watch {}: {}", directory.display(), error));
+  }
+
+  thread::spawn(move || {
+    let mut buffer = [0u8; 4096];
+    loop {
+      let read = unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+      if read <= 0 {
+        error!("stopped watching {}: {}", path.display(), io::Error::last_os_error());
+        break;
+      }
+
+      // a read returns whole events: the header, then the name padded with zeros
+      let mut offset = 0;
+      let mut changed_file = false;
+      while offset + mem::size_of::<libc::inotify_event>() <= read as usize {
+        let event: libc::inotify_event = unsafe { ptr::read_unaligned(buffer.as_ptr().add(offset) as *const libc::inotify_event) };
+        let start = offset + mem::size_of::<libc::inotify_event>();
+        let end = start + event.len as usize;
+        if end > read as usize {
+          break;
+        }
+        changed_file |= buffer[start..end].split(|&b| b == 0).next() == Some(&name[..]);
+        offset = end;
+      }
+      if changed_file {
+        changed(&path);
+      }
+    }
+  });
+
+  Ok(())
+}
+
+/// watches the file in a thread, by polling its modification time.
+/// A change requests a reload like SIGHUP
+#[cfg(not(target_os = "linux"))]
+pub fn watch(path: &str) -> Result<(), String> {
+  use std::fs;
+
+  const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+  let path = Path::new(path).to_path_buf();
+  let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+  let mut last = modified(&path);
+
+  thread::spawn(move || loop {
+    thread::sleep(POLL_INTERVAL);
+    let current = modified(&path);
+    if current != last {
+      last = current;
+      changed(&path);
+    }
+  });
+
+  Ok(())
+}
+
+fn changed(path: &Path) {
+  thread::sleep(SETTLE_DELAY);
+  info!("{} changed, reloading it", path.display());
+  signal::request_reload();
+}
@@ -0,0 +1,114 @@
+//! notifies an external system of the issued certificates, of the failed
+//! runs and of the certificates about to expire, with a JSON POST signed
+//! with HMAC-SHA256
+
+use std::{cmp, fs, thread, time::Duration};
+use chrono::Utc;
+use openssl::{asn1::Asn1Time, error::ErrorStack, hash::MessageDigest, pkey::PKey, sign::Signer, x509::X509};
+use ureq;
+
+use exit;
+use state::State;
+
+/// the hexadecimal HMAC-SHA256 of the body, with the secret as key
+pub const SIGNATURE_HEADER: &str = "X-Sozu-Acme-Signature";
+
+pub struct Webhook {
+  pub url:         String,
+  pub secret:      Option<Vec<u8>>,
+  /// the attempts after the first one, with an exponential backoff
+  pub retries:     u32,
+  /// a certificate expiring in less days is notified at each run
+  pub expiry_days: i32,
+}
+
+impl Webhook {
+  /// the events of a run for the certificate named after its first domain:
+  /// `issued`, `failed`, and `expiring` when the current certificate expires
+  /// soon, whatever the result of the run
+  pub fn notify(&self, certificate: &str, code: i32) -> bool {
+    let state = State::load();
+    let managed = state.managed(certificate);
+    let not_after = managed.and_then(|(_, managed)| fs::read(&managed.certificate).ok())
+      .and_then(|pem| X509::from_pem(&pem).ok());
+    let days_left = not_after.as_ref()
+      .and_then(|cert| Asn1Time::days_from_now(0).ok().and_then(|now| now.diff(cert.not_after()).ok()))
+      .map(|diff| diff.days);
+
+    let event = |event: &str| {
+      let mut payload = json!({
+        "event": event,
+        "certificate": certificate,
+        "exit_code": code,
+        "result": exit::description(code),
+        "timestamp": Utc::now().to_rfc3339(),
+      });
+      if let Some((domains, managed)) = managed {
+        payload["domains"] = json!(domains.split(',').collect::<Vec<_>>());
+        payload["fingerprint"] = json!(managed.fingerprint);
+        payload["path"] = json!(managed.certificate);
+      }
+      if let Some(ref cert) = not_after {
+        payload["not_after"] = json!(cert.not_after().to_string());
+      }
+      if let Some(days_left) = days_left {
+        payload["days_left"] = json!(days_left);
+      }
+      payload
+    };
+
+    let mut events = Vec::new();
+    match code {
+      0 => events.push(event("issued")),
+      exit::NOT_DUE => {},
+      _ => events.push(event("failed")),
+    }
+    if days_left.map(|days| days < self.expiry_days).unwrap_or(false) {
+      events.push(event("expiring"));
+    }
+
+    events.iter().all(|payload| self.send(&payload.to_string()))
+  }
+
+  fn send(&self, body: &str) -> bool {
+    let signature = match self.secret {
+      Some(ref secret) => match sign(secret, body.as_bytes()) {
+        Ok(signature) => Some(signature),
+        Err(e) => {
+          error!("could not sign the webhook payload: {}", e);
+          return false;
+        }
+      },
+      None => None,
+    };
+
+    for attempt in 0..=self.retries {
+      // 1, 2, 4... seconds between the attempts, at most 64
+      if attempt > 0 {
+        thread::sleep(Duration::from_secs(1u64 << cmp::min(attempt - 1, 6)));
+      }
+
+      let mut request = ureq::post(&self.url);
+      request.set("Content-Type", "application/json").timeout(Duration::from_secs(10));
+      if let Some(ref signature) = signature {
+        request.set(SIGNATURE_HEADER, &format!("sha256={}", signature));
+      }
+      let res = request.send_string(body);
+      if !res.synthetic() && !res.error() {
+        debug!("notified {}", self.url);
+        return true;
+      }
+      warn!("could not notify {} (attempt {}/{}): {}", self.url, attempt + 1, self.retries + 1, res.status_line());
+    }
+
+    error!("gave up notifying {}", self.url);
+    false
+  }
+}
+
+fn sign(secret: &[u8], body: &[u8]) -> Result<String, ErrorStack> {
+  let key = PKey::hmac(secret)?;
+  let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+  signer.update(body)?;
+  Ok(signer.sign_to_vec()?.iter().map(|b| format!("{:02x}", b)).collect())
+}